@@ -0,0 +1,570 @@
+//! Derive macros for [llama-cpp-2](https://docs.rs/llama-cpp-2).
+//!
+//! These are re-exported by `llama-cpp-2` when its `derive` feature is enabled and should not be
+//! depended on directly.
+
+use proc_macro::TokenStream;
+use proc_macro2::TokenStream as TokenStream2;
+use quote::quote;
+use syn::ext::IdentExt;
+use syn::meta::ParseNestedMeta;
+use syn::{
+    parse_macro_input, parse_quote, Attribute, Data, DeriveInput, Field, Fields, LitStr, Token,
+    Type, Variant,
+};
+
+/// Derive `llama_cpp_2::structured::LlamaStructured` for a struct or enum.
+///
+/// The generated JSON schema follows the representation used by `serde`, and understands the
+/// `rename`, `rename_all`, `default`, `skip`, `skip_deserializing`, `transparent`, `untagged`,
+/// `tag` and `content` serde attributes. Fields of type `Option<T>` or marked `#[serde(default)]`
+/// are optional. Attributes that change how a type is deserialized in ways the schema can not
+/// follow, like `flatten`, `from` or `deserialize_with`, are compile errors.
+///
+/// Recursive types are not supported, their schema would be infinite. `LlamaStructured::grammar`
+/// returns an error for them.
+#[proc_macro_derive(LlamaStructured, attributes(serde))]
+pub fn derive_llama_structured(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    expand(&input)
+        .unwrap_or_else(syn::Error::into_compile_error)
+        .into()
+}
+
+fn expand(input: &DeriveInput) -> syn::Result<TokenStream2> {
+    let attrs = ContainerAttrs::parse(&input.attrs)?;
+    let schema = match &input.data {
+        Data::Struct(data) => struct_schema(&attrs, &data.fields)?,
+        Data::Enum(data) => enum_schema(&attrs, data.variants.iter())?,
+        Data::Union(_) => {
+            return Err(syn::Error::new_spanned(
+                input,
+                "`LlamaStructured` cannot be derived for unions",
+            ))
+        }
+    };
+
+    let name = &input.ident;
+    let mut generics = input.generics.clone();
+    let type_params = generics
+        .type_params()
+        .map(|param| param.ident.clone())
+        .collect::<Vec<_>>();
+    let where_clause = generics.make_where_clause();
+    for param in type_params {
+        where_clause
+            .predicates
+            .push(parse_quote!(#param: ::llama_cpp_2::structured::LlamaStructured));
+    }
+    let (impl_generics, ty_generics, where_clause) = generics.split_for_impl();
+
+    Ok(quote! {
+        #[automatically_derived]
+        impl #impl_generics ::llama_cpp_2::structured::LlamaStructured for #name #ty_generics #where_clause {
+            fn json_schema() -> ::llama_cpp_2::structured::__private::serde_json::Value {
+                ::llama_cpp_2::structured::__private::guard::<Self>(|| #schema)
+            }
+        }
+    })
+}
+
+/// The schema of a value of type `ty`.
+fn type_schema(ty: &Type) -> TokenStream2 {
+    quote!(<#ty as ::llama_cpp_2::structured::LlamaStructured>::json_schema())
+}
+
+fn struct_schema(attrs: &ContainerAttrs, fields: &Fields) -> syn::Result<TokenStream2> {
+    if attrs.tag.is_some() || attrs.untagged {
+        return Err(syn::Error::new(
+            attrs.span,
+            "`tag` and `untagged` are only supported on enums",
+        ));
+    }
+    match fields {
+        Fields::Named(_) => object_schema(attrs, fields, None),
+        Fields::Unnamed(_) => {
+            let fields = deserialized_fields(fields)?;
+            match fields.as_slice() {
+                [(field, _)] => Ok(type_schema(&field.ty)),
+                fields => Ok(tuple_schema(fields.iter().map(|(field, _)| &field.ty))),
+            }
+        }
+        Fields::Unit => Ok(quote!(
+            ::llama_cpp_2::structured::__private::serde_json::json!({
+                "type": "null"
+            })
+        )),
+    }
+}
+
+fn tuple_schema<'a>(types: impl ExactSizeIterator<Item = &'a Type>) -> TokenStream2 {
+    let len = types.len();
+    let items = types.map(type_schema);
+    quote!({
+        let items: ::std::vec::Vec<::llama_cpp_2::structured::__private::serde_json::Value> =
+            ::std::vec![#(#items),*];
+        ::llama_cpp_2::structured::__private::serde_json::json!({
+            "type": "array",
+            "prefixItems": items,
+            "minItems": #len,
+            "maxItems": #len,
+        })
+    })
+}
+
+/// The schema of a struct with named fields. `tag` is an extra required property with a constant
+/// value, used for internally tagged enums.
+fn object_schema(
+    attrs: &ContainerAttrs,
+    fields: &Fields,
+    tag: Option<(&str, &str)>,
+) -> syn::Result<TokenStream2> {
+    if attrs.transparent {
+        let fields = deserialized_fields(fields)?;
+        return match fields.as_slice() {
+            [(field, _)] => Ok(type_schema(&field.ty)),
+            _ => Err(syn::Error::new(
+                attrs.span,
+                "`transparent` requires exactly one field",
+            )),
+        };
+    }
+
+    let mut inserts = Vec::new();
+    let mut required = Vec::new();
+    if let Some((tag, value)) = tag {
+        inserts.push(quote! {
+            properties.insert(
+                #tag.to_string(),
+                ::llama_cpp_2::structured::__private::serde_json::json!({ "const": #value }),
+            );
+        });
+        required.push(tag.to_string());
+    }
+    for (field, field_attrs) in deserialized_fields(fields)? {
+        let ident = field
+            .ident
+            .as_ref()
+            .expect("named field")
+            .unraw()
+            .to_string();
+        let name = match field_attrs.rename {
+            Some(name) => name,
+            None => rename_field(attrs.rename_all.as_deref(), &ident),
+        };
+        let schema = type_schema(&field.ty);
+        inserts.push(quote!(properties.insert(#name.to_string(), #schema);));
+        if !field_attrs.default && !attrs.default && !is_option(&field.ty) {
+            required.push(name);
+        }
+    }
+
+    Ok(quote! {{
+        let mut properties = ::llama_cpp_2::structured::__private::serde_json::Map::new();
+        #(#inserts)*
+        ::llama_cpp_2::structured::__private::serde_json::json!({
+            "type": "object",
+            "properties": properties,
+            "required": [#(#required),*],
+            "additionalProperties": false,
+        })
+    }})
+}
+
+fn enum_schema<'a>(
+    attrs: &ContainerAttrs,
+    variants: impl Iterator<Item = &'a Variant>,
+) -> syn::Result<TokenStream2> {
+    let mut alternatives = Vec::new();
+    let mut all_unit = true;
+    for variant in variants {
+        let variant_attrs = FieldAttrs::parse(&variant.attrs)?;
+        if variant_attrs.skip {
+            continue;
+        }
+        let name = match variant_attrs.rename {
+            Some(name) => name,
+            None => rename_variant(
+                attrs.rename_all.as_deref(),
+                &variant.ident.unraw().to_string(),
+            ),
+        };
+        all_unit &= matches!(variant.fields, Fields::Unit);
+        alternatives.push((name, variant));
+    }
+
+    if all_unit && !attrs.untagged && attrs.tag.is_none() {
+        let names = alternatives.iter().map(|(name, _)| name);
+        return Ok(quote!(
+            ::llama_cpp_2::structured::__private::serde_json::json!({
+                "enum": [#(#names),*]
+            })
+        ));
+    }
+
+    let variant_attrs = ContainerAttrs {
+        span: attrs.span,
+        ..ContainerAttrs::default()
+    };
+    let schemas = alternatives
+        .iter()
+        .map(|(name, variant)| {
+            let content = || struct_schema(&variant_attrs, &variant.fields);
+            match (&attrs.tag, &attrs.content) {
+                _ if attrs.untagged => content(),
+                (None, _) => {
+                    if matches!(variant.fields, Fields::Unit) {
+                        return Ok(quote!(
+                            ::llama_cpp_2::structured::__private::serde_json::json!({ "const": #name })
+                        ));
+                    }
+                    let content = content()?;
+                    Ok(quote!({
+                        let content = #content;
+                        ::llama_cpp_2::structured::__private::serde_json::json!({
+                            "type": "object",
+                            "properties": { #name: content },
+                            "required": [#name],
+                            "additionalProperties": false,
+                        })
+                    }))
+                }
+                (Some(tag), None) => match variant.fields {
+                    Fields::Named(_) => object_schema(&variant_attrs, &variant.fields, Some((tag, name))),
+                    Fields::Unit => object_schema(&variant_attrs, &Fields::Unit, Some((tag, name))),
+                    Fields::Unnamed(_) => Err(syn::Error::new_spanned(
+                        variant,
+                        "tuple variants are not supported in internally tagged enums",
+                    )),
+                },
+                (Some(tag), Some(content_name)) => {
+                    if matches!(variant.fields, Fields::Unit) {
+                        return Ok(quote!(::llama_cpp_2::structured::__private::serde_json::json!({
+                            "type": "object",
+                            "properties": { #tag: { "const": #name } },
+                            "required": [#tag],
+                            "additionalProperties": false,
+                        })));
+                    }
+                    let content = content()?;
+                    Ok(quote!({
+                        let content = #content;
+                        ::llama_cpp_2::structured::__private::serde_json::json!({
+                            "type": "object",
+                            "properties": { #tag: { "const": #name }, #content_name: content },
+                            "required": [#tag, #content_name],
+                            "additionalProperties": false,
+                        })
+                    }))
+                }
+            }
+        })
+        .collect::<syn::Result<Vec<_>>>()?;
+
+    let keyword = if attrs.untagged { "anyOf" } else { "oneOf" };
+    Ok(quote!({
+        let alternatives: ::std::vec::Vec<::llama_cpp_2::structured::__private::serde_json::Value> =
+            ::std::vec![#(#schemas),*];
+        ::llama_cpp_2::structured::__private::serde_json::json!({ #keyword: alternatives })
+    }))
+}
+
+/// The fields of a struct or variant that take part in deserialization.
+fn deserialized_fields(fields: &Fields) -> syn::Result<Vec<(&Field, FieldAttrs)>> {
+    let mut deserialized = Vec::new();
+    for field in fields {
+        let attrs = FieldAttrs::parse(&field.attrs)?;
+        if !attrs.skip {
+            deserialized.push((field, attrs));
+        }
+    }
+    Ok(deserialized)
+}
+
+fn is_option(ty: &Type) -> bool {
+    match ty {
+        Type::Path(path) => path
+            .path
+            .segments
+            .last()
+            .is_some_and(|segment| segment.ident == "Option"),
+        Type::Group(group) => is_option(&group.elem),
+        Type::Paren(paren) => is_option(&paren.elem),
+        _ => false,
+    }
+}
+
+/// Apply a serde `rename_all` rule to a field name, like serde's `RenameRule::apply_to_field`.
+/// Field names are assumed to be `snake_case`, so `lowercase` leaves them unchanged.
+fn rename_field(rule: Option<&str>, field: &str) -> String {
+    match rule {
+        Some("UPPERCASE" | "SCREAMING_SNAKE_CASE") => field.to_ascii_uppercase(),
+        Some("PascalCase") => {
+            let mut pascal = String::new();
+            let mut capitalize = true;
+            for c in field.chars() {
+                if c == '_' {
+                    capitalize = true;
+                } else if capitalize {
+                    pascal.push(c.to_ascii_uppercase());
+                    capitalize = false;
+                } else {
+                    pascal.push(c);
+                }
+            }
+            pascal
+        }
+        Some("camelCase") => lowercase_first(&rename_field(Some("PascalCase"), field)),
+        Some("kebab-case") => field.replace('_', "-"),
+        Some("SCREAMING-KEBAB-CASE") => field.to_ascii_uppercase().replace('_', "-"),
+        _ => field.to_string(),
+    }
+}
+
+/// Apply a serde `rename_all` rule to a variant name, like serde's
+/// `RenameRule::apply_to_variant`. Variant names are assumed to be `PascalCase`.
+fn rename_variant(rule: Option<&str>, variant: &str) -> String {
+    let snake_case = || {
+        let mut snake = String::new();
+        for (i, c) in variant.char_indices() {
+            if i > 0 && c.is_uppercase() {
+                snake.push('_');
+            }
+            snake.push(c.to_ascii_lowercase());
+        }
+        snake
+    };
+    match rule {
+        Some("lowercase") => variant.to_ascii_lowercase(),
+        Some("UPPERCASE") => variant.to_ascii_uppercase(),
+        Some("camelCase") => lowercase_first(variant),
+        Some("snake_case") => snake_case(),
+        Some("SCREAMING_SNAKE_CASE") => snake_case().to_ascii_uppercase(),
+        Some("kebab-case") => snake_case().replace('_', "-"),
+        Some("SCREAMING-KEBAB-CASE") => snake_case().to_ascii_uppercase().replace('_', "-"),
+        _ => variant.to_string(),
+    }
+}
+
+fn lowercase_first(name: &str) -> String {
+    let mut chars = name.chars();
+    chars.next().map_or_else(String::new, |first| {
+        first.to_ascii_lowercase().to_string() + chars.as_str()
+    })
+}
+
+const RENAME_RULES: [&str; 8] = [
+    "lowercase",
+    "UPPERCASE",
+    "PascalCase",
+    "camelCase",
+    "snake_case",
+    "SCREAMING_SNAKE_CASE",
+    "kebab-case",
+    "SCREAMING-KEBAB-CASE",
+];
+
+/// The `#[serde(...)]` attributes of a struct or enum.
+struct ContainerAttrs {
+    span: proc_macro2::Span,
+    rename_all: Option<String>,
+    default: bool,
+    transparent: bool,
+    untagged: bool,
+    tag: Option<String>,
+    content: Option<String>,
+}
+
+impl Default for ContainerAttrs {
+    fn default() -> Self {
+        Self {
+            span: proc_macro2::Span::call_site(),
+            rename_all: None,
+            default: false,
+            transparent: false,
+            untagged: false,
+            tag: None,
+            content: None,
+        }
+    }
+}
+
+impl ContainerAttrs {
+    fn parse(attrs: &[Attribute]) -> syn::Result<Self> {
+        let mut parsed = Self::default();
+        for attr in attrs.iter().filter(|attr| attr.path().is_ident("serde")) {
+            attr.parse_nested_meta(|meta| {
+                if meta.path.is_ident("rename_all") {
+                    let rule = deserialize_name(&meta)?;
+                    if !RENAME_RULES.contains(&rule.value().as_str()) {
+                        return Err(syn::Error::new_spanned(rule, "unknown rename rule"));
+                    }
+                    parsed.rename_all = Some(rule.value());
+                } else if meta.path.is_ident("default") {
+                    skip_meta(&meta)?;
+                    parsed.default = true;
+                } else if meta.path.is_ident("transparent") {
+                    parsed.transparent = true;
+                } else if meta.path.is_ident("untagged") {
+                    parsed.untagged = true;
+                } else if meta.path.is_ident("tag") {
+                    parsed.tag = Some(meta.value()?.parse::<LitStr>()?.value());
+                } else if meta.path.is_ident("content") {
+                    parsed.content = Some(meta.value()?.parse::<LitStr>()?.value());
+                } else if meta.path.is_ident("from") || meta.path.is_ident("try_from") {
+                    return Err(meta.error(
+                        "`LlamaStructured` cannot be derived for types deserialized from another type",
+                    ));
+                } else if meta.path.is_ident("rename_all_fields") {
+                    return Err(
+                        meta.error("`rename_all_fields` is not supported by `LlamaStructured`")
+                    );
+                } else {
+                    skip_meta(&meta)?;
+                }
+                Ok(())
+            })?;
+        }
+        Ok(parsed)
+    }
+}
+
+/// The `#[serde(...)]` attributes of a field or variant.
+#[derive(Default)]
+struct FieldAttrs {
+    rename: Option<String>,
+    default: bool,
+    skip: bool,
+}
+
+impl FieldAttrs {
+    fn parse(attrs: &[Attribute]) -> syn::Result<Self> {
+        let mut parsed = Self::default();
+        for attr in attrs.iter().filter(|attr| attr.path().is_ident("serde")) {
+            attr.parse_nested_meta(|meta| {
+                if meta.path.is_ident("rename") {
+                    parsed.rename = Some(deserialize_name(&meta)?.value());
+                } else if meta.path.is_ident("default") {
+                    skip_meta(&meta)?;
+                    parsed.default = true;
+                } else if meta.path.is_ident("skip") || meta.path.is_ident("skip_deserializing") {
+                    parsed.skip = true;
+                } else if meta.path.is_ident("flatten") {
+                    return Err(meta.error("`flatten` is not supported by `LlamaStructured`"));
+                } else if meta.path.is_ident("with") || meta.path.is_ident("deserialize_with") {
+                    return Err(meta.error(
+                        "`LlamaStructured` cannot be derived for fields deserialized with a function",
+                    ));
+                } else if meta.path.is_ident("rename_all") {
+                    return Err(
+                        meta.error("`rename_all` on variants is not supported by `LlamaStructured`")
+                    );
+                } else {
+                    skip_meta(&meta)?;
+                }
+                Ok(())
+            })?;
+        }
+        Ok(parsed)
+    }
+}
+
+/// Parse either `name = "..."` or `name(deserialize = "...")`.
+fn deserialize_name(meta: &ParseNestedMeta) -> syn::Result<LitStr> {
+    if meta.input.peek(Token![=]) {
+        return meta.value()?.parse();
+    }
+    let mut name = None;
+    meta.parse_nested_meta(|nested| {
+        if nested.path.is_ident("deserialize") {
+            name = Some(nested.value()?.parse::<LitStr>()?);
+        } else {
+            skip_meta(&nested)?;
+        }
+        Ok(())
+    })?;
+    name.ok_or_else(|| meta.error("expected `deserialize = \"...\"`"))
+}
+
+/// Consume the value of an attribute that does not affect the schema.
+fn skip_meta(meta: &ParseNestedMeta) -> syn::Result<()> {
+    if meta.input.peek(Token![=]) {
+        meta.value()?.parse::<syn::Expr>()?;
+    } else if meta.input.peek(syn::token::Paren) {
+        meta.parse_nested_meta(|nested| skip_meta(&nested))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rename_fields() {
+        assert_eq!(rename_field(Some("camelCase"), "first_name"), "firstName");
+        assert_eq!(rename_field(Some("PascalCase"), "first_name"), "FirstName");
+        assert_eq!(rename_field(Some("lowercase"), "first_name"), "first_name");
+        assert_eq!(rename_field(Some("UPPERCASE"), "first_name"), "FIRST_NAME");
+        assert_eq!(rename_field(Some("lowercase"), "firstName"), "firstName");
+        assert_eq!(
+            rename_field(Some("SCREAMING-KEBAB-CASE"), "first_name"),
+            "FIRST-NAME"
+        );
+        assert_eq!(rename_field(None, "first_name"), "first_name");
+    }
+
+    #[test]
+    fn rename_variants() {
+        assert_eq!(rename_variant(Some("snake_case"), "VeryHigh"), "very_high");
+        assert_eq!(rename_variant(Some("camelCase"), "VeryHigh"), "veryHigh");
+        assert_eq!(rename_variant(Some("lowercase"), "VeryHigh"), "veryhigh");
+        assert_eq!(rename_variant(Some("UPPERCASE"), "VeryHigh"), "VERYHIGH");
+        assert_eq!(
+            rename_variant(Some("SCREAMING_SNAKE_CASE"), "VeryHigh"),
+            "VERY_HIGH"
+        );
+        assert_eq!(rename_variant(Some("kebab-case"), "VeryHigh"), "very-high");
+    }
+
+    #[test]
+    fn expand_struct() {
+        let input: DeriveInput = parse_quote! {
+            #[serde(rename_all = "camelCase")]
+            struct Person {
+                first_name: String,
+                #[serde(default)]
+                age: u8,
+                nickname: Option<String>,
+                #[serde(skip)]
+                cache: (),
+            }
+        };
+        let expanded = expand(&input).unwrap().to_string();
+        assert!(expanded.contains("\"firstName\""));
+        assert!(!expanded.contains("cache"));
+        assert!(expanded.contains("\"required\" : [\"firstName\"]"));
+    }
+
+    #[test]
+    fn unsupported_attributes() {
+        let inputs: [DeriveInput; 4] = [
+            parse_quote! {
+                #[serde(rename_all_fields = "camelCase")]
+                enum A { B { c_d: u8 } }
+            },
+            parse_quote! {
+                struct A { #[serde(with = "b")] c: u8 }
+            },
+            parse_quote! {
+                struct A { #[serde(deserialize_with = "b")] c: u8 }
+            },
+            parse_quote! {
+                enum A { #[serde(rename_all = "camelCase")] B { c_d: u8 } }
+            },
+        ];
+        for input in inputs {
+            assert!(expand(&input).is_err());
+        }
+    }
+}
@@ -0,0 +1,434 @@
+//! Text generation as an [`Iterator`].
+//!
+//! [`Completion`] runs the usual generation loop: it evaluates the prompt, samples a token,
+//! converts it to text with a [`StreamingDetokenizer`], checks for stop sequences and evaluates
+//! the token for the next step. After the iterator ends, [`Completion::stop_reason`] tells why.
+//!
+//! ```no_run
+//! # use llama_cpp_2::context::LlamaContext;
+//! # fn example(ctx: &mut LlamaContext) -> Result<(), Box<dyn std::error::Error>> {
+//! use llama_cpp_2::completion::Completion;
+//! use llama_cpp_2::model::AddBos;
+//! use llama_cpp_2::sampling::LlamaSampler;
+//!
+//! let prompt = ctx.model.str_to_token("Hello my name is", AddBos::Always)?;
+//! let mut completion = Completion::new(ctx, &prompt, LlamaSampler::greedy())
+//!     .with_max_tokens(64)
+//!     .with_stops(["\n\n"]);
+//! for token in &mut completion {
+//!     print!("{}", token?.text);
+//! }
+//! println!("\n{:?}", completion.stop_reason());
+//! # Ok(())
+//! # }
+//! ```
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use crate::completion::token_healing::{common_prefix_len, TokenHealing, TokenPrefixIndex};
+use crate::context::shift::{ContextShift, ContextShiftError, SelfExtend};
+use crate::context::LlamaContext;
+use crate::detokenize::StreamingDetokenizer;
+use crate::llama_batch::{BatchAddError, LlamaBatch};
+use crate::model::LlamaModel;
+use crate::sampling::LlamaSampler;
+use crate::stop::{StopSequences, StopStatus};
+use crate::token::LlamaToken;
+use crate::{DecodeError, GrammarError, TokenToStringError};
+
+pub mod beam_search;
+pub mod guidance;
+pub mod n_best;
+pub mod scheduler;
+pub mod speculative;
+#[cfg(feature = "async")]
+pub mod stream;
+pub mod token_healing;
+
+/// Why a [`Completion`] stopped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum StopReason {
+    /// The maximum number of tokens was generated.
+    Length,
+    /// The stop sequence with this index was generated.
+    Stop(usize),
+    /// An end-of-generation token was sampled.
+    EndOfGeneration,
+    /// The context has no space for the next token.
+    ContextFull,
+    /// The cancellation flag was set.
+    Cancelled,
+}
+
+/// An error while generating text.
+#[derive(Debug, thiserror::Error)]
+pub enum CompletionError {
+    /// The prompt has no tokens.
+    #[error("the prompt is empty")]
+    EmptyPrompt,
+    /// Evaluating a batch failed.
+    #[error("{0}")]
+    DecodeError(#[from] DecodeError),
+    /// Adding a token to a batch failed.
+    #[error("{0}")]
+    BatchAddError(#[from] BatchAddError),
+    /// Converting a token to text failed.
+    #[error("{0}")]
+    TokenToStringError(#[from] TokenToStringError),
+    /// Building a sampler failed.
+    #[error("{0}")]
+    GrammarError(#[from] GrammarError),
+    /// Shifting the context failed.
+    #[error("{0}")]
+    ContextShiftError(#[from] ContextShiftError),
+    /// Rejected draft tokens could not be removed from the KV cache, for example of a recurrent
+    /// model, see [`speculative`].
+    #[error("the rejected draft tokens could not be removed from the KV cache")]
+    RollbackFailed,
+    /// The sampler did not select a token, it must end with a sampler like
+    /// [`LlamaSampler::dist`] or [`LlamaSampler::greedy`].
+    #[error("the sampler did not select a token")]
+    NoTokenSelected,
+}
+
+/// A generated token and the text that is ready to be shown.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CompletionToken {
+    /// The sampled token.
+    pub token: LlamaToken,
+    /// The text that became safe to emit with this token. It can be empty, for example while it
+    /// may be part of a stop sequence, and can include text of earlier tokens.
+    pub text: String,
+}
+
+/// Generates tokens from a prompt, see [the module docs](self).
+///
+/// Each item is a sampled token. The last item carries the rest of the text: for an
+/// end-of-generation token that is the text held back so far, for a stop sequence the text before
+/// it. Text held back when the cancellation flag is set is discarded.
+#[derive(Debug)]
+pub struct Completion<'a, 'm> {
+    ctx: &'a mut LlamaContext<'m>,
+    sampler: LlamaSampler,
+    output: TokenOutput<'m>,
+    cancel: Option<Arc<AtomicBool>>,
+    context_shift: Option<ContextShift>,
+    self_extend: Option<SelfExtend>,
+    healing: Option<TokenHealing>,
+    batch: LlamaBatch<'static>,
+    /// Tokens to evaluate before the next token can be sampled.
+    pending: Vec<LlamaToken>,
+    /// The number of tokens in the KV cache.
+    n_past: usize,
+    /// The position of the next token, below `n_past` with Self-Extend.
+    pos: usize,
+    stop_reason: Option<StopReason>,
+    failed: bool,
+}
+
+impl<'a, 'm> Completion<'a, 'm> {
+    /// Generate a completion of `prompt` with `sampler` in sequence 0 of `ctx`, which should not
+    /// contain any other tokens. Nothing is evaluated before the first call to
+    /// [`Iterator::next`].
+    ///
+    /// # Panics
+    ///
+    /// If the batch size of the context does not fit into a [`usize`].
+    #[must_use]
+    pub fn new(
+        ctx: &'a mut LlamaContext<'m>,
+        prompt: &[LlamaToken],
+        sampler: LlamaSampler,
+    ) -> Self {
+        let model = ctx.model;
+        let n_batch = usize::try_from(ctx.n_batch()).expect("n_batch fits into a usize");
+        Self {
+            ctx,
+            sampler,
+            output: TokenOutput::new(model),
+            cancel: None,
+            context_shift: None,
+            self_extend: None,
+            healing: None,
+            batch: LlamaBatch::new(n_batch, 1),
+            pending: prompt.to_vec(),
+            n_past: 0,
+            pos: 0,
+            stop_reason: None,
+            failed: false,
+        }
+    }
+
+    /// Stop after `max_tokens` tokens.
+    #[must_use]
+    pub fn with_max_tokens(mut self, max_tokens: usize) -> Self {
+        self.output.max_tokens = Some(max_tokens);
+        self
+    }
+
+    /// Stop when any of `stops` is generated. The stop sequence is not part of the text.
+    #[must_use]
+    pub fn with_stops(mut self, stops: impl IntoIterator<Item = impl Into<String>>) -> Self {
+        self.output.stops = StopSequences::new(stops);
+        self
+    }
+
+    /// Convert the tokens to text with `detokenizer`, for example to render special tokens. It
+    /// must be for the model of the context.
+    #[must_use]
+    pub fn with_detokenizer(mut self, detokenizer: StreamingDetokenizer<'m>) -> Self {
+        self.output.detokenizer = detokenizer;
+        self
+    }
+
+    /// Stop before evaluating the next token once `cancel` is set, for example from another
+    /// thread.
+    #[must_use]
+    pub fn with_cancel(mut self, cancel: Arc<AtomicBool>) -> Self {
+        self.cancel = Some(cancel);
+        self
+    }
+
+    /// Shift the context when it is full instead of stopping with [`StopReason::ContextFull`],
+    /// see [`crate::context::shift`]. The prompt must still fit into the context.
+    ///
+    /// If the model can not shift its context, the first call to [`Iterator::next`] returns
+    /// [`ContextShiftError::Unsupported`]. This replaces [`Self::with_self_extend`].
+    #[must_use]
+    pub fn with_context_shift(mut self, shift: ContextShift) -> Self {
+        self.context_shift = Some(shift);
+        self.self_extend = None;
+        self
+    }
+
+    /// Group the positions of older tokens with Self-Extend, see [`crate::context::shift`], so the
+    /// model can handle more tokens than it was trained for. The tokens still have to fit into the
+    /// context, and are evaluated in batches of at most the window width.
+    ///
+    /// If the model can not shift its context, the first call to [`Iterator::next`] returns
+    /// [`ContextShiftError::Unsupported`]. This replaces [`Self::with_context_shift`].
+    #[must_use]
+    pub fn with_self_extend(mut self, mut self_extend: SelfExtend) -> Self {
+        self_extend.reset();
+        self.self_extend = Some(self_extend);
+        self.context_shift = None;
+        self
+    }
+
+    /// Remove up to `max_tokens` tokens from the end of the prompt and let the first generated
+    /// token replace them, see [`token_healing`]. The text of the first token does not repeat the
+    /// removed text, so the output continues the original prompt.
+    ///
+    /// The sampler is applied to the allowed tokens only, so it must select a token, for example
+    /// by ending with [`LlamaSampler::dist`] or [`LlamaSampler::greedy`], otherwise generation
+    /// fails with [`CompletionError::NoTokenSelected`].
+    #[must_use]
+    pub fn with_token_healing(mut self, index: &TokenPrefixIndex, max_tokens: usize) -> Self {
+        let healing = TokenHealing::new(self.ctx.model, index, &self.pending, max_tokens);
+        self.pending = healing.prompt().to_vec();
+        self.output.strip_prefix = healing.removed_text().to_string();
+        self.healing = Some(healing);
+        self
+    }
+
+    /// Why the generation stopped, `None` while it is running or after an error.
+    #[must_use]
+    pub fn stop_reason(&self) -> Option<StopReason> {
+        self.stop_reason
+    }
+
+    /// The number of tokens generated so far.
+    #[must_use]
+    pub fn n_generated(&self) -> usize {
+        self.output.n_generated
+    }
+
+    /// The context the completion runs in.
+    #[must_use]
+    pub fn context(&self) -> &LlamaContext<'m> {
+        self.ctx
+    }
+
+    /// Evaluate the pending tokens in batches, with the logits of the last one.
+    fn evaluate(&mut self) -> Result<(), CompletionError> {
+        let mut n_batch = usize::try_from(self.ctx.n_batch()).expect("n_batch fits into a usize");
+        if let Some(self_extend) = &self.self_extend {
+            n_batch = n_batch.min(self_extend.window() as usize);
+        }
+        let n_pending = self.pending.len();
+        for (i, chunk) in self.pending.chunks(n_batch).enumerate() {
+            if let Some(self_extend) = &mut self.self_extend {
+                let pos = u32::try_from(self.pos).expect("position fits into a u32");
+                self.pos = self_extend.apply(self.ctx, 0, pos)? as usize;
+            }
+            self.batch.clear();
+            for (j, &token) in chunk.iter().enumerate() {
+                let pos = i32::try_from(self.pos).expect("position fits into an i32");
+                let last = i * n_batch + j + 1 == n_pending;
+                self.batch.add(token, pos, &[0], last)?;
+                self.n_past += 1;
+                self.pos += 1;
+            }
+            self.ctx.decode(&mut self.batch)?;
+        }
+        self.pending.clear();
+        Ok(())
+    }
+
+    fn step(&mut self) -> Result<Option<CompletionToken>, CompletionError> {
+        if self.pending.is_empty() {
+            return Err(CompletionError::EmptyPrompt);
+        }
+        if self.output.n_generated == 0
+            && (self.context_shift.is_some() || self.self_extend.is_some())
+            && !self.ctx.can_shift_context()
+        {
+            return Err(ContextShiftError::Unsupported.into());
+        }
+        let n_ctx = usize::try_from(self.ctx.n_ctx()).expect("n_ctx fits into a usize");
+        if self.output.n_generated > 0 && self.n_past + self.pending.len() > n_ctx {
+            if let Some(shift) = &self.context_shift {
+                let n_discard = self.ctx.shift_context(0, self.n_past, shift)?;
+                self.n_past -= n_discard;
+                self.pos -= n_discard;
+            }
+        }
+        let stop = if self
+            .cancel
+            .as_ref()
+            .is_some_and(|c| c.load(Ordering::Relaxed))
+        {
+            Some(StopReason::Cancelled)
+        } else if self.output.at_max_tokens() {
+            Some(StopReason::Length)
+        } else if self.n_past + self.pending.len() > n_ctx {
+            Some(StopReason::ContextFull)
+        } else {
+            None
+        };
+        if stop.is_some() {
+            self.stop_reason = stop;
+            return Ok(None);
+        }
+
+        self.evaluate()?;
+        let logits = self.batch.n_tokens() - 1;
+        let token = match &self.healing {
+            Some(healing) if self.output.n_generated == 0 && healing.is_healing() => {
+                healing.sample(&mut self.sampler, self.ctx.get_logits_ith(logits))?
+            }
+            _ => self.sampler.sample(self.ctx, logits),
+        };
+        let context_full = self.n_past >= n_ctx && self.context_shift.is_none();
+        let (token, stop) = self.output.push(token, context_full)?;
+        if stop.is_some() {
+            self.stop_reason = stop;
+        } else {
+            self.pending.push(token.token);
+        }
+        Ok(Some(token))
+    }
+}
+
+/// The number of tokens each sequence of `ctx` can hold, when all of them are used.
+pub(crate) fn n_ctx_seq(ctx: &LlamaContext) -> usize {
+    let n_ctx = ctx.n_ctx() / ctx.n_seq_max().max(1);
+    usize::try_from(n_ctx).expect("n_ctx fits into a usize")
+}
+
+/// Converts sampled tokens to text and decides when generation ends, shared by [`Completion`] and
+/// [`scheduler::Scheduler`].
+#[derive(Debug)]
+pub(crate) struct TokenOutput<'m> {
+    model: &'m LlamaModel,
+    pub(crate) detokenizer: StreamingDetokenizer<'m>,
+    pub(crate) stops: StopSequences,
+    pub(crate) max_tokens: Option<usize>,
+    pub(crate) n_generated: usize,
+    /// Text at the start of the output that is not emitted, the text removed by token healing.
+    pub(crate) strip_prefix: String,
+}
+
+impl<'m> TokenOutput<'m> {
+    pub(crate) fn new(model: &'m LlamaModel) -> Self {
+        Self {
+            model,
+            detokenizer: StreamingDetokenizer::new(model),
+            stops: StopSequences::new(Vec::<String>::new()),
+            max_tokens: None,
+            n_generated: 0,
+            strip_prefix: String::new(),
+        }
+    }
+
+    /// Whether the maximum number of tokens was generated.
+    pub(crate) fn at_max_tokens(&self) -> bool {
+        self.max_tokens.is_some_and(|max| self.n_generated >= max)
+    }
+
+    /// Convert a sampled token to text and return why generation ends with it, if it does.
+    /// `context_full` tells that there is no room to evaluate the token.
+    ///
+    /// The last token carries the rest of the text: for an end-of-generation token that is the
+    /// text held back so far, for a stop sequence the text before it.
+    pub(crate) fn push(
+        &mut self,
+        token: LlamaToken,
+        context_full: bool,
+    ) -> Result<(CompletionToken, Option<StopReason>), TokenToStringError> {
+        self.n_generated += 1;
+
+        let eog = self.model.is_eog_token(token);
+        let mut piece = if eog {
+            String::new()
+        } else {
+            self.detokenizer.push(token)?
+        };
+        let last = if eog {
+            Some(StopReason::EndOfGeneration)
+        } else if self.at_max_tokens() {
+            Some(StopReason::Length)
+        } else if context_full {
+            Some(StopReason::ContextFull)
+        } else {
+            None
+        };
+        if last.is_some() {
+            piece.push_str(&self.detokenizer.flush());
+        }
+        if !self.strip_prefix.is_empty() {
+            let n = common_prefix_len(&piece, &self.strip_prefix);
+            piece.drain(..n);
+            if piece.is_empty() {
+                self.strip_prefix.drain(..n);
+            } else {
+                self.strip_prefix.clear();
+            }
+        }
+
+        let (text, stop) = match self.stops.push(&piece) {
+            StopStatus::Stop { text, stop } => (text, Some(StopReason::Stop(stop))),
+            StopStatus::Continue(mut text) => {
+                if last.is_some() {
+                    text.push_str(&self.stops.flush());
+                }
+                (text, last)
+            }
+        };
+        Ok((CompletionToken { token, text }, stop))
+    }
+}
+
+impl Iterator for Completion<'_, '_> {
+    type Item = Result<CompletionToken, CompletionError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.stop_reason.is_some() || self.failed {
+            return None;
+        }
+        let result = self.step().transpose();
+        self.failed = matches!(result, Some(Err(_)));
+        result
+    }
+}
@@ -0,0 +1,375 @@
+//! Beam search decoding.
+//!
+//! Instead of sampling one token at a time, [`BeamSearch`] keeps the `width` most likely partial
+//! completions (beams), each in its own sequence of the context. Every step decodes the last
+//! token of all beams in one batch, extends each beam by its most likely tokens and keeps the
+//! best of all extensions; the KV cache of a beam that is extended in several ways is copied with
+//! [`LlamaContext::copy_kv_cache_seq`]. Beams are ranked by their cumulative log-probability
+//! divided by `length ^ length_penalty`.
+//!
+//! ```no_run
+//! # use llama_cpp_2::context::LlamaContext;
+//! # fn example(ctx: &mut LlamaContext) -> Result<(), Box<dyn std::error::Error>> {
+//! use std::num::NonZeroUsize;
+//!
+//! use llama_cpp_2::completion::beam_search::BeamSearch;
+//! use llama_cpp_2::model::{AddBos, Special};
+//!
+//! // the context needs at least 4 sequences, see `LlamaContextParams::with_n_seq_max`
+//! let prompt = ctx.model.str_to_token("English: cheese\nFrench:", AddBos::Always)?;
+//! let beams = BeamSearch::new(NonZeroUsize::new(4).unwrap(), 32)
+//!     .with_length_penalty(0.6)
+//!     .with_early_stopping(true)
+//!     .run(ctx, &prompt)?;
+//! for beam in beams {
+//!     let text = ctx.model.tokens_to_str(&beam.tokens, Special::Plaintext)?;
+//!     println!("{:.2} {text}", beam.logprob);
+//! }
+//! # Ok(())
+//! # }
+//! ```
+
+use std::num::NonZeroUsize;
+
+use crate::completion::{n_ctx_seq, CompletionError, StopReason};
+use crate::context::LlamaContext;
+use crate::llama_batch::LlamaBatch;
+use crate::sampling::logprobs::top_logprobs;
+use crate::token::LlamaToken;
+
+/// A completion found by [`BeamSearch::run`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct Beam {
+    /// The generated tokens, without the end-of-generation token.
+    pub tokens: Vec<LlamaToken>,
+    /// The sum of the log-probabilities of the generated tokens, including the end-of-generation
+    /// token.
+    pub logprob: f32,
+    /// The log-probability with the length penalty applied, beams are ranked by it.
+    pub score: f32,
+    /// Why the beam ended: [`StopReason::EndOfGeneration`], [`StopReason::Length`] or
+    /// [`StopReason::ContextFull`].
+    pub stop_reason: StopReason,
+}
+
+/// A beam that is still being extended.
+#[derive(Debug)]
+struct Running {
+    tokens: Vec<LlamaToken>,
+    logprob: f32,
+    seq: usize,
+    /// The batch index of the logits of the next token.
+    logits: i32,
+}
+
+/// Beam search settings, see [the module docs](self).
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BeamSearch {
+    width: NonZeroUsize,
+    max_tokens: usize,
+    length_penalty: f32,
+    early_stopping: bool,
+}
+
+impl BeamSearch {
+    /// Search with `width` beams for completions of at most `max_tokens` tokens, with a length
+    /// penalty of 1 and without early stopping.
+    #[must_use]
+    pub fn new(width: NonZeroUsize, max_tokens: usize) -> Self {
+        Self {
+            width,
+            max_tokens,
+            length_penalty: 1.0,
+            early_stopping: false,
+        }
+    }
+
+    /// Set the exponent of the length a beam's log-probability is divided by. Values above 0
+    /// favor longer completions, values below 0 shorter ones.
+    #[must_use]
+    pub fn with_length_penalty(mut self, length_penalty: f32) -> Self {
+        self.length_penalty = length_penalty;
+        self
+    }
+
+    /// Stop as soon as `width` beams are finished. Otherwise the search continues until no running
+    /// beam can score better than the finished ones.
+    #[must_use]
+    pub fn with_early_stopping(mut self, early_stopping: bool) -> Self {
+        self.early_stopping = early_stopping;
+        self
+    }
+
+    /// The number of beams.
+    #[must_use]
+    pub fn width(&self) -> NonZeroUsize {
+        self.width
+    }
+
+    /// Search completions of `prompt` in the sequences `0..width` of `ctx`, which are cleared.
+    /// Returns at most `width` beams, the best first.
+    ///
+    /// # Errors
+    ///
+    /// If the prompt is empty or decoding fails.
+    ///
+    /// # Panics
+    ///
+    /// If the context has fewer sequences than beams or a position does not fit into an [`i32`].
+    pub fn run(
+        &self,
+        ctx: &mut LlamaContext,
+        prompt: &[LlamaToken],
+    ) -> Result<Vec<Beam>, CompletionError> {
+        let width = self.width.get();
+        assert!(
+            width <= ctx.n_seq_max() as usize,
+            "{width} beams need as many sequences, but the context has {}",
+            ctx.n_seq_max()
+        );
+        if prompt.is_empty() {
+            return Err(CompletionError::EmptyPrompt);
+        }
+        for seq in 0..width {
+            clear_seq(ctx, seq);
+        }
+        let n_ctx_seq = n_ctx_seq(ctx);
+        let stop = if self.max_tokens == 0 {
+            Some(StopReason::Length)
+        } else if prompt.len() > n_ctx_seq {
+            Some(StopReason::ContextFull)
+        } else {
+            None
+        };
+        if let Some(stop_reason) = stop {
+            return Ok(vec![Beam {
+                tokens: Vec::new(),
+                logprob: 0.0,
+                score: 0.0,
+                stop_reason,
+            }]);
+        }
+
+        let n_batch = usize::try_from(ctx.n_batch()).expect("n_batch fits into a usize");
+        let mut batch = LlamaBatch::new(n_batch.max(width), 1);
+        for (i, chunk) in prompt.chunks(n_batch).enumerate() {
+            batch.clear();
+            for (j, &token) in chunk.iter().enumerate() {
+                let pos = i32::try_from(i * n_batch + j).expect("position fits into an i32");
+                batch.add(token, pos, &[0], i * n_batch + j + 1 == prompt.len())?;
+            }
+            ctx.decode(&mut batch)?;
+        }
+
+        let mut running = vec![Running {
+            tokens: Vec::new(),
+            logprob: 0.0,
+            seq: 0,
+            logits: batch.n_tokens() - 1,
+        }];
+        let mut finished: Vec<Beam> = Vec::new();
+        loop {
+            // all running beams have the same length
+            let n_generated = running[0].tokens.len() + 1;
+            let next = self.extend(ctx, &running, &mut finished);
+
+            let stop = if n_generated >= self.max_tokens {
+                Some(StopReason::Length)
+            } else if prompt.len() + n_generated > n_ctx_seq {
+                Some(StopReason::ContextFull)
+            } else {
+                None
+            };
+            if let Some(stop_reason) = stop {
+                for (parent, token, logprob) in next {
+                    let mut tokens = running[parent].tokens.clone();
+                    tokens.push(token);
+                    finished.push(self.beam(tokens, logprob, stop_reason));
+                }
+                break;
+            }
+            if next.is_empty() || self.is_done(&finished, &next, n_generated) {
+                break;
+            }
+            running = self.advance(ctx, &mut batch, &running, next, prompt.len())?;
+        }
+
+        for seq in 0..width {
+            clear_seq(ctx, seq);
+        }
+        finished.sort_by(|a, b| b.score.total_cmp(&a.score));
+        finished.truncate(width);
+        Ok(finished)
+    }
+
+    /// The best `width` extensions of the running beams as the parent, the token and the
+    /// log-probability. Extensions that end a beam are moved to `finished` if they are among the
+    /// best `width`.
+    fn extend(
+        &self,
+        ctx: &LlamaContext,
+        running: &[Running],
+        finished: &mut Vec<Beam>,
+    ) -> Vec<(usize, LlamaToken, f32)> {
+        let width = self.width.get();
+        let mut candidates = Vec::new();
+        for (parent, beam) in running.iter().enumerate() {
+            let logits = ctx.token_data_array_ith(beam.logits);
+            for (token, logprob) in top_logprobs(&logits, 2 * width) {
+                candidates.push((parent, token, beam.logprob + logprob));
+            }
+        }
+        candidates.sort_by(|a, b| b.2.total_cmp(&a.2));
+
+        let mut next = Vec::new();
+        for (rank, (parent, token, logprob)) in candidates.into_iter().enumerate() {
+            if next.len() == width {
+                break;
+            }
+            if ctx.model.is_eog_token(token) {
+                if rank < width {
+                    let tokens = running[parent].tokens.clone();
+                    finished.push(self.beam(tokens, logprob, StopReason::EndOfGeneration));
+                }
+            } else {
+                next.push((parent, token, logprob));
+            }
+        }
+        finished.sort_by(|a, b| b.score.total_cmp(&a.score));
+        finished.truncate(width);
+        next
+    }
+
+    /// Move the extensions into sequences and decode their tokens.
+    fn advance(
+        &self,
+        ctx: &mut LlamaContext,
+        batch: &mut LlamaBatch,
+        running: &[Running],
+        next: Vec<(usize, LlamaToken, f32)>,
+        n_prompt: usize,
+    ) -> Result<Vec<Running>, CompletionError> {
+        let width = self.width.get();
+        let parents: Vec<_> = next.iter().map(|&(parent, _, _)| parent).collect();
+        let parent_seqs: Vec<_> = running.iter().map(|beam| beam.seq).collect();
+        let (seqs, copies) = assign_sequences(&parents, &parent_seqs, width);
+        for (src, dest) in copies {
+            clear_seq(ctx, dest);
+            let src = i32::try_from(src).expect("sequence id fits into an i32");
+            let dest = i32::try_from(dest).expect("sequence id fits into an i32");
+            ctx.copy_kv_cache_seq(src, dest, None, None)
+                .expect("whole sequences are copied");
+        }
+
+        batch.clear();
+        let pos = n_prompt + running[0].tokens.len();
+        let pos = i32::try_from(pos).expect("position fits into an i32");
+        let mut extended = Vec::with_capacity(next.len());
+        for ((parent, token, logprob), seq) in next.into_iter().zip(seqs) {
+            let seq_id = i32::try_from(seq).expect("sequence id fits into an i32");
+            batch.add(token, pos, &[seq_id], true)?;
+            let mut tokens = running[parent].tokens.clone();
+            tokens.push(token);
+            extended.push(Running {
+                tokens,
+                logprob,
+                seq,
+                logits: batch.n_tokens() - 1,
+            });
+        }
+        for seq in 0..width {
+            if !extended.iter().any(|beam| beam.seq == seq) {
+                clear_seq(ctx, seq);
+            }
+        }
+        ctx.decode(batch)?;
+        Ok(extended)
+    }
+
+    /// Whether no running beam can get into the best `width` finished ones.
+    fn is_done(
+        &self,
+        finished: &[Beam],
+        next: &[(usize, LlamaToken, f32)],
+        n_generated: usize,
+    ) -> bool {
+        if finished.len() < self.width.get() {
+            return false;
+        }
+        if self.early_stopping {
+            return true;
+        }
+        let worst = finished.last().map_or(f32::NEG_INFINITY, |beam| beam.score);
+        next.iter()
+            .all(|&(_, _, logprob)| self.score(logprob, n_generated) <= worst)
+    }
+
+    fn beam(&self, tokens: Vec<LlamaToken>, logprob: f32, stop_reason: StopReason) -> Beam {
+        // the length includes the end-of-generation token
+        let len = tokens.len() + usize::from(stop_reason == StopReason::EndOfGeneration);
+        Beam {
+            score: self.score(logprob, len),
+            tokens,
+            logprob,
+            stop_reason,
+        }
+    }
+
+    #[allow(clippy::cast_precision_loss)]
+    fn score(&self, logprob: f32, len: usize) -> f32 {
+        logprob / (len as f32).powf(self.length_penalty)
+    }
+}
+
+/// Choose the sequence of each extended beam, given the parent of each and the sequences of the
+/// parents. The first extension of a parent keeps its sequence, the others get a free one that
+/// the parent's sequence has to be copied to. Returns the sequences and the copies.
+fn assign_sequences(
+    parents: &[usize],
+    parent_seqs: &[usize],
+    n_seq: usize,
+) -> (Vec<usize>, Vec<(usize, usize)>) {
+    let mut used = vec![false; n_seq];
+    let mut seqs: Vec<Option<usize>> = vec![None; parents.len()];
+    for (i, &parent) in parents.iter().enumerate() {
+        let seq = parent_seqs[parent];
+        if !used[seq] {
+            used[seq] = true;
+            seqs[i] = Some(seq);
+        }
+    }
+    let mut free = (0..n_seq).filter(|&seq| !used[seq]);
+    let mut copies = Vec::new();
+    let seqs = seqs
+        .into_iter()
+        .zip(parents)
+        .map(|(seq, &parent)| {
+            seq.unwrap_or_else(|| {
+                let dest = free.next().expect("there is a sequence for every beam");
+                copies.push((parent_seqs[parent], dest));
+                dest
+            })
+        })
+        .collect();
+    (seqs, copies)
+}
+
+fn clear_seq(ctx: &mut LlamaContext, seq: usize) {
+    let seq = u32::try_from(seq).expect("sequence id fits into a u32");
+    ctx.clear_kv_cache_seq(Some(seq), None, None)
+        .expect("sequence id fits into an i32");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn assign_sequences_copies_shared_parents() {
+        // parent 0 is in sequence 2 and has two extensions, parent 1 (sequence 0) none and
+        // parent 2 (sequence 1) one
+        let (seqs, copies) = assign_sequences(&[0, 2, 0], &[2, 0, 1], 3);
+        assert_eq!(seqs, [2, 1, 0]);
+        assert_eq!(copies, [(2, 0)]);
+    }
+}
@@ -0,0 +1,289 @@
+//! Classifier-free guidance with a negative prompt.
+//!
+//! [`GuidedCompletion`] evaluates a prompt and a negative prompt as sequences 0 and 1 of one
+//! context, in the same batches, and appends every generated token to both. Before sampling, the
+//! logits are combined as `guidance_scale * (positive - negative) + negative` by
+//! [`guided_token_data_array`], which moves the output away from what the negative prompt makes
+//! likely, for example an unwanted style. A scale of 1 is the same as no guidance.
+//!
+//! ```no_run
+//! # use llama_cpp_2::context::LlamaContext;
+//! # fn example(ctx: &mut LlamaContext) -> Result<(), Box<dyn std::error::Error>> {
+//! use llama_cpp_2::completion::guidance::GuidedCompletion;
+//! use llama_cpp_2::model::AddBos;
+//! use llama_cpp_2::sampling::LlamaSampler;
+//!
+//! // the context needs at least 2 sequences, see `LlamaContextParams::with_n_seq_max`
+//! let prompt = ctx.model.str_to_token("A formal letter:\n", AddBos::Always)?;
+//! let negative = ctx.model.str_to_token("A casual letter:\n", AddBos::Always)?;
+//! let sampler = LlamaSampler::chain_simple([LlamaSampler::top_k(40), LlamaSampler::dist(42)]);
+//! let completion = GuidedCompletion::new(ctx, &prompt, &negative, sampler, 1.5)
+//!     .with_max_tokens(128);
+//! for token in completion {
+//!     print!("{}", token?.text);
+//! }
+//! # Ok(())
+//! # }
+//! ```
+
+use crate::completion::{n_ctx_seq, CompletionError, CompletionToken, StopReason, TokenOutput};
+use crate::context::LlamaContext;
+use crate::detokenize::StreamingDetokenizer;
+use crate::llama_batch::LlamaBatch;
+use crate::sampling::LlamaSampler;
+use crate::stop::StopSequences;
+use crate::token::data::LlamaTokenData;
+use crate::token::data_array::LlamaTokenDataArray;
+use crate::token::LlamaToken;
+
+/// Combine the logits of the prompt and the negative prompt as
+/// `guidance_scale * (positive - negative) + negative`.
+///
+/// # Panics
+///
+/// If the logits have different lengths or there are more than [`i32::MAX`] of them.
+#[must_use]
+pub fn guided_token_data_array(
+    positive: &[f32],
+    negative: &[f32],
+    guidance_scale: f32,
+) -> LlamaTokenDataArray {
+    assert_eq!(
+        positive.len(),
+        negative.len(),
+        "the logits must have the same length"
+    );
+    LlamaTokenDataArray::from_iter(
+        positive
+            .iter()
+            .zip(negative)
+            .enumerate()
+            .map(|(i, (&pos, &neg))| {
+                let id = LlamaToken(i32::try_from(i).expect("token fits into an i32"));
+                LlamaTokenData::new(id, guidance_scale * (pos - neg) + neg, 0.0)
+            }),
+        false,
+    )
+}
+
+/// The tokens of one of the prompts.
+#[derive(Debug)]
+struct Sequence {
+    id: i32,
+    /// Tokens to evaluate before the next token can be sampled.
+    pending: Vec<LlamaToken>,
+    /// The number of tokens in the KV cache.
+    n_past: usize,
+}
+
+/// Generates tokens with classifier-free guidance, see [the module docs](self).
+///
+/// Like [`crate::completion::Completion`], each item is a sampled token.
+#[derive(Debug)]
+pub struct GuidedCompletion<'a, 'm> {
+    ctx: &'a mut LlamaContext<'m>,
+    sampler: LlamaSampler,
+    output: TokenOutput<'m>,
+    guidance_scale: f32,
+    batch: LlamaBatch<'static>,
+    positive: Sequence,
+    negative: Sequence,
+    stop_reason: Option<StopReason>,
+    failed: bool,
+}
+
+impl<'a, 'm> GuidedCompletion<'a, 'm> {
+    /// Generate a completion of `prompt` guided away from `negative_prompt`, in sequences 0 and 1
+    /// of `ctx`, which are cleared. `sampler` is applied to the combined logits, so it must select
+    /// a token, for example by ending with [`LlamaSampler::dist`] or [`LlamaSampler::greedy`],
+    /// otherwise generation fails with [`CompletionError::NoTokenSelected`]. Nothing is evaluated
+    /// before the first call to [`Iterator::next`].
+    ///
+    /// # Panics
+    ///
+    /// If the context has fewer than 2 sequences or its batch size does not fit into a [`usize`].
+    #[must_use]
+    pub fn new(
+        ctx: &'a mut LlamaContext<'m>,
+        prompt: &[LlamaToken],
+        negative_prompt: &[LlamaToken],
+        sampler: LlamaSampler,
+        guidance_scale: f32,
+    ) -> Self {
+        assert!(
+            ctx.n_seq_max() >= 2,
+            "guidance needs 2 sequences, but the context has {}",
+            ctx.n_seq_max()
+        );
+        let model = ctx.model;
+        let n_batch = usize::try_from(ctx.n_batch()).expect("n_batch fits into a usize");
+        Self {
+            ctx,
+            sampler,
+            output: TokenOutput::new(model),
+            guidance_scale,
+            batch: LlamaBatch::new(n_batch.max(2), 1),
+            positive: Sequence {
+                id: 0,
+                pending: prompt.to_vec(),
+                n_past: 0,
+            },
+            negative: Sequence {
+                id: 1,
+                pending: negative_prompt.to_vec(),
+                n_past: 0,
+            },
+            stop_reason: None,
+            failed: false,
+        }
+    }
+
+    /// See [`crate::completion::Completion::with_max_tokens`].
+    #[must_use]
+    pub fn with_max_tokens(mut self, max_tokens: usize) -> Self {
+        self.output.max_tokens = Some(max_tokens);
+        self
+    }
+
+    /// See [`crate::completion::Completion::with_stops`].
+    #[must_use]
+    pub fn with_stops(mut self, stops: impl IntoIterator<Item = impl Into<String>>) -> Self {
+        self.output.stops = StopSequences::new(stops);
+        self
+    }
+
+    /// See [`crate::completion::Completion::with_detokenizer`].
+    #[must_use]
+    pub fn with_detokenizer(mut self, detokenizer: StreamingDetokenizer<'m>) -> Self {
+        self.output.detokenizer = detokenizer;
+        self
+    }
+
+    /// Why the generation stopped, `None` while it is running or after an error.
+    #[must_use]
+    pub fn stop_reason(&self) -> Option<StopReason> {
+        self.stop_reason
+    }
+
+    /// The number of tokens generated so far.
+    #[must_use]
+    pub fn n_generated(&self) -> usize {
+        self.output.n_generated
+    }
+
+    /// The context the completion runs in.
+    #[must_use]
+    pub fn context(&self) -> &LlamaContext<'m> {
+        self.ctx
+    }
+
+    /// Evaluate the pending tokens of both sequences. The last token of each goes into the final
+    /// batch, with its logits at index 0 for the prompt and 1 for the negative prompt.
+    fn evaluate(&mut self) -> Result<(), CompletionError> {
+        let n_batch = usize::try_from(self.ctx.n_batch()).expect("n_batch fits into a usize");
+        let prefix: Vec<_> = [&self.positive, &self.negative]
+            .into_iter()
+            .flat_map(|seq| {
+                let n = seq.pending.len() - 1;
+                (0..n).map(move |i| (seq.pending[i], seq.n_past + i, seq.id))
+            })
+            .collect();
+        for chunk in prefix.chunks(n_batch) {
+            self.batch.clear();
+            for &(token, pos, seq) in chunk {
+                let pos = i32::try_from(pos).expect("position fits into an i32");
+                self.batch.add(token, pos, &[seq], false)?;
+            }
+            self.ctx.decode(&mut self.batch)?;
+        }
+
+        self.batch.clear();
+        for seq in [&mut self.positive, &mut self.negative] {
+            let token = *seq.pending.last().expect("sequences have pending tokens");
+            seq.n_past += seq.pending.len();
+            seq.pending.clear();
+            let pos = i32::try_from(seq.n_past - 1).expect("position fits into an i32");
+            self.batch.add(token, pos, &[seq.id], true)?;
+        }
+        self.ctx.decode(&mut self.batch)?;
+        Ok(())
+    }
+
+    fn step(&mut self) -> Result<Option<CompletionToken>, CompletionError> {
+        if self.positive.pending.is_empty() || self.negative.pending.is_empty() {
+            return Err(CompletionError::EmptyPrompt);
+        }
+        let n_ctx_seq = n_ctx_seq(self.ctx);
+        let full = |seq: &Sequence| seq.n_past + seq.pending.len() > n_ctx_seq;
+        let stop = if self.output.at_max_tokens() {
+            Some(StopReason::Length)
+        } else if full(&self.positive) || full(&self.negative) {
+            Some(StopReason::ContextFull)
+        } else {
+            None
+        };
+        if stop.is_some() {
+            self.stop_reason = stop;
+            return Ok(None);
+        }
+
+        if self.positive.n_past == 0 {
+            for seq in [0, 1] {
+                self.ctx
+                    .clear_kv_cache_seq(Some(seq), None, None)
+                    .expect("sequence id fits into an i32");
+            }
+        }
+        self.evaluate()?;
+        let mut candidates = guided_token_data_array(
+            self.ctx.get_logits_ith(0),
+            self.ctx.get_logits_ith(1),
+            self.guidance_scale,
+        );
+        candidates.apply_sampler(&self.sampler);
+        let token = candidates
+            .selected_token()
+            .ok_or(CompletionError::NoTokenSelected)?;
+        self.sampler.accept(token);
+
+        let context_full = self.positive.n_past.max(self.negative.n_past) >= n_ctx_seq;
+        let (token, stop) = self.output.push(token, context_full)?;
+        if stop.is_some() {
+            self.stop_reason = stop;
+        } else {
+            self.positive.pending.push(token.token);
+            self.negative.pending.push(token.token);
+        }
+        Ok(Some(token))
+    }
+}
+
+impl Iterator for GuidedCompletion<'_, '_> {
+    type Item = Result<CompletionToken, CompletionError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.stop_reason.is_some() || self.failed {
+            return None;
+        }
+        let result = self.step().transpose();
+        self.failed = matches!(result, Some(Err(_)));
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn combine_logits() {
+        let candidates = guided_token_data_array(&[1.0, 2.0, -1.0], &[0.0, 3.0, -1.0], 1.5);
+        let logits: Vec<_> = candidates.data.iter().map(LlamaTokenData::logit).collect();
+        assert_eq!(logits, [1.5, 1.5, -1.0]);
+        assert_eq!(candidates.data[2].id(), LlamaToken(2));
+
+        let unguided = guided_token_data_array(&[1.0, 2.0], &[5.0, -3.0], 1.0);
+        let logits: Vec<_> = unguided.data.iter().map(LlamaTokenData::logit).collect();
+        assert_eq!(logits, [1.0, 2.0]);
+    }
+}
@@ -0,0 +1,343 @@
+//! Several completions of one prompt, like the `n` parameter of the `OpenAI` API.
+//!
+//! [`NBest`] evaluates the prompt once in sequence 0, copies its KV cache into the sequences
+//! `1..n` and then generates all completions together: every step decodes the last token of each
+//! unfinished completion in one batch and samples each with its own sampler. The completions are
+//! the basis of best-of-N selection or self-consistency voting.
+//!
+//! ```no_run
+//! # use llama_cpp_2::context::LlamaContext;
+//! # fn example(ctx: &mut LlamaContext) -> Result<(), Box<dyn std::error::Error>> {
+//! use llama_cpp_2::completion::n_best::NBest;
+//! use llama_cpp_2::model::AddBos;
+//! use llama_cpp_2::sampling::config::SamplerConfig;
+//!
+//! // the context needs at least 4 sequences, see `LlamaContextParams::with_n_seq_max`
+//! let prompt = ctx.model.str_to_token("The capital of France is", AddBos::Always)?;
+//! let config = SamplerConfig {
+//!     seed: 42,
+//!     ..SamplerConfig::default()
+//! };
+//! let completions = NBest::from_config(ctx, &prompt, &config, 4)?
+//!     .with_max_tokens(16)
+//!     .run()?;
+//! for completion in completions {
+//!     println!("{:?}: {}", completion.stop_reason, completion.text);
+//! }
+//! # Ok(())
+//! # }
+//! ```
+
+use crate::completion::{n_ctx_seq, CompletionError, CompletionToken, StopReason, TokenOutput};
+use crate::context::LlamaContext;
+use crate::detokenize::StreamingDetokenizer;
+use crate::llama_batch::LlamaBatch;
+use crate::sampling::config::{SamplerConfig, DEFAULT_SEED};
+use crate::sampling::LlamaSampler;
+use crate::stop::StopSequences;
+use crate::token::LlamaToken;
+use crate::GrammarError;
+
+/// A finished completion of [`NBest::run`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NBestCompletion {
+    /// The generated tokens.
+    pub tokens: Vec<LlamaToken>,
+    /// The generated text, without a stop sequence.
+    pub text: String,
+    /// Why the completion stopped.
+    pub stop_reason: StopReason,
+}
+
+/// One of the completions, generated in its own sequence.
+#[derive(Debug)]
+struct Branch<'m> {
+    sampler: LlamaSampler,
+    output: TokenOutput<'m>,
+    tokens: Vec<LlamaToken>,
+    text: String,
+    /// The number of tokens in the sequence.
+    n_past: usize,
+    stop_reason: Option<StopReason>,
+}
+
+/// Generates several completions of one prompt, see [the module docs](self).
+#[derive(Debug)]
+pub struct NBest<'a, 'm> {
+    ctx: &'a mut LlamaContext<'m>,
+    prompt: Vec<LlamaToken>,
+    batch: LlamaBatch<'static>,
+    branches: Vec<Branch<'m>>,
+    started: bool,
+}
+
+impl<'a, 'm> NBest<'a, 'm> {
+    /// Generate one completion of `prompt` per sampler, in the sequences `0..n` of `ctx`, which
+    /// are cleared. Nothing is evaluated before the first call to [`Self::step`].
+    ///
+    /// # Panics
+    ///
+    /// If there are no samplers, more samplers than sequences in the context or the batch size of
+    /// the context does not fit into a [`usize`].
+    #[must_use]
+    pub fn new(
+        ctx: &'a mut LlamaContext<'m>,
+        prompt: &[LlamaToken],
+        samplers: impl IntoIterator<Item = LlamaSampler>,
+    ) -> Self {
+        let model = ctx.model;
+        let branches: Vec<_> = samplers
+            .into_iter()
+            .map(|sampler| Branch {
+                sampler,
+                output: TokenOutput::new(model),
+                tokens: Vec::new(),
+                text: String::new(),
+                n_past: 0,
+                stop_reason: None,
+            })
+            .collect();
+        assert!(!branches.is_empty(), "at least one sampler is required");
+        assert!(
+            branches.len() <= ctx.n_seq_max() as usize,
+            "{} completions need as many sequences, but the context has {}",
+            branches.len(),
+            ctx.n_seq_max()
+        );
+        let n_batch = usize::try_from(ctx.n_batch()).expect("n_batch fits into a usize");
+        Self {
+            ctx,
+            prompt: prompt.to_vec(),
+            batch: LlamaBatch::new(n_batch.max(branches.len()), 1),
+            branches,
+            started: false,
+        }
+    }
+
+    /// Generate `n` completions with samplers built from `config`. Each gets its own seed,
+    /// `config.seed + i`, so the completions differ but can be reproduced. With
+    /// [`DEFAULT_SEED`] every sampler gets a random seed.
+    ///
+    /// # Errors
+    ///
+    /// If [`SamplerConfig::build`] fails.
+    ///
+    /// # Panics
+    ///
+    /// See [`Self::new`].
+    pub fn from_config(
+        ctx: &'a mut LlamaContext<'m>,
+        prompt: &[LlamaToken],
+        config: &SamplerConfig,
+        n: u32,
+    ) -> Result<Self, GrammarError> {
+        let samplers = (0..n)
+            .map(|i| {
+                let mut config = config.clone();
+                if config.seed != DEFAULT_SEED {
+                    config.seed = config.seed.wrapping_add(i);
+                }
+                config.build(ctx.model)
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(Self::new(ctx, prompt, samplers))
+    }
+
+    /// Stop each completion after `max_tokens` tokens.
+    #[must_use]
+    pub fn with_max_tokens(mut self, max_tokens: usize) -> Self {
+        for branch in &mut self.branches {
+            branch.output.max_tokens = Some(max_tokens);
+        }
+        self
+    }
+
+    /// Stop each completion when any of `stops` is generated.
+    #[must_use]
+    pub fn with_stops(mut self, stops: impl IntoIterator<Item = impl Into<String>>) -> Self {
+        let stops = StopSequences::new(stops);
+        for branch in &mut self.branches {
+            branch.output.stops = stops.clone();
+        }
+        self
+    }
+
+    /// Convert the tokens of each completion to text with a copy of `detokenizer`.
+    #[must_use]
+    pub fn with_detokenizer(mut self, detokenizer: &StreamingDetokenizer<'m>) -> Self {
+        for branch in &mut self.branches {
+            branch.output.detokenizer = detokenizer.clone();
+        }
+        self
+    }
+
+    /// The number of completions.
+    #[must_use]
+    pub fn n(&self) -> usize {
+        self.branches.len()
+    }
+
+    /// Why completion `i` stopped, `None` while it is running.
+    ///
+    /// # Panics
+    ///
+    /// If `i` is not below [`Self::n`].
+    #[must_use]
+    pub fn stop_reason(&self, i: usize) -> Option<StopReason> {
+        self.branches[i].stop_reason
+    }
+
+    /// Whether all completions stopped.
+    #[must_use]
+    pub fn is_finished(&self) -> bool {
+        self.branches.iter().all(|b| b.stop_reason.is_some())
+    }
+
+    /// Generate the next token of every running completion. The first call evaluates the prompt.
+    /// Returns the index of each completion that got a token with the token, or nothing once all
+    /// completions stopped.
+    ///
+    /// # Errors
+    ///
+    /// If the prompt is empty, decoding fails or a token can not be converted to text. The
+    /// completions can not be continued after an error.
+    ///
+    /// # Panics
+    ///
+    /// If a position does not fit into an [`i32`].
+    pub fn step(&mut self) -> Result<Vec<(usize, CompletionToken)>, CompletionError> {
+        if self.started {
+            self.generate()
+        } else {
+            self.start()
+        }
+    }
+
+    /// Run all completions to the end.
+    ///
+    /// # Errors
+    ///
+    /// See [`Self::step`].
+    ///
+    /// # Panics
+    ///
+    /// See [`Self::step`].
+    pub fn run(mut self) -> Result<Vec<NBestCompletion>, CompletionError> {
+        while !self.is_finished() {
+            self.step()?;
+        }
+        Ok(self
+            .branches
+            .into_iter()
+            .map(|branch| NBestCompletion {
+                tokens: branch.tokens,
+                text: branch.text,
+                stop_reason: branch.stop_reason.expect("all completions stopped"),
+            })
+            .collect())
+    }
+
+    /// Evaluate the prompt in sequence 0, copy it to the other sequences and sample the first
+    /// token of every completion from its logits.
+    fn start(&mut self) -> Result<Vec<(usize, CompletionToken)>, CompletionError> {
+        if self.prompt.is_empty() {
+            return Err(CompletionError::EmptyPrompt);
+        }
+        self.started = true;
+        for seq in 0..self.branches.len() {
+            self.clear_seq(seq);
+        }
+        let stop = if self.branches[0].output.at_max_tokens() {
+            Some(StopReason::Length)
+        } else if self.prompt.len() > n_ctx_seq(self.ctx) {
+            Some(StopReason::ContextFull)
+        } else {
+            None
+        };
+        if stop.is_some() {
+            for branch in &mut self.branches {
+                branch.stop_reason = stop;
+            }
+            return Ok(Vec::new());
+        }
+
+        let n_batch = usize::try_from(self.ctx.n_batch()).expect("n_batch fits into a usize");
+        for (i, chunk) in self.prompt.chunks(n_batch).enumerate() {
+            self.batch.clear();
+            for (j, &token) in chunk.iter().enumerate() {
+                let pos = i32::try_from(i * n_batch + j).expect("position fits into an i32");
+                let last = i * n_batch + j + 1 == self.prompt.len();
+                self.batch.add(token, pos, &[0], last)?;
+            }
+            self.ctx.decode(&mut self.batch)?;
+        }
+        for seq in 1..self.branches.len() {
+            let seq = i32::try_from(seq).expect("sequence id fits into an i32");
+            self.ctx
+                .copy_kv_cache_seq(0, seq, None, None)
+                .expect("whole sequences are copied");
+        }
+
+        let logits = self.batch.n_tokens() - 1;
+        let n_prompt = self.prompt.len();
+        (0..self.branches.len())
+            .map(|i| self.sample(i, logits, n_prompt))
+            .collect()
+    }
+
+    /// Decode the last token of every running completion and sample the next ones.
+    fn generate(&mut self) -> Result<Vec<(usize, CompletionToken)>, CompletionError> {
+        self.batch.clear();
+        let mut scheduled = Vec::new();
+        for (i, branch) in self.branches.iter().enumerate() {
+            if branch.stop_reason.is_some() {
+                continue;
+            }
+            let token = *branch
+                .tokens
+                .last()
+                .expect("running completions have a token");
+            let pos = i32::try_from(branch.n_past).expect("position fits into an i32");
+            let seq = i32::try_from(i).expect("sequence id fits into an i32");
+            self.batch.add(token, pos, &[seq], true)?;
+            scheduled.push((i, self.batch.n_tokens() - 1, branch.n_past + 1));
+        }
+        if scheduled.is_empty() {
+            return Ok(Vec::new());
+        }
+        self.ctx.decode(&mut self.batch)?;
+        scheduled
+            .into_iter()
+            .map(|(i, logits, n_past)| self.sample(i, logits, n_past))
+            .collect()
+    }
+
+    /// Sample the next token of completion `i` from the logits at batch index `logits`, after its
+    /// sequence reached `n_past` tokens.
+    fn sample(
+        &mut self,
+        i: usize,
+        logits: i32,
+        n_past: usize,
+    ) -> Result<(usize, CompletionToken), CompletionError> {
+        let n_ctx_seq = n_ctx_seq(self.ctx);
+        let branch = &mut self.branches[i];
+        branch.n_past = n_past;
+        let token = branch.sampler.sample(self.ctx, logits);
+        let (token, stop) = branch.output.push(token, n_past >= n_ctx_seq)?;
+        branch.tokens.push(token.token);
+        branch.text.push_str(&token.text);
+        if stop.is_some() {
+            branch.stop_reason = stop;
+            self.clear_seq(i);
+        }
+        Ok((i, token))
+    }
+
+    fn clear_seq(&mut self, seq: usize) {
+        let seq = u32::try_from(seq).expect("sequence id fits into a u32");
+        self.ctx
+            .clear_kv_cache_seq(Some(seq), None, None)
+            .expect("sequence id fits into an i32");
+    }
+}
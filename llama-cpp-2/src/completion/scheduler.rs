@@ -0,0 +1,357 @@
+//! Continuous batching: serving many generation requests from one context.
+//!
+//! A [`Scheduler`] assigns each [`GenerationRequest`] to a free sequence of the context (see
+//! [`crate::context::params::LlamaContextParams::with_n_seq_max`]) and queues it while all
+//! sequences are in use. Every call to [`Scheduler::step`] packs the next tokens of the generating
+//! requests and chunks of the prompts still being evaluated into one batch, decodes it once and
+//! samples each request from its own logits. Requests start and finish independently.
+//!
+//! ```no_run
+//! # use llama_cpp_2::context::LlamaContext;
+//! # use llama_cpp_2::token::LlamaToken;
+//! # fn example(ctx: &mut LlamaContext, prompts: Vec<Vec<LlamaToken>>) -> Result<(), Box<dyn std::error::Error>> {
+//! use llama_cpp_2::completion::scheduler::{GenerationRequest, Scheduler, SchedulerEvent};
+//! use llama_cpp_2::sampling::LlamaSampler;
+//!
+//! let mut scheduler = Scheduler::new(ctx);
+//! for prompt in prompts {
+//!     scheduler.submit(GenerationRequest::new(prompt, LlamaSampler::greedy()).with_max_tokens(64));
+//! }
+//! while !scheduler.is_idle() {
+//!     for event in scheduler.step()? {
+//!         match event {
+//!             SchedulerEvent::Token { id, token } => println!("{id:?}: {:?}", token.text),
+//!             SchedulerEvent::Finished { id, reason } => println!("{id:?} finished: {reason:?}"),
+//!             SchedulerEvent::Failed { id, error } => println!("{id:?} failed: {error}"),
+//!         }
+//!     }
+//! }
+//! # Ok(())
+//! # }
+//! ```
+
+use std::collections::VecDeque;
+
+use crate::completion::{n_ctx_seq, CompletionError, CompletionToken, StopReason, TokenOutput};
+use crate::context::LlamaContext;
+use crate::detokenize::{SpecialTokens, StreamingDetokenizer};
+use crate::llama_batch::LlamaBatch;
+use crate::sampling::LlamaSampler;
+use crate::stop::StopSequences;
+use crate::token::LlamaToken;
+
+/// Identifies a request submitted to a [`Scheduler`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct RequestId(u64);
+
+/// A request for a [`Scheduler`], with its own sampler.
+#[derive(Debug)]
+pub struct GenerationRequest {
+    prompt: Vec<LlamaToken>,
+    sampler: LlamaSampler,
+    max_tokens: Option<usize>,
+    stops: Vec<String>,
+    special: SpecialTokens,
+}
+
+impl GenerationRequest {
+    /// A request to complete `prompt`, sampling with `sampler`.
+    #[must_use]
+    pub fn new(prompt: Vec<LlamaToken>, sampler: LlamaSampler) -> Self {
+        Self {
+            prompt,
+            sampler,
+            max_tokens: None,
+            stops: Vec::new(),
+            special: SpecialTokens::default(),
+        }
+    }
+
+    /// See [`crate::completion::Completion::with_max_tokens`].
+    #[must_use]
+    pub fn with_max_tokens(mut self, max_tokens: usize) -> Self {
+        self.max_tokens = Some(max_tokens);
+        self
+    }
+
+    /// See [`crate::completion::Completion::with_stops`].
+    #[must_use]
+    pub fn with_stops(mut self, stops: impl IntoIterator<Item = impl Into<String>>) -> Self {
+        self.stops = stops.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// See [`StreamingDetokenizer::with_special`].
+    #[must_use]
+    pub fn with_special(mut self, special: SpecialTokens) -> Self {
+        self.special = special;
+        self
+    }
+}
+
+/// Something that happened to a request during [`Scheduler::step`].
+#[derive(Debug)]
+pub enum SchedulerEvent {
+    /// A token was generated, see [`crate::completion::Completion`] for the text it carries.
+    Token {
+        /// The request.
+        id: RequestId,
+        /// The token and its text.
+        token: CompletionToken,
+    },
+    /// The request finished and its sequence is free again. This follows the last token.
+    Finished {
+        /// The request.
+        id: RequestId,
+        /// Why it finished.
+        reason: StopReason,
+    },
+    /// The request failed and was removed.
+    Failed {
+        /// The request.
+        id: RequestId,
+        /// What went wrong.
+        error: CompletionError,
+    },
+}
+
+/// A request that owns a sequence.
+#[derive(Debug)]
+struct Slot<'m> {
+    id: RequestId,
+    sampler: LlamaSampler,
+    output: TokenOutput<'m>,
+    /// Tokens to evaluate before the next token can be sampled.
+    pending: Vec<LlamaToken>,
+    /// The number of tokens in the sequence.
+    n_past: usize,
+}
+
+/// Runs many requests in the sequences of one context, see [the module docs](self).
+///
+/// Each sequence can hold `n_ctx / n_seq_max` tokens; a request that needs more stops with
+/// [`StopReason::ContextFull`].
+#[derive(Debug)]
+pub struct Scheduler<'a, 'm> {
+    ctx: &'a mut LlamaContext<'m>,
+    batch: LlamaBatch<'static>,
+    /// The request in each sequence, indexed by sequence id.
+    slots: Vec<Option<Slot<'m>>>,
+    queue: VecDeque<(RequestId, GenerationRequest)>,
+    next_id: u64,
+}
+
+impl<'a, 'm> Scheduler<'a, 'm> {
+    /// Schedule requests onto the sequences of `ctx`. Sequences are cleared when a request starts.
+    ///
+    /// # Panics
+    ///
+    /// If the batch size of the context does not fit into a [`usize`].
+    #[must_use]
+    pub fn new(ctx: &'a mut LlamaContext<'m>) -> Self {
+        let n_batch = usize::try_from(ctx.n_batch()).expect("n_batch fits into a usize");
+        let slots = (0..ctx.n_seq_max()).map(|_| None).collect();
+        Self {
+            ctx,
+            batch: LlamaBatch::new(n_batch, 1),
+            slots,
+            queue: VecDeque::new(),
+            next_id: 0,
+        }
+    }
+
+    /// Queue a request. It starts at the next [`Self::step`] with a free sequence.
+    pub fn submit(&mut self, request: GenerationRequest) -> RequestId {
+        let id = RequestId(self.next_id);
+        self.next_id += 1;
+        self.queue.push_back((id, request));
+        id
+    }
+
+    /// Remove a queued or running request without further events. Returns whether it was found.
+    pub fn cancel(&mut self, id: RequestId) -> bool {
+        if let Some(i) = self.queue.iter().position(|(queued, _)| *queued == id) {
+            self.queue.remove(i);
+            return true;
+        }
+        let running = self
+            .slots
+            .iter()
+            .position(|slot| slot.as_ref().is_some_and(|slot| slot.id == id));
+        if let Some(seq) = running {
+            self.free(seq);
+        }
+        running.is_some()
+    }
+
+    /// The number of requests that own a sequence.
+    #[must_use]
+    pub fn n_running(&self) -> usize {
+        self.slots.iter().flatten().count()
+    }
+
+    /// The number of requests waiting for a sequence.
+    #[must_use]
+    pub fn n_queued(&self) -> usize {
+        self.queue.len()
+    }
+
+    /// Whether there are no requests left.
+    #[must_use]
+    pub fn is_idle(&self) -> bool {
+        self.queue.is_empty() && self.n_running() == 0
+    }
+
+    /// The context the requests run in.
+    #[must_use]
+    pub fn context(&self) -> &LlamaContext<'m> {
+        self.ctx
+    }
+
+    /// Start queued requests, decode one batch and sample the requests that have logits in it.
+    /// Generating requests get their tokens into the batch first, the rest of it is filled with
+    /// prompt chunks. Returns nothing if there are no requests.
+    ///
+    /// # Errors
+    ///
+    /// If decoding the batch fails. No request advances then and the tokens of the batch are
+    /// removed from the KV cache again, also those of micro-batches llama.cpp already processed,
+    /// so the step can be retried or requests can be cancelled.
+    ///
+    /// # Panics
+    ///
+    /// If a position does not fit into an [`i32`].
+    pub fn step(&mut self) -> Result<Vec<SchedulerEvent>, CompletionError> {
+        let mut events = Vec::new();
+        self.start_queued(&mut events);
+
+        let n_batch = usize::try_from(self.ctx.n_batch()).expect("n_batch fits into a usize");
+        let mut order: Vec<usize> = (0..self.slots.len())
+            .filter(|&seq| self.slots[seq].is_some())
+            .collect();
+        order.sort_by_key(|&seq| self.slots[seq].as_ref().map(|s| s.output.n_generated == 0));
+
+        // the sequence, the number of its tokens and the batch index of its logits
+        let mut scheduled = Vec::new();
+        self.batch.clear();
+        for seq in order {
+            let slot = self.slots[seq]
+                .as_ref()
+                .expect("only running slots are ordered");
+            let space = n_batch - usize::try_from(self.batch.n_tokens()).expect("n_tokens fits");
+            if space == 0 {
+                break;
+            }
+            let n = slot.pending.len().min(space);
+            let seq_id = i32::try_from(seq).expect("sequence id fits into an i32");
+            for (j, &token) in slot.pending[..n].iter().enumerate() {
+                let pos = i32::try_from(slot.n_past + j).expect("position fits into an i32");
+                self.batch
+                    .add(token, pos, &[seq_id], j + 1 == slot.pending.len())?;
+            }
+            let logits = (n == slot.pending.len()).then(|| self.batch.n_tokens() - 1);
+            scheduled.push((seq, n, logits));
+        }
+        if scheduled.is_empty() {
+            return Ok(events);
+        }
+        if let Err(error) = self.ctx.decode(&mut self.batch) {
+            for &(seq, _, _) in &scheduled {
+                let slot = self.slots[seq]
+                    .as_ref()
+                    .expect("scheduled slots are running");
+                self.clear_seq(seq, slot.n_past);
+            }
+            return Err(error.into());
+        }
+
+        let n_ctx_seq = n_ctx_seq(self.ctx);
+        for (seq, n, logits) in scheduled {
+            let slot = self.slots[seq]
+                .as_mut()
+                .expect("scheduled slots are running");
+            slot.pending.drain(..n);
+            slot.n_past += n;
+            let Some(i) = logits else {
+                continue;
+            };
+            let token = slot.sampler.sample(self.ctx, i);
+            let id = slot.id;
+            match slot.output.push(token, slot.n_past >= n_ctx_seq) {
+                Ok((token, None)) => {
+                    slot.pending.push(token.token);
+                    events.push(SchedulerEvent::Token { id, token });
+                }
+                Ok((token, Some(reason))) => {
+                    events.push(SchedulerEvent::Token { id, token });
+                    events.push(SchedulerEvent::Finished { id, reason });
+                    self.free(seq);
+                }
+                Err(error) => {
+                    events.push(SchedulerEvent::Failed {
+                        id,
+                        error: error.into(),
+                    });
+                    self.free(seq);
+                }
+            }
+        }
+        Ok(events)
+    }
+
+    /// Move queued requests into free sequences, finishing those that can not start.
+    fn start_queued(&mut self, events: &mut Vec<SchedulerEvent>) {
+        let n_ctx_seq = n_ctx_seq(self.ctx);
+        while let Some(seq) = self.slots.iter().position(Option::is_none) {
+            let Some((id, request)) = self.queue.pop_front() else {
+                break;
+            };
+            if request.prompt.is_empty() {
+                events.push(SchedulerEvent::Failed {
+                    id,
+                    error: CompletionError::EmptyPrompt,
+                });
+                continue;
+            }
+            let reason = if request.max_tokens == Some(0) {
+                Some(StopReason::Length)
+            } else if request.prompt.len() > n_ctx_seq {
+                Some(StopReason::ContextFull)
+            } else {
+                None
+            };
+            if let Some(reason) = reason {
+                events.push(SchedulerEvent::Finished { id, reason });
+                continue;
+            }
+
+            let mut output = TokenOutput::new(self.ctx.model);
+            output.detokenizer =
+                StreamingDetokenizer::new(self.ctx.model).with_special(request.special);
+            output.stops = StopSequences::new(request.stops);
+            output.max_tokens = request.max_tokens;
+            self.clear_seq(seq, 0);
+            self.slots[seq] = Some(Slot {
+                id,
+                sampler: request.sampler,
+                output,
+                pending: request.prompt,
+                n_past: 0,
+            });
+        }
+    }
+
+    fn free(&mut self, seq: usize) {
+        self.slots[seq] = None;
+        self.clear_seq(seq, 0);
+    }
+
+    /// Remove the tokens of sequence `seq` from position `from` on from the KV cache.
+    fn clear_seq(&mut self, seq: usize, from: usize) {
+        let seq = u32::try_from(seq).expect("sequence id fits into a u32");
+        let from = u32::try_from(from).expect("position fits into a u32");
+        self.ctx
+            .clear_kv_cache_seq(Some(seq), Some(from), None)
+            .expect("sequence id and position fit into an i32");
+    }
+}
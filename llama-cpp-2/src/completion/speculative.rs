@@ -0,0 +1,534 @@
+//! Speculative decoding: generating several tokens per evaluation of the model.
+//!
+//! A [`Drafter`] cheaply proposes the next tokens, for example a smaller [`DraftModel`] with the
+//! same vocabulary or [`prompt_lookup::PromptLookup`], which copies them from earlier text.
+//! [`SpeculativeCompletion`] evaluates the last token and all proposed ones in one batch, samples
+//! the target model at each of them and accepts the proposals up to the first one that differs
+//! from the sampled token, which replaces it. The rejected tokens are removed from the KV cache.
+//! With greedy sampling the output is the same as without speculation, it just takes fewer
+//! evaluations of the target model when the proposals are good.
+//!
+//! ```no_run
+//! # use llama_cpp_2::context::LlamaContext;
+//! # fn example(target: &mut LlamaContext, draft: &mut LlamaContext) -> Result<(), Box<dyn std::error::Error>> {
+//! use llama_cpp_2::completion::speculative::{DraftModel, SpeculativeCompletion};
+//! use llama_cpp_2::model::AddBos;
+//! use llama_cpp_2::sampling::LlamaSampler;
+//!
+//! let prompt = target.model.str_to_token("Hello my name is", AddBos::Always)?;
+//! let drafter = DraftModel::new(target.model, draft)?;
+//! let mut completion = SpeculativeCompletion::new(target, &prompt, LlamaSampler::greedy(), drafter)
+//!     .with_draft_len(8)
+//!     .with_max_tokens(256);
+//! for token in &mut completion {
+//!     print!("{}", token?.text);
+//! }
+//! println!("\nacceptance rate: {:.2}", completion.stats().acceptance_rate());
+//! # Ok(())
+//! # }
+//! ```
+
+use std::collections::VecDeque;
+
+use crate::completion::{n_ctx_seq, CompletionError, CompletionToken, StopReason, TokenOutput};
+use crate::context::LlamaContext;
+use crate::detokenize::StreamingDetokenizer;
+use crate::llama_batch::LlamaBatch;
+use crate::model::LlamaModel;
+use crate::sampling::LlamaSampler;
+use crate::stop::StopSequences;
+use crate::token::LlamaToken;
+
+pub mod prompt_lookup;
+
+/// Proposes tokens for a [`SpeculativeCompletion`] to verify.
+pub trait Drafter {
+    /// Propose up to `n_max` tokens to follow `tokens`, the prompt and the tokens generated so
+    /// far. Proposing no tokens is fine, the completion then generates a single token.
+    ///
+    /// # Errors
+    ///
+    /// If drafting fails, this ends the completion.
+    fn draft(
+        &mut self,
+        tokens: &[LlamaToken],
+        n_max: usize,
+    ) -> Result<Vec<LlamaToken>, CompletionError>;
+}
+
+/// The vocabularies of a target and a draft model differ.
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum IncompatibleVocabError {
+    /// The tokenizers are of different types.
+    #[error("the vocabulary types differ: {target} and {draft}")]
+    ///
+    /// The types are the raw `llama_vocab_type` values, as [`VocabType`](crate::model::VocabType)
+    /// does not cover all of them.
+    VocabType {
+        /// The type of the target model.
+        target: u32,
+        /// The type of the draft model.
+        draft: u32,
+    },
+    /// The vocabularies have different sizes.
+    #[error("the vocabulary sizes differ: {target} and {draft}")]
+    NVocab {
+        /// The size of the target vocabulary.
+        target: i32,
+        /// The size of the draft vocabulary.
+        draft: i32,
+    },
+    /// A special token differs.
+    #[error("the {name} tokens differ: {target} and {draft}")]
+    SpecialToken {
+        /// The name of the token, e.g. `"BOS"`.
+        name: &'static str,
+        /// The token of the target model.
+        target: LlamaToken,
+        /// The token of the draft model.
+        draft: LlamaToken,
+    },
+    /// Only one of the models adds a special token when tokenizing.
+    #[error("only one of the models adds {name} tokens")]
+    AddSpecialToken {
+        /// The name of the token, e.g. `"BOS"`.
+        name: &'static str,
+    },
+}
+
+/// Check that tokens of `draft` mean the same for `target`: the vocabularies have the same type
+/// and size and the same special tokens.
+///
+/// # Errors
+///
+/// The first difference that was found.
+// `llama_vocab_type` is not a `u32` on every platform
+#[allow(clippy::unnecessary_cast)]
+pub fn check_vocab_compatible(
+    target: &LlamaModel,
+    draft: &LlamaModel,
+) -> Result<(), IncompatibleVocabError> {
+    let (target_vocab, draft_vocab) = (target.vocab_ptr(), draft.vocab_ptr());
+    let (target_type, draft_type) = unsafe {
+        (
+            llama_cpp_sys_2::llama_vocab_type(target_vocab),
+            llama_cpp_sys_2::llama_vocab_type(draft_vocab),
+        )
+    };
+    if target_type != draft_type {
+        return Err(IncompatibleVocabError::VocabType {
+            target: target_type as u32,
+            draft: draft_type as u32,
+        });
+    }
+    if target.n_vocab() != draft.n_vocab() {
+        return Err(IncompatibleVocabError::NVocab {
+            target: target.n_vocab(),
+            draft: draft.n_vocab(),
+        });
+    }
+    for (name, target, draft) in [
+        ("BOS", target.token_bos(), draft.token_bos()),
+        ("EOS", target.token_eos(), draft.token_eos()),
+    ] {
+        if target != draft {
+            return Err(IncompatibleVocabError::SpecialToken {
+                name,
+                target,
+                draft,
+            });
+        }
+    }
+    let same_add = unsafe {
+        [
+            (
+                "BOS",
+                llama_cpp_sys_2::llama_vocab_get_add_bos(target_vocab)
+                    == llama_cpp_sys_2::llama_vocab_get_add_bos(draft_vocab),
+            ),
+            (
+                "EOS",
+                llama_cpp_sys_2::llama_vocab_get_add_eos(target_vocab)
+                    == llama_cpp_sys_2::llama_vocab_get_add_eos(draft_vocab),
+            ),
+        ]
+    };
+    for (name, same) in same_add {
+        if !same {
+            return Err(IncompatibleVocabError::AddSpecialToken { name });
+        }
+    }
+    Ok(())
+}
+
+/// A [`Drafter`] that proposes the tokens a smaller model generates, in sequence 0 of its own
+/// context.
+///
+/// The draft context keeps the tokens it evaluated and only evaluates the ones that changed since
+/// the last draft, so after the prompt each draft costs about one evaluation per proposed token.
+#[derive(Debug)]
+pub struct DraftModel<'a, 'm> {
+    ctx: &'a mut LlamaContext<'m>,
+    sampler: LlamaSampler,
+    batch: LlamaBatch<'static>,
+    /// The tokens in the KV cache of the draft context.
+    tokens: Vec<LlamaToken>,
+}
+
+impl<'a, 'm> DraftModel<'a, 'm> {
+    /// Draft with `ctx` for a target model `target`, sampling greedily. Sequence 0 of `ctx` is
+    /// cleared.
+    ///
+    /// # Errors
+    ///
+    /// If the vocabularies are not compatible, see [`check_vocab_compatible`].
+    ///
+    /// # Panics
+    ///
+    /// If the batch size of the context does not fit into a [`usize`].
+    pub fn new(
+        target: &LlamaModel,
+        ctx: &'a mut LlamaContext<'m>,
+    ) -> Result<Self, IncompatibleVocabError> {
+        check_vocab_compatible(target, ctx.model)?;
+        ctx.clear_kv_cache_seq(Some(0), None, None)
+            .expect("sequence 0 fits into an i32");
+        let n_batch = usize::try_from(ctx.n_batch()).expect("n_batch fits into a usize");
+        Ok(Self {
+            ctx,
+            sampler: LlamaSampler::greedy(),
+            batch: LlamaBatch::new(n_batch, 1),
+            tokens: Vec::new(),
+        })
+    }
+
+    /// Sample the proposals with `sampler` instead of greedily.
+    #[must_use]
+    pub fn with_sampler(mut self, sampler: LlamaSampler) -> Self {
+        self.sampler = sampler;
+        self
+    }
+}
+
+impl Drafter for DraftModel<'_, '_> {
+    fn draft(
+        &mut self,
+        tokens: &[LlamaToken],
+        n_max: usize,
+    ) -> Result<Vec<LlamaToken>, CompletionError> {
+        let n_ctx = n_ctx_seq(self.ctx);
+        if n_max == 0 || tokens.is_empty() || tokens.len() + n_max > n_ctx {
+            return Ok(Vec::new());
+        }
+
+        // keep the shared prefix, but evaluate the last token again for its logits
+        let mut n_keep = self
+            .tokens
+            .iter()
+            .zip(tokens)
+            .take_while(|(a, b)| a == b)
+            .count()
+            .min(tokens.len() - 1);
+        let start = u32::try_from(n_keep).expect("position fits into a u32");
+        let removed = self
+            .ctx
+            .clear_kv_cache_seq(Some(0), Some(start), None)
+            .expect("position fits into an i32");
+        if !removed {
+            self.ctx
+                .clear_kv_cache_seq(Some(0), None, None)
+                .expect("sequence 0 fits into an i32");
+            n_keep = 0;
+        }
+        self.tokens.truncate(n_keep);
+
+        let new = &tokens[n_keep..];
+        let n_batch = usize::try_from(self.ctx.n_batch()).expect("n_batch fits into a usize");
+        for (i, chunk) in new.chunks(n_batch).enumerate() {
+            self.batch.clear();
+            for (j, &token) in chunk.iter().enumerate() {
+                let pos =
+                    i32::try_from(n_keep + i * n_batch + j).expect("position fits into an i32");
+                let last = i * n_batch + j + 1 == new.len();
+                self.batch.add(token, pos, &[0], last)?;
+            }
+            self.ctx.decode(&mut self.batch)?;
+            self.tokens.extend_from_slice(chunk);
+        }
+
+        let mut draft = Vec::new();
+        loop {
+            let token = self.sampler.sample(self.ctx, self.batch.n_tokens() - 1);
+            draft.push(token);
+            if draft.len() == n_max || self.ctx.model.is_eog_token(token) {
+                return Ok(draft);
+            }
+            self.batch.clear();
+            let pos = i32::try_from(self.tokens.len()).expect("position fits into an i32");
+            self.batch.add(token, pos, &[0], true)?;
+            self.ctx.decode(&mut self.batch)?;
+            self.tokens.push(token);
+        }
+    }
+}
+
+/// Counts of the proposed and accepted tokens of a [`SpeculativeCompletion`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SpeculativeStats {
+    /// The number of evaluations of the target model after the prompt.
+    pub n_steps: usize,
+    /// The number of proposed tokens.
+    pub n_drafted: usize,
+    /// The number of proposed tokens that were accepted.
+    pub n_accepted: usize,
+}
+
+impl SpeculativeStats {
+    /// The fraction of the proposed tokens that were accepted, 0 if none were proposed.
+    #[must_use]
+    #[allow(clippy::cast_precision_loss)]
+    pub fn acceptance_rate(&self) -> f64 {
+        if self.n_drafted == 0 {
+            0.0
+        } else {
+            self.n_accepted as f64 / self.n_drafted as f64
+        }
+    }
+}
+
+/// Generates tokens from a prompt with speculative decoding, see [the module docs](self).
+///
+/// Like [`crate::completion::Completion`], each item is a generated token; several can come from
+/// one evaluation of the target model.
+#[derive(Debug)]
+pub struct SpeculativeCompletion<'a, 'm, D> {
+    ctx: &'a mut LlamaContext<'m>,
+    drafter: D,
+    sampler: LlamaSampler,
+    output: TokenOutput<'m>,
+    n_draft: usize,
+    batch: LlamaBatch<'static>,
+    /// The prompt and the generated tokens.
+    tokens: Vec<LlamaToken>,
+    /// The number of tokens in the KV cache, all but the last one after the first step.
+    n_past: usize,
+    /// Accepted tokens that were not returned yet.
+    ready: VecDeque<CompletionToken>,
+    stats: SpeculativeStats,
+    stop_reason: Option<StopReason>,
+    failed: bool,
+}
+
+impl<'a, 'm, D: Drafter> SpeculativeCompletion<'a, 'm, D> {
+    /// Generate a completion of `prompt` with `sampler` in sequence 0 of `ctx`, which should not
+    /// contain any other tokens, verifying the proposals of `drafter`. Up to 16 tokens are
+    /// proposed at a time.
+    ///
+    /// # Panics
+    ///
+    /// If the batch size of the context does not fit into a [`usize`].
+    #[must_use]
+    pub fn new(
+        ctx: &'a mut LlamaContext<'m>,
+        prompt: &[LlamaToken],
+        sampler: LlamaSampler,
+        drafter: D,
+    ) -> Self {
+        let model = ctx.model;
+        let n_batch = usize::try_from(ctx.n_batch()).expect("n_batch fits into a usize");
+        Self {
+            ctx,
+            drafter,
+            sampler,
+            output: TokenOutput::new(model),
+            n_draft: 16,
+            batch: LlamaBatch::new(n_batch, 1),
+            tokens: prompt.to_vec(),
+            n_past: 0,
+            ready: VecDeque::new(),
+            stats: SpeculativeStats::default(),
+            stop_reason: None,
+            failed: false,
+        }
+    }
+
+    /// Propose at most `n_draft` tokens at a time. Fewer are proposed near the end of the context
+    /// or the maximum number of tokens.
+    #[must_use]
+    pub fn with_draft_len(mut self, n_draft: usize) -> Self {
+        self.n_draft = n_draft;
+        self
+    }
+
+    /// See [`crate::completion::Completion::with_max_tokens`].
+    #[must_use]
+    pub fn with_max_tokens(mut self, max_tokens: usize) -> Self {
+        self.output.max_tokens = Some(max_tokens);
+        self
+    }
+
+    /// See [`crate::completion::Completion::with_stops`].
+    #[must_use]
+    pub fn with_stops(mut self, stops: impl IntoIterator<Item = impl Into<String>>) -> Self {
+        self.output.stops = StopSequences::new(stops);
+        self
+    }
+
+    /// See [`crate::completion::Completion::with_detokenizer`].
+    #[must_use]
+    pub fn with_detokenizer(mut self, detokenizer: StreamingDetokenizer<'m>) -> Self {
+        self.output.detokenizer = detokenizer;
+        self
+    }
+
+    /// Why the generation stopped, `None` while it is running or after an error.
+    #[must_use]
+    pub fn stop_reason(&self) -> Option<StopReason> {
+        self.stop_reason.filter(|_| self.ready.is_empty())
+    }
+
+    /// The number of tokens generated so far, including ones not returned yet.
+    #[must_use]
+    pub fn n_generated(&self) -> usize {
+        self.output.n_generated
+    }
+
+    /// The counts of proposed and accepted tokens.
+    #[must_use]
+    pub fn stats(&self) -> SpeculativeStats {
+        self.stats
+    }
+
+    /// The drafter, for example to read its statistics.
+    #[must_use]
+    pub fn drafter(&self) -> &D {
+        &self.drafter
+    }
+
+    /// The context of the target model.
+    #[must_use]
+    pub fn context(&self) -> &LlamaContext<'m> {
+        self.ctx
+    }
+
+    /// Evaluate the tokens before the last one, e.g. the prompt, without logits.
+    fn evaluate_prefix(&mut self) -> Result<(), CompletionError> {
+        let n_batch = usize::try_from(self.ctx.n_batch()).expect("n_batch fits into a usize");
+        let end = self.tokens.len() - 1;
+        for chunk in self.tokens[self.n_past..end].chunks(n_batch) {
+            self.batch.clear();
+            for &token in chunk {
+                let pos = i32::try_from(self.n_past).expect("position fits into an i32");
+                self.batch.add(token, pos, &[0], false)?;
+                self.n_past += 1;
+            }
+            self.ctx.decode(&mut self.batch)?;
+        }
+        Ok(())
+    }
+
+    /// Evaluate the last token and the proposed ones and sample at each, until a sampled token
+    /// differs from the proposal. Returns the sampled tokens.
+    fn verify(&mut self, draft: &[LlamaToken]) -> Result<Vec<LlamaToken>, CompletionError> {
+        self.batch.clear();
+        let last = *self.tokens.last().expect("there is a token to evaluate");
+        for (i, &token) in std::iter::once(&last).chain(draft).enumerate() {
+            let pos = i32::try_from(self.n_past + i).expect("position fits into an i32");
+            self.batch.add(token, pos, &[0], true)?;
+        }
+        self.ctx.decode(&mut self.batch)?;
+
+        let mut accepted = Vec::new();
+        for i in 0..=draft.len() {
+            let idx = i32::try_from(i).expect("batch index fits into an i32");
+            let token = self.sampler.sample(self.ctx, idx);
+            accepted.push(token);
+            if draft.get(i) != Some(&token) {
+                break;
+            }
+        }
+
+        // the last token and the accepted proposals stay in the KV cache
+        self.n_past += accepted.len();
+        if accepted.len() <= draft.len() {
+            let start = u32::try_from(self.n_past).expect("position fits into a u32");
+            let removed = self
+                .ctx
+                .clear_kv_cache_seq(Some(0), Some(start), None)
+                .expect("position fits into an i32");
+            if !removed {
+                return Err(CompletionError::RollbackFailed);
+            }
+        }
+        Ok(accepted)
+    }
+
+    fn step(&mut self) -> Result<(), CompletionError> {
+        if self.tokens.is_empty() {
+            return Err(CompletionError::EmptyPrompt);
+        }
+        let n_ctx = n_ctx_seq(self.ctx);
+        let n_batch = usize::try_from(self.ctx.n_batch()).expect("n_batch fits into a usize");
+        let stop = if self.output.at_max_tokens() {
+            Some(StopReason::Length)
+        } else if self.tokens.len() > n_ctx {
+            Some(StopReason::ContextFull)
+        } else {
+            None
+        };
+        if stop.is_some() {
+            self.stop_reason = stop;
+            return Ok(());
+        }
+
+        self.evaluate_prefix()?;
+        let n_remaining = self
+            .output
+            .max_tokens
+            .map_or(usize::MAX, |max| max - self.output.n_generated);
+        let n_draft = self
+            .n_draft
+            .min(n_ctx - self.tokens.len())
+            .min(n_batch - 1)
+            .min(n_remaining - 1);
+        let mut draft = if n_draft == 0 {
+            Vec::new()
+        } else {
+            self.drafter.draft(&self.tokens, n_draft)?
+        };
+        draft.truncate(n_draft);
+
+        let accepted = self.verify(&draft)?;
+        self.stats.n_steps += 1;
+        self.stats.n_drafted += draft.len();
+        self.stats.n_accepted += accepted.len() - 1;
+
+        for token in accepted {
+            self.tokens.push(token);
+            let (token, stop) = self.output.push(token, self.tokens.len() > n_ctx)?;
+            self.ready.push_back(token);
+            if stop.is_some() {
+                self.stop_reason = stop;
+                break;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<D: Drafter> Iterator for SpeculativeCompletion<'_, '_, D> {
+    type Item = Result<CompletionToken, CompletionError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.ready.is_empty() {
+            if self.stop_reason.is_some() || self.failed {
+                return None;
+            }
+            if let Err(error) = self.step() {
+                self.failed = true;
+                return Some(Err(error));
+            }
+        }
+        self.ready.pop_front().map(Ok)
+    }
+}
@@ -0,0 +1,158 @@
+//! Speculative decoding without a draft model, by n-gram lookup.
+//!
+//! When the output largely copies the input, as in extraction or editing, the next tokens can be
+//! guessed from earlier text: [`PromptLookup`] searches the prompt and the generated tokens for
+//! the last n-gram, trying longer n-grams first, and proposes the tokens that followed its most
+//! recent occurrence.
+//!
+//! ```no_run
+//! # use llama_cpp_2::context::LlamaContext;
+//! # use llama_cpp_2::token::LlamaToken;
+//! # fn example(ctx: &mut LlamaContext, prompt: &[LlamaToken]) -> Result<(), Box<dyn std::error::Error>> {
+//! use llama_cpp_2::completion::speculative::prompt_lookup::PromptLookup;
+//! use llama_cpp_2::completion::speculative::SpeculativeCompletion;
+//! use llama_cpp_2::sampling::LlamaSampler;
+//!
+//! let drafter = PromptLookup::new().with_ngram_len(2, 4);
+//! let mut completion = SpeculativeCompletion::new(ctx, prompt, LlamaSampler::greedy(), drafter)
+//!     .with_draft_len(10);
+//! for token in &mut completion {
+//!     print!("{}", token?.text);
+//! }
+//! let lookups = completion.drafter().stats();
+//! println!("\n{} of {} lookups matched", lookups.n_matches, lookups.n_lookups);
+//! println!("acceptance rate: {:.2}", completion.stats().acceptance_rate());
+//! # Ok(())
+//! # }
+//! ```
+
+use crate::completion::speculative::Drafter;
+use crate::completion::CompletionError;
+use crate::token::LlamaToken;
+
+/// Counts of the lookups of a [`PromptLookup`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PromptLookupStats {
+    /// The number of drafts that were requested.
+    pub n_lookups: usize,
+    /// The number of lookups that found an n-gram.
+    pub n_matches: usize,
+    /// The sum of the lengths of the matched n-grams.
+    pub total_match_len: usize,
+    /// The length of the n-gram matched by the last lookup, `None` if it found nothing.
+    pub last_match_len: Option<usize>,
+}
+
+/// A [`Drafter`] that copies tokens from earlier text, see [the module docs](self).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PromptLookup {
+    ngram_min: usize,
+    ngram_max: usize,
+    stats: PromptLookupStats,
+}
+
+impl Default for PromptLookup {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl PromptLookup {
+    /// Look up n-grams of 1 to 3 tokens.
+    #[must_use]
+    pub fn new() -> Self {
+        Self {
+            ngram_min: 1,
+            ngram_max: 3,
+            stats: PromptLookupStats::default(),
+        }
+    }
+
+    /// Look up n-grams of `min` to `max` tokens. Longer n-grams give fewer, but better proposals.
+    ///
+    /// # Panics
+    ///
+    /// If `min` is 0 or larger than `max`.
+    #[must_use]
+    pub fn with_ngram_len(mut self, min: usize, max: usize) -> Self {
+        assert!(
+            min > 0 && min <= max,
+            "the n-gram lengths must satisfy 0 < min <= max, but are {min} and {max}"
+        );
+        self.ngram_min = min;
+        self.ngram_max = max;
+        self
+    }
+
+    /// The counts of the lookups so far.
+    #[must_use]
+    pub fn stats(&self) -> PromptLookupStats {
+        self.stats
+    }
+
+    /// Find the longest n-gram at the end of `tokens` that occurred before and return its length
+    /// and up to `n_max` tokens that followed its most recent occurrence.
+    fn lookup<'t>(
+        &self,
+        tokens: &'t [LlamaToken],
+        n_max: usize,
+    ) -> Option<(usize, &'t [LlamaToken])> {
+        (self.ngram_min..=self.ngram_max.min(tokens.len().saturating_sub(1)))
+            .rev()
+            .find_map(|n| {
+                let ngram = &tokens[tokens.len() - n..];
+                // occurrences that are followed by at least one token
+                (0..tokens.len() - n)
+                    .rev()
+                    .find(|&i| &tokens[i..i + n] == ngram)
+                    .map(|i| {
+                        let end = (i + n + n_max).min(tokens.len());
+                        (n, &tokens[i + n..end])
+                    })
+            })
+    }
+}
+
+impl Drafter for PromptLookup {
+    fn draft(
+        &mut self,
+        tokens: &[LlamaToken],
+        n_max: usize,
+    ) -> Result<Vec<LlamaToken>, CompletionError> {
+        let found = self.lookup(tokens, n_max);
+        self.stats.n_lookups += 1;
+        self.stats.last_match_len = found.map(|(n, _)| n);
+        let Some((n, draft)) = found else {
+            return Ok(Vec::new());
+        };
+        self.stats.n_matches += 1;
+        self.stats.total_match_len += n;
+        Ok(draft.to_vec())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tokens(ids: &[i32]) -> Vec<LlamaToken> {
+        ids.iter().copied().map(LlamaToken).collect()
+    }
+
+    #[test]
+    fn lookup() {
+        let lookup = PromptLookup::new().with_ngram_len(1, 2);
+        let history = tokens(&[1, 2, 3, 4, 9, 2, 3, 5, 6, 7, 2, 3]);
+        // the most recent occurrence of the 2-gram [2, 3]
+        assert_eq!(lookup.lookup(&history, 2), Some((2, &history[7..9])));
+        // only the 1-gram [9] occurred before
+        let history = tokens(&[9, 8, 7, 1, 9]);
+        assert_eq!(lookup.lookup(&history, 5), Some((1, &history[1..5])));
+        assert_eq!(lookup.lookup(&tokens(&[1, 2, 3]), 5), None);
+        assert_eq!(lookup.lookup(&tokens(&[1]), 5), None);
+
+        let mut lookup = lookup;
+        assert_eq!(lookup.draft(&history, 2).unwrap(), tokens(&[8, 7]));
+        assert_eq!(lookup.stats().last_match_len, Some(1));
+    }
+}
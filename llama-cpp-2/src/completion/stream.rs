@@ -0,0 +1,251 @@
+//! Text generation as a [`Stream`] for async code, enabled by the `async` feature.
+//!
+//! A [`CompletionWorker`] owns a [`LlamaContext`] on a dedicated thread and runs one
+//! [`Completion`] at a time, so decoding never blocks the async runtime. Each request returns a
+//! [`CompletionStream`]; dropping it cancels the generation, aborting an evaluation that is in
+//! progress.
+//!
+//! ```no_run
+//! # use std::sync::Arc;
+//! # use llama_cpp_2::llama_backend::LlamaBackend;
+//! # use llama_cpp_2::model::LlamaModel;
+//! # async fn example(backend: Arc<LlamaBackend>, model: Arc<LlamaModel>) -> Result<(), Box<dyn std::error::Error>> {
+//! use std::future::poll_fn;
+//! use std::pin::Pin;
+//!
+//! use futures_core::Stream;
+//! use llama_cpp_2::completion::stream::{CompletionRequest, CompletionWorker};
+//! use llama_cpp_2::context::params::LlamaContextParams;
+//! use llama_cpp_2::model::AddBos;
+//! use llama_cpp_2::sampling::config::SamplerConfig;
+//!
+//! let worker = CompletionWorker::spawn(backend, model.clone(), LlamaContextParams::default())?;
+//! let prompt = model.str_to_token("Hello my name is", AddBos::Always)?;
+//! let mut stream = worker.complete(
+//!     CompletionRequest::new(prompt, SamplerConfig::default()).with_max_tokens(64),
+//! );
+//! // or `StreamExt::next` from the `futures` crate
+//! while let Some(token) = poll_fn(|cx| Pin::new(&mut stream).poll_next(cx)).await {
+//!     print!("{}", token?.text);
+//! }
+//! println!("\n{:?}", stream.stop_reason());
+//! # Ok(())
+//! # }
+//! ```
+
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc};
+use std::task::{Context, Poll};
+
+use futures_channel::mpsc::{unbounded, UnboundedReceiver, UnboundedSender};
+use futures_core::Stream;
+
+use crate::completion::{Completion, CompletionError, CompletionToken, StopReason};
+use crate::context::params::LlamaContextParams;
+use crate::context::LlamaContext;
+use crate::detokenize::{SpecialTokens, StreamingDetokenizer};
+use crate::llama_backend::LlamaBackend;
+use crate::model::LlamaModel;
+use crate::sampling::config::SamplerConfig;
+use crate::token::LlamaToken;
+use crate::LlamaContextLoadError;
+
+/// A request for a [`CompletionWorker`]. The sampler is built on the worker thread, as
+/// [`crate::sampling::LlamaSampler`] can not be sent between threads.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CompletionRequest {
+    prompt: Vec<LlamaToken>,
+    sampler: SamplerConfig,
+    max_tokens: Option<usize>,
+    stops: Vec<String>,
+    special: SpecialTokens,
+}
+
+impl CompletionRequest {
+    /// A request to complete `prompt`, sampling with `sampler`.
+    #[must_use]
+    pub fn new(prompt: Vec<LlamaToken>, sampler: SamplerConfig) -> Self {
+        Self {
+            prompt,
+            sampler,
+            max_tokens: None,
+            stops: Vec::new(),
+            special: SpecialTokens::default(),
+        }
+    }
+
+    /// See [`Completion::with_max_tokens`].
+    #[must_use]
+    pub fn with_max_tokens(mut self, max_tokens: usize) -> Self {
+        self.max_tokens = Some(max_tokens);
+        self
+    }
+
+    /// See [`Completion::with_stops`].
+    #[must_use]
+    pub fn with_stops(mut self, stops: impl IntoIterator<Item = impl Into<String>>) -> Self {
+        self.stops = stops.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// See [`StreamingDetokenizer::with_special`].
+    #[must_use]
+    pub fn with_special(mut self, special: SpecialTokens) -> Self {
+        self.special = special;
+        self
+    }
+}
+
+/// A message from the worker to a [`CompletionStream`].
+enum Event {
+    Token(Result<CompletionToken, CompletionError>),
+    Done(Option<StopReason>),
+}
+
+struct Job {
+    request: CompletionRequest,
+    events: UnboundedSender<Event>,
+    cancel: Arc<AtomicBool>,
+}
+
+/// A thread that owns a [`LlamaContext`] and runs completions, see [the module docs](self).
+///
+/// Requests are run one after another, each with an empty KV cache. Dropping the worker lets the
+/// thread finish the queued requests and exit.
+#[derive(Debug)]
+pub struct CompletionWorker {
+    jobs: mpsc::Sender<Job>,
+}
+
+impl CompletionWorker {
+    /// Spawn a worker thread with a new context for `model`.
+    ///
+    /// # Errors
+    ///
+    /// If the context can not be created.
+    ///
+    /// # Panics
+    ///
+    /// If the thread can not be spawned or exits while creating the context.
+    pub fn spawn(
+        backend: Arc<LlamaBackend>,
+        model: Arc<LlamaModel>,
+        params: LlamaContextParams,
+    ) -> Result<Self, LlamaContextLoadError> {
+        let (jobs, receiver) = mpsc::channel::<Job>();
+        let (created, created_receiver) = mpsc::sync_channel(1);
+        std::thread::Builder::new()
+            .name("llama-completion".to_string())
+            .spawn(move || {
+                let mut ctx = match model.new_context(&backend, params) {
+                    Ok(ctx) => ctx,
+                    Err(error) => {
+                        let _ = created.send(Err(error));
+                        return;
+                    }
+                };
+                let _ = created.send(Ok(()));
+                for job in receiver {
+                    ctx.clear_kv_cache();
+                    run(&mut ctx, job);
+                }
+            })
+            .expect("failed to spawn the completion worker");
+
+        created_receiver
+            .recv()
+            .expect("the completion worker exited")?;
+        Ok(Self { jobs })
+    }
+
+    /// Queue a completion and stream its tokens.
+    #[must_use]
+    pub fn complete(&self, request: CompletionRequest) -> CompletionStream {
+        let (events, receiver) = unbounded();
+        let cancel = Arc::new(AtomicBool::new(false));
+        // if the worker is gone the stream ends immediately
+        let _ = self.jobs.send(Job {
+            request,
+            events,
+            cancel: Arc::clone(&cancel),
+        });
+        CompletionStream {
+            events: receiver,
+            cancel,
+            stop_reason: None,
+        }
+    }
+}
+
+fn run(ctx: &mut LlamaContext, job: Job) {
+    let Job {
+        request,
+        events,
+        cancel,
+    } = job;
+    if cancel.load(Ordering::Relaxed) {
+        return;
+    }
+    let sampler = match request.sampler.build(ctx.model) {
+        Ok(sampler) => sampler,
+        Err(error) => {
+            let _ = events.unbounded_send(Event::Token(Err(error.into())));
+            return;
+        }
+    };
+
+    ctx.set_abort_flag(Arc::clone(&cancel));
+    let detokenizer = StreamingDetokenizer::new(ctx.model).with_special(request.special);
+    let mut completion = Completion::new(ctx, &request.prompt, sampler)
+        .with_stops(request.stops)
+        .with_detokenizer(detokenizer)
+        .with_cancel(cancel);
+    if let Some(max_tokens) = request.max_tokens {
+        completion = completion.with_max_tokens(max_tokens);
+    }
+    for token in &mut completion {
+        if events.unbounded_send(Event::Token(token)).is_err() {
+            // the stream was dropped
+            return;
+        }
+    }
+    let _ = events.unbounded_send(Event::Done(completion.stop_reason()));
+}
+
+/// The tokens of a request to a [`CompletionWorker`]. Dropping the stream cancels the generation.
+#[derive(Debug)]
+pub struct CompletionStream {
+    events: UnboundedReceiver<Event>,
+    cancel: Arc<AtomicBool>,
+    stop_reason: Option<StopReason>,
+}
+
+impl CompletionStream {
+    /// Why the generation stopped, see [`Completion::stop_reason`]. `None` until the stream ended.
+    #[must_use]
+    pub fn stop_reason(&self) -> Option<StopReason> {
+        self.stop_reason
+    }
+}
+
+impl Stream for CompletionStream {
+    type Item = Result<CompletionToken, CompletionError>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        loop {
+            match Pin::new(&mut self.events).poll_next(cx) {
+                Poll::Ready(Some(Event::Token(token))) => return Poll::Ready(Some(token)),
+                Poll::Ready(Some(Event::Done(stop_reason))) => self.stop_reason = stop_reason,
+                Poll::Ready(None) => return Poll::Ready(None),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+    }
+}
+
+impl Drop for CompletionStream {
+    fn drop(&mut self) {
+        self.cancel.store(true, Ordering::Relaxed);
+    }
+}
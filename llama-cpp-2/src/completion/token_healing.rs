@@ -0,0 +1,223 @@
+//! Token healing at the end of the prompt.
+//!
+//! A prompt that ends in the middle of a word or with a space is tokenized differently than the
+//! same text inside a longer one: `"fn ma"` ends with the token `"ma"`, although the model would
+//! rather generate `" main"` after `"fn"`. Token healing removes the last tokens of the prompt and
+//! lets the first generated token replace them, allowing only tokens whose text starts with the
+//! removed text. This matters for code completion, where the prompt ends at the cursor.
+//!
+//! ```no_run
+//! # use llama_cpp_2::context::LlamaContext;
+//! # fn example(ctx: &mut LlamaContext) -> Result<(), Box<dyn std::error::Error>> {
+//! use llama_cpp_2::completion::token_healing::TokenPrefixIndex;
+//! use llama_cpp_2::completion::Completion;
+//! use llama_cpp_2::model::AddBos;
+//! use llama_cpp_2::sampling::LlamaSampler;
+//!
+//! // building the index converts the whole vocabulary, so build it once per model
+//! let index = TokenPrefixIndex::new(ctx.model);
+//! let prompt = ctx.model.str_to_token("fn ma", AddBos::Always)?;
+//! let completion = Completion::new(ctx, &prompt, LlamaSampler::greedy())
+//!     .with_token_healing(&index, 2)
+//!     .with_max_tokens(32);
+//! for token in completion {
+//!     // the text continues the prompt, e.g. "in() {"
+//!     print!("{}", token?.text);
+//! }
+//! # Ok(())
+//! # }
+//! ```
+
+use crate::completion::CompletionError;
+use crate::detokenize::StreamingDetokenizer;
+use crate::model::LlamaModel;
+use crate::sampling::LlamaSampler;
+use crate::token::data::LlamaTokenData;
+use crate::token::data_array::LlamaTokenDataArray;
+use crate::token::LlamaToken;
+use crate::token_type::LlamaTokenAttr;
+
+/// The tokens of a vocabulary sorted by their text, to find the tokens that start with a prefix.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TokenPrefixIndex {
+    entries: Vec<(String, LlamaToken)>,
+}
+
+impl TokenPrefixIndex {
+    /// Index the tokens of `model` from [`LlamaModel::tokens`]. Special tokens and tokens without
+    /// text are left out.
+    #[must_use]
+    pub fn new(model: &LlamaModel) -> Self {
+        Self::from_pieces(
+            model
+                .tokens(false)
+                .filter_map(|(token, text)| Some((text.ok()?, token))),
+        )
+    }
+
+    fn from_pieces(pieces: impl IntoIterator<Item = (String, LlamaToken)>) -> Self {
+        let mut entries: Vec<_> = pieces
+            .into_iter()
+            .filter(|(text, _)| !text.is_empty())
+            .collect();
+        entries.sort_unstable();
+        Self { entries }
+    }
+
+    /// The tokens whose text starts with `prefix`, in the order of their text.
+    pub fn starting_with<'a>(&'a self, prefix: &'a str) -> impl Iterator<Item = LlamaToken> + 'a {
+        let start = self
+            .entries
+            .partition_point(|(text, _)| text.as_str() < prefix);
+        self.entries[start..]
+            .iter()
+            .take_while(move |(text, _)| text.starts_with(prefix))
+            .map(|&(_, token)| token)
+    }
+
+    /// The number of indexed tokens.
+    #[must_use]
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Whether no token was indexed.
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+/// The prompt with its last tokens removed and the tokens the first generated token must be one
+/// of, see [the module docs](self).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TokenHealing {
+    prompt: Vec<LlamaToken>,
+    removed: String,
+    allowed: Vec<LlamaToken>,
+}
+
+impl TokenHealing {
+    /// Remove up to `max_tokens` tokens from the end of `prompt`, as many as possible while some
+    /// token of `index` starts with their text. The first token of the prompt is always kept, as
+    /// are special tokens and everything before them.
+    #[must_use]
+    pub fn new(
+        model: &LlamaModel,
+        index: &TokenPrefixIndex,
+        prompt: &[LlamaToken],
+        max_tokens: usize,
+    ) -> Self {
+        let mut healing = Self {
+            prompt: prompt.to_vec(),
+            removed: String::new(),
+            allowed: Vec::new(),
+        };
+        for n in 1..=max_tokens.min(prompt.len().saturating_sub(1)) {
+            let Some(removed) = removed_text(model, &prompt[prompt.len() - n..]) else {
+                break;
+            };
+            let allowed: Vec<_> = index.starting_with(&removed).collect();
+            if allowed.is_empty() {
+                break;
+            }
+            healing = Self {
+                prompt: prompt[..prompt.len() - n].to_vec(),
+                removed,
+                allowed,
+            };
+        }
+        healing
+    }
+
+    /// The prompt to evaluate, without the removed tokens.
+    #[must_use]
+    pub fn prompt(&self) -> &[LlamaToken] {
+        &self.prompt
+    }
+
+    /// The text of the removed tokens, empty if no token was removed.
+    #[must_use]
+    pub fn removed_text(&self) -> &str {
+        &self.removed
+    }
+
+    /// The tokens the first generated token must be one of, empty if no token was removed.
+    #[must_use]
+    pub fn allowed_tokens(&self) -> &[LlamaToken] {
+        &self.allowed
+    }
+
+    /// Whether any token was removed.
+    #[must_use]
+    pub fn is_healing(&self) -> bool {
+        !self.allowed.is_empty()
+    }
+
+    /// Sample the first token from `logits`, among the allowed tokens only.
+    pub(crate) fn sample(
+        &self,
+        sampler: &mut LlamaSampler,
+        logits: &[f32],
+    ) -> Result<LlamaToken, CompletionError> {
+        let mut candidates = LlamaTokenDataArray::from_iter(
+            self.allowed.iter().map(|&token| {
+                let logit = usize::try_from(token.0).map_or(f32::NEG_INFINITY, |i| logits[i]);
+                LlamaTokenData::new(token, logit, 0.0)
+            }),
+            false,
+        );
+        candidates.apply_sampler(sampler);
+        let token = candidates
+            .selected_token()
+            .ok_or(CompletionError::NoTokenSelected)?;
+        sampler.accept(token);
+        Ok(token)
+    }
+}
+
+/// The text of `tokens`, `None` if one of them is a control token.
+fn removed_text(model: &LlamaModel, tokens: &[LlamaToken]) -> Option<String> {
+    let mut detokenizer = StreamingDetokenizer::new(model);
+    let mut text = String::new();
+    for &token in tokens {
+        if model.token_attr(token).contains(LlamaTokenAttr::Control) {
+            return None;
+        }
+        text.push_str(&detokenizer.push(token).ok()?);
+    }
+    text.push_str(&detokenizer.flush());
+    Some(text)
+}
+
+/// The length of the longest common prefix of `a` and `b`, in bytes.
+pub(crate) fn common_prefix_len(a: &str, b: &str) -> usize {
+    a.chars()
+        .zip(b.chars())
+        .take_while(|(a, b)| a == b)
+        .map(|(c, _)| c.len_utf8())
+        .sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn starting_with() {
+        let index = TokenPrefixIndex::from_pieces(
+            [" main", "ma", "", " ma", "m", " mai", "n"]
+                .into_iter()
+                .enumerate()
+                .map(|(i, text)| (text.to_string(), LlamaToken(i32::try_from(i).unwrap()))),
+        );
+        assert_eq!(index.len(), 6);
+        let tokens: Vec<_> = index.starting_with(" ma").collect();
+        assert_eq!(tokens, [LlamaToken(3), LlamaToken(5), LlamaToken(0)]);
+        assert_eq!(index.starting_with("x").count(), 0);
+        assert_eq!(index.starting_with("").count(), 6);
+
+        assert_eq!(common_prefix_len(" mä", " mäin"), 4);
+        assert_eq!(common_prefix_len("ab", "b"), 0);
+    }
+}
@@ -1,9 +1,12 @@
 //! Safe wrapper around `llama_context`.
 
+use std::ffi::c_void;
 use std::fmt::{Debug, Formatter};
 use std::num::NonZeroI32;
 use std::ptr::NonNull;
 use std::slice;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
 
 use crate::llama_batch::LlamaBatch;
 use crate::model::{LlamaLoraAdapter, LlamaModel};
@@ -18,7 +21,9 @@ use crate::{
 
 pub mod kv_cache;
 pub mod params;
+pub mod prefix_cache;
 pub mod session;
+pub mod shift;
 
 /// Safe wrapper around `llama_context`.
 #[allow(clippy::module_name_repetitions)]
@@ -28,8 +33,20 @@ pub struct LlamaContext<'a> {
     pub model: &'a LlamaModel,
     initialized_logits: Vec<i32>,
     embeddings_enabled: bool,
+    /// Kept alive while llama.cpp holds a pointer to it.
+    abort_callback: Option<Box<AbortCallback>>,
+    split_on_no_kv_slot: bool,
+    /// Whether all sequences share one KV cache buffer, see
+    /// [`LlamaContextParams::with_kv_unified`](params::LlamaContextParams::with_kv_unified).
+    kv_unified: bool,
+    /// The index of the first token of the last batch llama.cpp decoded in the batch passed to
+    /// [`Self::decode`], non-zero after a split.
+    output_offset: i32,
 }
 
+/// The closure behind `llama_set_abort_callback`.
+type AbortCallback = Box<dyn FnMut() -> bool + Send>;
+
 impl Debug for LlamaContext<'_> {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         f.debug_struct("LlamaContext")
@@ -43,12 +60,17 @@ impl<'model> LlamaContext<'model> {
         llama_model: &'model LlamaModel,
         llama_context: NonNull<llama_cpp_sys_2::llama_context>,
         embeddings_enabled: bool,
+        kv_unified: bool,
     ) -> Self {
         Self {
             context: llama_context,
             model: llama_model,
             initialized_logits: Vec::new(),
             embeddings_enabled,
+            abort_callback: None,
+            split_on_no_kv_slot: false,
+            kv_unified,
+            output_offset: 0,
         }
     }
 
@@ -70,6 +92,12 @@ impl<'model> LlamaContext<'model> {
         unsafe { llama_cpp_sys_2::llama_n_ctx(self.context.as_ptr()) }
     }
 
+    /// Gets the max number of sequences, see [`params::LlamaContextParams::with_n_seq_max`].
+    #[must_use]
+    pub fn n_seq_max(&self) -> u32 {
+        unsafe { llama_cpp_sys_2::llama_n_seq_max(self.context.as_ptr()) }
+    }
+
     /// Decodes the batch.
     ///
     /// # Errors
@@ -80,19 +108,156 @@ impl<'model> LlamaContext<'model> {
     ///
     /// - the returned [`std::ffi::c_int`] from llama-cpp does not fit into a i32 (this should never happen on most systems)
     pub fn decode(&mut self, batch: &mut LlamaBatch) -> Result<(), DecodeError> {
-        let result =
-            unsafe { llama_cpp_sys_2::llama_decode(self.context.as_ptr(), batch.llama_batch) };
+        let output_offset = match self.decode_raw(batch.llama_batch) {
+            Err(DecodeError::NoKvSlot) if self.split_on_no_kv_slot => self.decode_split(batch)?,
+            result => result.map(|()| 0)?,
+        };
+        self.output_offset = output_offset;
+        self.initialized_logits
+            .clone_from(&batch.initialized_logits);
+        Ok(())
+    }
 
-        match NonZeroI32::new(result) {
-            None => {
-                self.initialized_logits
-                    .clone_from(&batch.initialized_logits);
+    /// Retry a [`Self::decode`] that fails with [`DecodeError::NoKvSlot`] by decoding the batch in
+    /// smaller parts, which can fit into a fragmented KV cache. Disabled by default.
+    ///
+    /// Only the tokens before the first token with logits are split off, as llama.cpp only keeps
+    /// the outputs of the last part. This never helps when the first token has logits, as in the
+    /// batches of [`crate::completion::scheduler::Scheduler::step`] with a generating request and
+    /// the verification batches of [`crate::completion::speculative`]. Batches from
+    /// [`LlamaBatch::get_one`] are not split either, as llama.cpp assigns their positions.
+    ///
+    /// If a part still fails with [`DecodeError::NoKvSlot`], the parts decoded before it are
+    /// removed from the KV cache again, so the error keeps its meaning. On other errors they
+    /// remain in the KV cache.
+    pub fn set_split_on_no_kv_slot(&mut self, split: bool) {
+        self.split_on_no_kv_slot = split;
+    }
+
+    fn decode_raw(&mut self, batch: llama_cpp_sys_2::llama_batch) -> Result<(), DecodeError> {
+        let result = unsafe { llama_cpp_sys_2::llama_decode(self.context.as_ptr(), batch) };
+        NonZeroI32::new(result).map_or(Ok(()), |error| Err(DecodeError::from(error)))
+    }
+
+    /// Decode the tokens before the first output in halves, then the rest. Returns the index of the
+    /// first token of the last part.
+    fn decode_split(&mut self, batch: &LlamaBatch) -> Result<i32, DecodeError> {
+        let n_tokens = batch.n_tokens();
+        let first_output = batch
+            .initialized_logits
+            .iter()
+            .copied()
+            .min()
+            .unwrap_or(n_tokens);
+        if first_output == 0 || batch.seq_positions(0, 0).is_none() {
+            return Err(DecodeError::NoKvSlot);
+        }
+        // the number of tokens at the start of the batch that are in the KV cache
+        let mut decoded = 0;
+        let result = self
+            .decode_halves(batch, 0, first_output, &mut decoded)
+            .and_then(|()| {
+                if first_output < n_tokens {
+                    let rest = batch
+                        .view(first_output, n_tokens)
+                        .ok_or(DecodeError::NoKvSlot)?;
+                    self.decode_raw(rest)?;
+                }
+                Ok(first_output)
+            });
+        if matches!(result, Err(DecodeError::NoKvSlot)) {
+            self.remove_decoded(batch, decoded);
+        }
+        result
+    }
+
+    fn decode_halves(
+        &mut self,
+        batch: &LlamaBatch,
+        start: i32,
+        end: i32,
+        decoded: &mut i32,
+    ) -> Result<(), DecodeError> {
+        let view = batch.view(start, end).ok_or(DecodeError::NoKvSlot)?;
+        match self.decode_raw(view) {
+            Err(DecodeError::NoKvSlot) if end - start > 1 => {
+                let mid = start + (end - start) / 2;
+                self.decode_halves(batch, start, mid, decoded)?;
+                self.decode_halves(batch, mid, end, decoded)
+            }
+            Ok(()) => {
+                *decoded = end;
                 Ok(())
             }
-            Some(error) => Err(DecodeError::from(error)),
+            result => result,
+        }
+    }
+
+    /// Remove the first `n_tokens` tokens of `batch` from the KV cache.
+    fn remove_decoded(&mut self, batch: &LlamaBatch, n_tokens: i32) {
+        let mem = unsafe { llama_cpp_sys_2::llama_get_memory(self.context.as_ptr()) };
+        for (seq, pos) in batch.seq_positions(0, n_tokens).unwrap_or_default() {
+            unsafe { llama_cpp_sys_2::llama_memory_seq_rm(mem, seq, pos, pos + 1) };
         }
     }
 
+    /// The index llama.cpp uses for the output of the `i`-th token of the last decoded batch.
+    pub(crate) fn output_index(&self, i: i32) -> i32 {
+        // negative indices count from the last output
+        if i < 0 {
+            i
+        } else {
+            i - self.output_offset
+        }
+    }
+
+    /// Set a callback that is polled while a batch is evaluated. Returning `true` stops the
+    /// evaluation and [`Self::decode`] returns [`DecodeError::Aborted`]. This replaces any previous
+    /// callback.
+    ///
+    /// The callback can be called from a thread of the compute backend. A panic in it aborts the
+    /// process, as it can not unwind through llama.cpp.
+    ///
+    /// ```no_run
+    /// # use llama_cpp_2::context::LlamaContext;
+    /// # fn example(ctx: &mut LlamaContext) {
+    /// use std::time::{Duration, Instant};
+    ///
+    /// let deadline = Instant::now() + Duration::from_secs(30);
+    /// ctx.set_abort_callback(move || Instant::now() > deadline);
+    /// # }
+    /// ```
+    pub fn set_abort_callback(&mut self, callback: impl FnMut() -> bool + Send + 'static) {
+        let mut callback: Box<AbortCallback> = Box::new(Box::new(callback));
+        let data: *mut AbortCallback = &raw mut *callback;
+        unsafe {
+            llama_cpp_sys_2::llama_set_abort_callback(
+                self.context.as_ptr(),
+                Some(abort_callback),
+                data.cast(),
+            );
+        }
+        self.abort_callback = Some(callback);
+    }
+
+    /// Abort evaluations once `cancel` is set, for example by another thread when a client
+    /// disconnects. See [`Self::set_abort_callback`].
+    pub fn set_abort_flag(&mut self, cancel: Arc<AtomicBool>) {
+        self.set_abort_callback(move || cancel.load(Ordering::Relaxed));
+    }
+
+    /// Remove the abort callback.
+    pub fn clear_abort_callback(&mut self) {
+        unsafe {
+            llama_cpp_sys_2::llama_set_abort_callback(
+                self.context.as_ptr(),
+                None,
+                std::ptr::null_mut(),
+            );
+        }
+        self.abort_callback = None;
+    }
+
     /// Encodes the batch.
     ///
     /// # Errors
@@ -108,6 +273,7 @@ impl<'model> LlamaContext<'model> {
 
         match NonZeroI32::new(result) {
             None => {
+                self.output_offset = 0;
                 self.initialized_logits
                     .clone_from(&batch.initialized_logits);
                 Ok(())
@@ -177,7 +343,10 @@ impl<'model> LlamaContext<'model> {
             usize::try_from(self.model.n_embd()).expect("n_embd does not fit into a usize");
 
         unsafe {
-            let embedding = llama_cpp_sys_2::llama_get_embeddings_ith(self.context.as_ptr(), i);
+            let embedding = llama_cpp_sys_2::llama_get_embeddings_ith(
+                self.context.as_ptr(),
+                self.output_index(i),
+            );
             // Technically also possible whenever `i >= batch.n_tokens`, but no good way of checking `n_tokens` here.
             if embedding.is_null() {
                 Err(EmbeddingsError::LogitsNotEnabled)
@@ -290,7 +459,9 @@ impl<'model> LlamaContext<'model> {
             i
         );
 
-        let data = unsafe { llama_cpp_sys_2::llama_get_logits_ith(self.context.as_ptr(), i) };
+        let data = unsafe {
+            llama_cpp_sys_2::llama_get_logits_ith(self.context.as_ptr(), self.output_index(i))
+        };
         let len = usize::try_from(self.model.n_vocab()).expect("n_vocab does not fit into a usize");
 
         unsafe { slice::from_raw_parts(data, len) }
@@ -356,6 +527,11 @@ impl<'model> LlamaContext<'model> {
     }
 }
 
+unsafe extern "C" fn abort_callback(data: *mut c_void) -> bool {
+    let callback = &mut *data.cast::<AbortCallback>();
+    callback()
+}
+
 impl Drop for LlamaContext<'_> {
     fn drop(&mut self) {
         unsafe { llama_cpp_sys_2::llama_free(self.context.as_ptr()) }
@@ -381,6 +381,39 @@ impl LlamaContextParams {
         self.context_params.offload_kqv
     }
 
+    /// Set whether all sequences share a single unified KV cache buffer.
+    ///
+    /// Partially copying one sequence's KV cache into another (as done by
+    /// [`PrefixCache`](crate::context::prefix_cache::PrefixCache)) requires a unified KV cache.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use llama_cpp_2::context::params::LlamaContextParams;
+    /// let params = LlamaContextParams::default()
+    ///     .with_kv_unified(true);
+    /// assert_eq!(params.kv_unified(), true);
+    /// ```
+    #[must_use]
+    pub fn with_kv_unified(mut self, enabled: bool) -> Self {
+        self.context_params.kv_unified = enabled;
+        self
+    }
+
+    /// Get whether all sequences share a single unified KV cache buffer.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use llama_cpp_2::context::params::LlamaContextParams;
+    /// let params = LlamaContextParams::default();
+    /// assert_eq!(params.kv_unified(), false);
+    /// ```
+    #[must_use]
+    pub fn kv_unified(&self) -> bool {
+        self.context_params.kv_unified
+    }
+
     /// Set the type of rope scaling.
     ///
     /// # Examples
@@ -0,0 +1,265 @@
+//! Reuse of the KV cache for prompts that share a prefix.
+//!
+//! Prompts often start with the same tokens, for example a long system prompt or few-shot
+//! examples. [`PrefixCache`] remembers which tokens are in each sequence of a [`LlamaContext`],
+//! finds the longest prefix a new prompt shares with any of them, copies it into the target
+//! sequence and only decodes the rest:
+//!
+//! ```no_run
+//! # use llama_cpp_2::context::LlamaContext;
+//! # use llama_cpp_2::token::LlamaToken;
+//! # fn example(ctx: &mut LlamaContext, prompts: &[Vec<LlamaToken>]) -> Result<(), Box<dyn std::error::Error>> {
+//! use llama_cpp_2::context::prefix_cache::PrefixCache;
+//! use llama_cpp_2::llama_batch::LlamaBatch;
+//! use llama_cpp_2::sampling::LlamaSampler;
+//!
+//! let mut cache = PrefixCache::new(1);
+//! let mut batch = LlamaBatch::new(ctx.n_batch() as usize, 1);
+//! let mut sampler = LlamaSampler::greedy();
+//! for prompt in prompts {
+//!     let reused = cache.evaluate(ctx, 0, prompt, &mut batch)?;
+//!     let token = sampler.sample(ctx, batch.n_tokens() - 1);
+//!     println!("reused {reused} tokens, sampled {token}");
+//! }
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! The cache only knows about the tokens it decoded itself or that were added with
+//! [`PrefixCache::push`]. Recurrent models can not remove part of a sequence, so nothing is reused
+//! for them.
+//!
+//! Prefixes are only copied from other sequences if the context was created with
+//! [`LlamaContextParams::with_kv_unified`](crate::context::params::LlamaContextParams::with_kv_unified),
+//! because llama.cpp can not copy part of a sequence between separate KV cache buffers. Otherwise
+//! each sequence only reuses its own previous tokens.
+
+use crate::context::kv_cache::KvCacheConversionError;
+use crate::context::LlamaContext;
+use crate::llama_batch::{BatchAddError, LlamaBatch};
+use crate::token::LlamaToken;
+use crate::DecodeError;
+
+/// Errors that can occur when reusing a prefix.
+#[derive(Debug, Eq, PartialEq, thiserror::Error)]
+pub enum PrefixCacheError {
+    /// A sequence id or position does not fit into the KV cache API.
+    #[error("{0}")]
+    KvCacheConversionError(#[from] KvCacheConversionError),
+    /// Decoding the rest of the prompt failed. The sequence is cleared.
+    #[error("{0}")]
+    DecodeError(#[from] DecodeError),
+    /// Adding a token to the batch failed. The sequence is cleared.
+    #[error("{0}")]
+    BatchAddError(#[from] BatchAddError),
+}
+
+/// The tokens in the sequences of a context, see [the module docs](self).
+///
+/// Reusing a prefix of another sequence requires a unified KV cache
+/// ([`LlamaContextParams::with_kv_unified`](crate::context::params::LlamaContextParams::with_kv_unified)),
+/// without it only the target sequence's own prefix is reused.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PrefixCache {
+    sequences: Vec<Vec<LlamaToken>>,
+}
+
+impl PrefixCache {
+    /// A cache for the sequences `0..n_seq` of a context whose KV cache is empty.
+    #[must_use]
+    pub fn new(n_seq: u32) -> Self {
+        Self {
+            sequences: vec![Vec::new(); n_seq as usize],
+        }
+    }
+
+    /// The tokens in sequence `seq_id`.
+    ///
+    /// # Panics
+    ///
+    /// If `seq_id` is not managed by the cache.
+    #[must_use]
+    pub fn tokens(&self, seq_id: u32) -> &[LlamaToken] {
+        &self.sequences[seq_id as usize]
+    }
+
+    /// Record that `tokens` were decoded into sequence `seq_id` after its current tokens, for
+    /// example the generated ones.
+    ///
+    /// # Panics
+    ///
+    /// If `seq_id` is not managed by the cache.
+    pub fn push(&mut self, seq_id: u32, tokens: &[LlamaToken]) {
+        self.sequences[seq_id as usize].extend_from_slice(tokens);
+    }
+
+    /// Forget the tokens of sequence `seq_id`, for example after it was changed without the cache.
+    ///
+    /// # Panics
+    ///
+    /// If `seq_id` is not managed by the cache.
+    pub fn forget(&mut self, seq_id: u32) {
+        self.sequences[seq_id as usize].clear();
+    }
+
+    /// Prepare sequence `seq_id` for `prompt`: copy the longest prefix it shares with any sequence
+    /// and remove everything after it. Returns the number of reused tokens; the tokens after them
+    /// still have to be decoded, at the positions following the reused ones.
+    ///
+    /// The last token of the prompt is never reused, so its logits can be computed.
+    ///
+    /// # Errors
+    ///
+    /// If a sequence id or position does not fit into the KV cache API.
+    ///
+    /// # Panics
+    ///
+    /// If `seq_id` is not managed by the cache.
+    pub fn reuse(
+        &mut self,
+        ctx: &mut LlamaContext,
+        seq_id: u32,
+        prompt: &[LlamaToken],
+    ) -> Result<usize, PrefixCacheError> {
+        let dest = i32::try_from(seq_id).map_err(KvCacheConversionError::SeqIdTooLarge)?;
+        let (src, mut n_reused) = self.best_match(seq_id, prompt, ctx.kv_unified);
+        if ctx.model.is_recurrent() {
+            n_reused = 0;
+        }
+        let position = |n: usize| u32::try_from(n).map_err(KvCacheConversionError::P1TooLarge);
+
+        if n_reused == 0 {
+            ctx.clear_kv_cache_seq(Some(seq_id), None, None)?;
+        } else if src == seq_id {
+            let start = position(n_reused)?;
+            if !ctx.clear_kv_cache_seq(Some(seq_id), Some(start), None)? {
+                // partial removal failed, start over
+                ctx.clear_kv_cache_seq(Some(seq_id), None, None)?;
+                n_reused = 0;
+            }
+        } else {
+            let end = position(n_reused)?;
+            let src_id = i32::try_from(src).map_err(KvCacheConversionError::SeqIdTooLarge)?;
+            ctx.clear_kv_cache_seq(Some(seq_id), None, None)?;
+            ctx.copy_kv_cache_seq(src_id, dest, None, Some(end))?;
+        }
+
+        let tokens = &mut self.sequences[seq_id as usize];
+        tokens.clear();
+        tokens.extend_from_slice(&prompt[..n_reused]);
+        Ok(n_reused)
+    }
+
+    /// [Reuse](Self::reuse) a prefix for `prompt` in sequence `seq_id` and decode the rest of it
+    /// with `batch`, in chunks of at most `n_batch` tokens. Afterwards the logits of the last token
+    /// of the prompt are at index `batch.n_tokens() - 1`. Returns the number of reused tokens.
+    ///
+    /// # Errors
+    ///
+    /// See [`PrefixCacheError`]. If decoding fails the sequence is cleared.
+    ///
+    /// # Panics
+    ///
+    /// If `seq_id` is not managed by the cache or `prompt` is empty.
+    pub fn evaluate(
+        &mut self,
+        ctx: &mut LlamaContext,
+        seq_id: u32,
+        prompt: &[LlamaToken],
+        batch: &mut LlamaBatch,
+    ) -> Result<usize, PrefixCacheError> {
+        assert!(!prompt.is_empty(), "the prompt must not be empty");
+        let n_reused = self.reuse(ctx, seq_id, prompt)?;
+        let result = self.decode(ctx, seq_id, &prompt[n_reused..], batch);
+        if result.is_err() {
+            self.forget(seq_id);
+            ctx.clear_kv_cache_seq(Some(seq_id), None, None)?;
+        }
+        result.map(|()| n_reused)
+    }
+
+    fn decode(
+        &mut self,
+        ctx: &mut LlamaContext,
+        seq_id: u32,
+        tokens: &[LlamaToken],
+        batch: &mut LlamaBatch,
+    ) -> Result<(), PrefixCacheError> {
+        let seq = i32::try_from(seq_id).map_err(KvCacheConversionError::SeqIdTooLarge)?;
+        let n_batch = usize::try_from(ctx.n_batch()).expect("n_batch fits into a usize");
+        for (i, chunk) in tokens.chunks(n_batch).enumerate() {
+            batch.clear();
+            let n_past = self.sequences[seq_id as usize].len();
+            for (j, &token) in chunk.iter().enumerate() {
+                let pos = i32::try_from(n_past + j).expect("position fits into an i32");
+                let last = i * n_batch + j + 1 == tokens.len();
+                batch.add(token, pos, &[seq], last)?;
+            }
+            ctx.decode(batch)?;
+            self.push(seq_id, chunk);
+        }
+        Ok(())
+    }
+
+    /// The sequence sharing the longest prefix with `prompt`, preferring `seq_id`, and the length
+    /// of the prefix, which is shorter than the prompt. Other sequences are only considered if
+    /// `other_sequences` is set.
+    fn best_match(
+        &self,
+        seq_id: u32,
+        prompt: &[LlamaToken],
+        other_sequences: bool,
+    ) -> (u32, usize) {
+        let shared = |tokens: &[LlamaToken]| {
+            tokens
+                .iter()
+                .zip(prompt)
+                .take_while(|(a, b)| a == b)
+                .count()
+                .min(prompt.len().saturating_sub(1))
+        };
+        let own = shared(self.tokens(seq_id));
+        if !other_sequences {
+            return (seq_id, own);
+        }
+        (0..)
+            .zip(&self.sequences)
+            .map(|(id, tokens)| (id, shared(tokens)))
+            .filter(|&(_, n)| n > own)
+            .max_by_key(|&(id, n)| (n, std::cmp::Reverse(id)))
+            .unwrap_or((seq_id, own))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tokens(ids: &[i32]) -> Vec<LlamaToken> {
+        ids.iter().copied().map(LlamaToken).collect()
+    }
+
+    #[test]
+    fn best_match() {
+        let mut cache = PrefixCache::new(3);
+        cache.push(0, &tokens(&[1, 2, 3]));
+        cache.push(1, &tokens(&[1, 2, 3, 4, 5]));
+        cache.push(2, &tokens(&[1, 2, 3, 4, 6]));
+
+        assert_eq!(
+            cache.best_match(0, &tokens(&[1, 2, 3, 4, 5, 6]), true),
+            (1, 5)
+        );
+        assert_eq!(cache.best_match(2, &tokens(&[1, 2, 3, 4, 7]), true), (2, 4));
+        assert_eq!(cache.best_match(0, &tokens(&[1, 2, 3, 4, 7]), true), (1, 4));
+        // the last token of the prompt is always decoded
+        assert_eq!(cache.best_match(1, &tokens(&[1, 2, 3, 4, 5]), true), (1, 4));
+        assert_eq!(cache.best_match(0, &tokens(&[9]), true), (0, 0));
+        assert_eq!(cache.best_match(0, &[], true), (0, 0));
+        // without a unified KV cache only the own sequence is reused
+        assert_eq!(
+            cache.best_match(0, &tokens(&[1, 2, 3, 4, 5, 6]), false),
+            (0, 3)
+        );
+    }
+}
@@ -0,0 +1,334 @@
+//! Moving tokens in the KV cache to make room for more of them.
+//!
+//! - Context shifting: when a sequence reaches `n_ctx`, [`LlamaContext::shift_context`] keeps the
+//!   first tokens (for example the system prompt), removes a fraction of the tokens after them
+//!   from the KV cache and moves the remaining tokens back, so generation can continue. The model
+//!   loses the discarded tokens from its view.
+//!   [`crate::completion::Completion::with_context_shift`] does this automatically.
+//! - [`SelfExtend`] (grouped attention, <https://arxiv.org/abs/2401.01325>): the positions of
+//!   older tokens are divided by a group size, so a model can attend to more tokens than its
+//!   training context `n_ctx_train` without fine-tuning. The context itself must still be large
+//!   enough for all tokens. [`crate::completion::Completion::with_self_extend`] does this
+//!   automatically.
+
+use std::num::{NonZeroU8, TryFromIntError};
+
+use crate::context::kv_cache::KvCacheConversionError;
+use crate::context::LlamaContext;
+use crate::model::RopeType;
+
+/// Errors that can occur when shifting the context.
+#[derive(Debug, Eq, PartialEq, thiserror::Error)]
+pub enum ContextShiftError {
+    /// The model can not shift its context, for example a recurrent model or one using M-RoPE.
+    #[error("the model does not support context shifting")]
+    Unsupported,
+    /// All tokens of the sequence are kept, so nothing can be discarded.
+    #[error("no tokens can be discarded: {n_past} tokens with {n_keep} to keep")]
+    NothingToDiscard {
+        /// The number of tokens in the sequence.
+        n_past: usize,
+        /// The number of tokens to keep.
+        n_keep: usize,
+    },
+    /// llama.cpp failed to remove the tokens from the KV cache.
+    #[error("the tokens could not be removed from the KV cache")]
+    RemoveFailed,
+    /// A position does not fit into the KV cache API.
+    #[error("{0}")]
+    KvCacheConversionError(#[from] KvCacheConversionError),
+}
+
+/// How to shift the context, see [the module docs](self).
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ContextShift {
+    n_keep: usize,
+    discard: f32,
+}
+
+impl ContextShift {
+    /// Keep the first `n_keep` tokens and discard half of the others, like llama.cpp.
+    #[must_use]
+    pub fn new(n_keep: usize) -> Self {
+        Self {
+            n_keep,
+            discard: 0.5,
+        }
+    }
+
+    /// Set the fraction of the tokens after the kept ones to discard. At least one token is
+    /// discarded.
+    ///
+    /// # Panics
+    ///
+    /// If `discard` is not in `0.0..=1.0`.
+    #[must_use]
+    pub fn with_discard(mut self, discard: f32) -> Self {
+        assert!(
+            (0.0..=1.0).contains(&discard),
+            "discard must be in 0.0..=1.0, but is {discard}"
+        );
+        self.discard = discard;
+        self
+    }
+
+    /// The number of tokens kept at the start of the sequence.
+    #[must_use]
+    pub fn n_keep(&self) -> usize {
+        self.n_keep
+    }
+
+    /// The number of tokens to discard from a sequence of `n_past` tokens.
+    #[must_use]
+    #[allow(
+        clippy::cast_precision_loss,
+        clippy::cast_possible_truncation,
+        clippy::cast_sign_loss
+    )]
+    pub fn n_discard(&self, n_past: usize) -> usize {
+        let n_left = n_past.saturating_sub(self.n_keep);
+        ((n_left as f32 * self.discard) as usize).clamp(n_left.min(1), n_left)
+    }
+}
+
+impl LlamaContext<'_> {
+    /// Whether the context can be shifted with [`Self::shift_context`]. This is not the case for
+    /// recurrent models, models using M-RoPE, and if llama.cpp reports that the memory can not be
+    /// shifted.
+    #[must_use]
+    pub fn can_shift_context(&self) -> bool {
+        if self.model.is_recurrent()
+            || matches!(
+                self.model.rope_type(),
+                Some(RopeType::MRope | RopeType::Vision)
+            )
+        {
+            return false;
+        }
+        let mem = unsafe { llama_cpp_sys_2::llama_get_memory(self.context.as_ptr()) };
+        unsafe { llama_cpp_sys_2::llama_memory_can_shift(mem) }
+    }
+
+    /// Discard tokens of sequence `seq_id`, which has `n_past` tokens, as configured by `shift`
+    /// and move the following tokens back. Returns the number of discarded tokens, the next token
+    /// goes to position `n_past - n_discard`.
+    ///
+    /// # Errors
+    ///
+    /// See [`ContextShiftError`]. The KV cache is unchanged on error.
+    pub fn shift_context(
+        &mut self,
+        seq_id: i32,
+        n_past: usize,
+        shift: &ContextShift,
+    ) -> Result<usize, ContextShiftError> {
+        if !self.can_shift_context() {
+            return Err(ContextShiftError::Unsupported);
+        }
+        let n_discard = shift.n_discard(n_past);
+        if n_discard == 0 {
+            return Err(ContextShiftError::NothingToDiscard {
+                n_past,
+                n_keep: shift.n_keep,
+            });
+        }
+
+        // check all positions first, so the KV cache is not changed if one does not fit
+        let position = |pos: usize, error: fn(TryFromIntError) -> KvCacheConversionError| {
+            i32::try_from(pos).map_err(error)
+        };
+        let keep = position(shift.n_keep, KvCacheConversionError::P0TooLarge)?;
+        let discard_end = position(shift.n_keep + n_discard, KvCacheConversionError::P1TooLarge)?;
+        let end = position(n_past, KvCacheConversionError::P1TooLarge)?;
+
+        // a negative id matches all sequences, like in llama.cpp
+        let seq = u32::try_from(seq_id).ok();
+        let removed = self.clear_kv_cache_seq(
+            seq,
+            Some(keep.unsigned_abs()),
+            Some(discard_end.unsigned_abs()),
+        )?;
+        if !removed {
+            return Err(ContextShiftError::RemoveFailed);
+        }
+        self.kv_cache_seq_add(
+            seq_id,
+            Some(discard_end.unsigned_abs()),
+            Some(end.unsigned_abs()),
+            keep - discard_end,
+        )?;
+        Ok(n_discard)
+    }
+}
+
+/// Self-Extend with a group size and a window width, see [the module docs](self).
+///
+/// Before evaluating tokens at position `pos`, call [`SelfExtend::apply`] with it and use the
+/// returned position instead. Batches must not be larger than the window.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SelfExtend {
+    group_size: NonZeroU8,
+    window: u32,
+    /// The start of the next window to group.
+    grouped: u32,
+}
+
+/// A change of positions in the KV cache.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum PositionOp {
+    Add { p0: u32, p1: u32, delta: i32 },
+    Div { p0: u32, p1: u32, d: NonZeroU8 },
+}
+
+impl SelfExtend {
+    /// Group `group_size` positions into one for the tokens outside of the last `window` positions.
+    /// This extends the usable context to about `group_size` times the training context, with a
+    /// `window` of at most the training context.
+    ///
+    /// # Panics
+    ///
+    /// If `window` is not a non-zero multiple of `group_size`.
+    #[must_use]
+    pub fn new(group_size: NonZeroU8, window: u32) -> Self {
+        assert!(
+            window > 0 && window.checked_rem(u32::from(group_size.get())) == Some(0),
+            "the window ({window}) must be a non-zero multiple of the group size ({group_size})"
+        );
+        Self {
+            group_size,
+            window,
+            grouped: 0,
+        }
+    }
+
+    /// The number of positions grouped into one.
+    #[must_use]
+    pub fn group_size(&self) -> NonZeroU8 {
+        self.group_size
+    }
+
+    /// The width of the window.
+    #[must_use]
+    pub fn window(&self) -> u32 {
+        self.window
+    }
+
+    /// Forget the grouped positions, for a new sequence.
+    pub fn reset(&mut self) {
+        self.grouped = 0;
+    }
+
+    /// Group the positions of sequence `seq_id` that left the window and return the position of
+    /// the next token, which replaces `pos`.
+    ///
+    /// # Errors
+    ///
+    /// If the model can not shift its context or a position does not fit into the KV cache API.
+    pub fn apply(
+        &mut self,
+        ctx: &mut LlamaContext,
+        seq_id: i32,
+        pos: u32,
+    ) -> Result<u32, ContextShiftError> {
+        let (ops, pos) = self.ops(pos);
+        if ops.is_empty() {
+            return Ok(pos);
+        }
+        if !ctx.can_shift_context() {
+            return Err(ContextShiftError::Unsupported);
+        }
+        for op in ops {
+            match op {
+                PositionOp::Add { p0, p1, delta } => {
+                    ctx.kv_cache_seq_add(seq_id, Some(p0), Some(p1), delta)?;
+                }
+                PositionOp::Div { p0, p1, d } => {
+                    ctx.kv_cache_seq_div(seq_id, Some(p0), Some(p1), d)?;
+                }
+            }
+        }
+        Ok(pos)
+    }
+
+    /// The changes of positions that group the windows before `pos`, as in the `main` example of
+    /// llama.cpp.
+    #[allow(clippy::cast_possible_wrap)]
+    fn ops(&mut self, mut pos: u32) -> (Vec<PositionOp>, u32) {
+        let n = u32::from(self.group_size.get());
+        let w = self.window;
+        let mut ops = Vec::new();
+        while pos >= self.grouped + w {
+            // the tokens are moved by these offsets, which are below `n_ctx`
+            let ib = (n * self.grouped) / w;
+            let bd = (w / n) * (n - 1);
+            let dd = (w / n) as i32 - (ib * bd) as i32 - w as i32;
+            let start = self.grouped + ib * bd;
+            ops.push(PositionOp::Add {
+                p0: self.grouped,
+                p1: pos,
+                delta: (ib * bd) as i32,
+            });
+            ops.push(PositionOp::Div {
+                p0: start,
+                p1: start + w,
+                d: self.group_size,
+            });
+            ops.push(PositionOp::Add {
+                p0: start + w,
+                p1: pos + ib * bd,
+                delta: dd,
+            });
+            pos -= bd;
+            self.grouped += w / n;
+        }
+        (ops, pos)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Apply `ops` to the positions of the tokens, like llama.cpp.
+    fn apply(positions: &mut [u32], ops: &[PositionOp]) {
+        for &op in ops {
+            for pos in positions.iter_mut() {
+                match op {
+                    PositionOp::Add { p0, p1, delta } if (p0..p1).contains(pos) => {
+                        *pos = pos.checked_add_signed(delta).unwrap();
+                    }
+                    PositionOp::Div { p0, p1, d } if (p0..p1).contains(pos) => {
+                        *pos /= u32::from(d.get());
+                    }
+                    _ => {}
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn self_extend() {
+        let mut self_extend = SelfExtend::new(NonZeroU8::new(2).unwrap(), 4);
+        let mut positions = Vec::new();
+        let mut pos = 0;
+        for _ in 0..12 {
+            let (ops, next) = self_extend.ops(pos);
+            apply(&mut positions, &ops);
+            positions.push(next);
+            pos = next + 1;
+        }
+        assert_eq!(positions, [0, 0, 1, 1, 2, 2, 3, 3, 4, 5, 6, 7]);
+        assert_eq!(pos, 8);
+    }
+
+    #[test]
+    fn n_discard() {
+        let shift = ContextShift::new(4);
+        assert_eq!(shift.n_discard(100), 48);
+        assert_eq!(shift.n_discard(5), 1);
+        assert_eq!(shift.n_discard(4), 0);
+        assert_eq!(shift.n_discard(2), 0);
+        assert_eq!(shift.with_discard(0.0).n_discard(100), 1);
+        assert_eq!(shift.with_discard(1.0).n_discard(100), 96);
+    }
+}
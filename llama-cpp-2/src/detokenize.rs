@@ -0,0 +1,197 @@
+//! Incremental conversion of generated tokens to text.
+//!
+//! A token does not always end on a character boundary: multibyte UTF-8 characters are often split
+//! over several byte tokens. [`StreamingDetokenizer`] buffers incomplete characters and only
+//! yields complete text:
+//!
+//! ```no_run
+//! # use llama_cpp_2::model::LlamaModel;
+//! # use llama_cpp_2::token::LlamaToken;
+//! # fn example(model: &LlamaModel, tokens: &[LlamaToken]) -> Result<(), Box<dyn std::error::Error>> {
+//! use llama_cpp_2::detokenize::{SpecialTokens, StreamingDetokenizer};
+//!
+//! let mut detokenizer = StreamingDetokenizer::new(model)
+//!     .with_special(SpecialTokens::Render)
+//!     .with_strip_leading_space(true);
+//! for &token in tokens {
+//!     print!("{}", detokenizer.push(token)?);
+//! }
+//! println!("{}", detokenizer.flush());
+//! # Ok(())
+//! # }
+//! ```
+
+use std::num::NonZeroU16;
+
+use crate::model::LlamaModel;
+use crate::token::LlamaToken;
+use crate::TokenToStringError;
+
+/// How special and control tokens, such as `<|im_end|>`, are converted to text.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SpecialTokens {
+    /// Render them as their text, e.g. `<|im_end|>`.
+    Render,
+    /// Leave them out of the text, like llama.cpp does by default.
+    #[default]
+    Hide,
+}
+
+/// Converts a stream of tokens to text, see [the module docs](self).
+#[derive(Debug, Clone)]
+pub struct StreamingDetokenizer<'a> {
+    model: &'a LlamaModel,
+    special: SpecialTokens,
+    strip_leading_space: bool,
+    /// No text has been produced since the start or the last flush.
+    at_start: bool,
+    utf8: Utf8Decoder,
+}
+
+impl<'a> StreamingDetokenizer<'a> {
+    /// Create a detokenizer for the tokens of `model` that hides special tokens and keeps leading
+    /// spaces.
+    #[must_use]
+    pub fn new(model: &'a LlamaModel) -> Self {
+        Self {
+            model,
+            special: SpecialTokens::default(),
+            strip_leading_space: false,
+            at_start: true,
+            utf8: Utf8Decoder::default(),
+        }
+    }
+
+    /// Set how special tokens are converted to text.
+    #[must_use]
+    pub fn with_special(mut self, special: SpecialTokens) -> Self {
+        self.special = special;
+        self
+    }
+
+    /// Strip a leading space from the first token that produces text, e.g. the space
+    /// `SentencePiece` tokenizers prepend to the first word of a prompt.
+    #[must_use]
+    pub fn with_strip_leading_space(mut self, strip_leading_space: bool) -> Self {
+        self.strip_leading_space = strip_leading_space;
+        self
+    }
+
+    /// Add a token and return the text that is complete. The text can be empty, for example if
+    /// the token is part of a multibyte character.
+    ///
+    /// # Errors
+    ///
+    /// If llama.cpp fails to convert the token.
+    ///
+    /// # Panics
+    ///
+    /// If the size reported by llama.cpp does not fit into a [`usize`]. (this should never happen)
+    pub fn push(&mut self, token: LlamaToken) -> Result<String, TokenToStringError> {
+        let special = self.special == SpecialTokens::Render;
+        let lstrip = if self.at_start && self.strip_leading_space {
+            NonZeroU16::new(1)
+        } else {
+            None
+        };
+        let bytes = match self.model.token_to_piece_bytes(token, 8, special, lstrip) {
+            Err(TokenToStringError::InsufficientBufferSpace(size)) => {
+                self.model.token_to_piece_bytes(
+                    token,
+                    usize::try_from(size.unsigned_abs()).expect("size fits into usize"),
+                    special,
+                    lstrip,
+                )
+            }
+            // hidden special tokens have no text
+            Err(TokenToStringError::UnknownTokenType) => Ok(Vec::new()),
+            bytes => bytes,
+        }?;
+        if !bytes.is_empty() {
+            self.at_start = false;
+        }
+        Ok(self.utf8.push(&bytes))
+    }
+
+    /// Return the remaining text at the end of the stream, with an incomplete character replaced by
+    /// U+FFFD. Afterwards the detokenizer can be reused for a new stream.
+    pub fn flush(&mut self) -> String {
+        self.at_start = true;
+        self.utf8.flush()
+    }
+}
+
+/// Decodes UTF-8 that arrives in chunks.
+#[derive(Debug, Clone, Default)]
+struct Utf8Decoder {
+    /// Bytes of an incomplete character.
+    pending: Vec<u8>,
+}
+
+impl Utf8Decoder {
+    /// Add bytes and decode all complete characters, invalid bytes are replaced by U+FFFD.
+    fn push(&mut self, bytes: &[u8]) -> String {
+        self.pending.extend_from_slice(bytes);
+
+        let mut text = String::new();
+        let mut rest = self.pending.as_slice();
+        loop {
+            match std::str::from_utf8(rest) {
+                Ok(valid) => {
+                    text.push_str(valid);
+                    rest = &[];
+                    break;
+                }
+                Err(error) => {
+                    let (valid, invalid) = rest.split_at(error.valid_up_to());
+                    text.push_str(std::str::from_utf8(valid).expect("validated by from_utf8"));
+                    let Some(len) = error.error_len() else {
+                        // an incomplete character at the end, wait for the next token
+                        rest = invalid;
+                        break;
+                    };
+                    text.push(char::REPLACEMENT_CHARACTER);
+                    rest = &invalid[len..];
+                }
+            }
+        }
+        let consumed = self.pending.len() - rest.len();
+        self.pending.drain(..consumed);
+        text
+    }
+
+    fn flush(&mut self) -> String {
+        let text = String::from_utf8_lossy(&self.pending).into_owned();
+        self.pending.clear();
+        text
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decode(chunks: &[&[u8]]) -> Vec<String> {
+        let mut decoder = Utf8Decoder::default();
+        let mut texts: Vec<_> = chunks.iter().map(|chunk| decoder.push(chunk)).collect();
+        texts.push(decoder.flush());
+        texts
+    }
+
+    #[test]
+    fn split_characters() {
+        // "é" is C3 A9, "🦙" is F0 9F A6 99
+        assert_eq!(
+            decode(&[b"caf\xC3", b"\xA9 \xF0\x9F", b"\xA6", b"\x99!"]),
+            ["caf", "é ", "", "🦙!", ""]
+        );
+    }
+
+    #[test]
+    fn invalid_bytes() {
+        assert_eq!(
+            decode(&[b"a\xFFb\xC3", b"c", b"\xE2\x82"]),
+            ["a\u{FFFD}b", "\u{FFFD}c", "", "\u{FFFD}"]
+        );
+    }
+}
@@ -0,0 +1,777 @@
+//! A native rust parser for GBNF grammars.
+//!
+//! This is a translation of the grammar parser in llama.cpp (`src/llama-grammar.cpp`). It allows
+//! grammars to be validated (with line and column information on failure) before they are handed
+//! to [`crate::sampling::LlamaSampler::grammar`], which only reports a null sampler.
+//!
+//! ```
+//! # use std::str::FromStr;
+//! use llama_cpp_2::grammar::LlamaGrammar;
+//!
+//! let grammar = LlamaGrammar::from_str(r#"root ::= "yes" | "no""#).unwrap();
+//! assert_eq!(grammar.as_str(), r#"root ::= "yes" | "no""#);
+//!
+//! let err = LlamaGrammar::from_str("root ::= \"yes\" | answer").unwrap_err();
+//! assert_eq!(err.to_string(), "undefined rule `answer` at line 1, column 18");
+//! ```
+
+use std::collections::BTreeMap;
+use std::fmt::{Display, Formatter, Write};
+use std::str::FromStr;
+
+use llama_cpp_sys_2::{
+    llama_grammar_element, llama_gretype, LLAMA_GRETYPE_ALT, LLAMA_GRETYPE_CHAR,
+    LLAMA_GRETYPE_CHAR_ALT, LLAMA_GRETYPE_CHAR_ANY, LLAMA_GRETYPE_CHAR_NOT,
+    LLAMA_GRETYPE_CHAR_RNG_UPPER, LLAMA_GRETYPE_END, LLAMA_GRETYPE_RULE_REF,
+};
+
+#[cfg(feature = "serde")]
+pub mod json_schema;
+pub mod regex;
+
+#[cfg(test)]
+mod tests;
+
+/// A position in a grammar string. Both the line and the column are 1-based, the column is
+/// counted in characters.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Location {
+    /// The line number.
+    pub line: usize,
+    /// The column number.
+    pub column: usize,
+}
+
+impl Location {
+    /// Compute the location of the byte `offset` within `src`.
+    fn from_offset(src: &str, offset: usize) -> Self {
+        let before = &src[..offset];
+        let line_start = before.rfind('\n').map_or(0, |i| i + 1);
+        Self {
+            line: before.matches('\n').count() + 1,
+            column: before[line_start..].chars().count() + 1,
+        }
+    }
+}
+
+impl Display for Location {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "line {}, column {}", self.line, self.column)
+    }
+}
+
+/// There was an error parsing a grammar.
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+#[allow(clippy::module_name_repetitions)]
+pub enum GrammarParseError {
+    /// The grammar ended in the middle of a rule.
+    #[error("unexpected end of input at {0}")]
+    UnexpectedEndOfInput(Location),
+    /// A specific token was expected but something else was found.
+    #[error("expected {expected} at {location}")]
+    Expected {
+        /// A description of what was expected.
+        expected: &'static str,
+        /// Where it was expected.
+        location: Location,
+    },
+    /// An escape sequence that is not understood by llama.cpp.
+    #[error("unknown escape sequence at {0}")]
+    UnknownEscape(Location),
+    /// A `\x`, `\u` or `\U` escape that is not followed by enough hex digits.
+    #[error("expected {digits} hex digits at {location}")]
+    InvalidHexEscape {
+        /// The number of hex digits required by the escape.
+        digits: usize,
+        /// The location of the escape.
+        location: Location,
+    },
+    /// A hex escape that does not correspond to a valid unicode scalar value.
+    #[error("invalid code point {code_point:#x} at {location}")]
+    InvalidCodePoint {
+        /// The decoded value.
+        code_point: u32,
+        /// The location of the escape.
+        location: Location,
+    },
+    /// `*`, `+`, `?` or `{m,n}` without an item to repeat.
+    #[error("expected an item before the repetition operator at {0}")]
+    MissingRepetitionTarget(Location),
+    /// A `{m,n}` repetition where `n < m`.
+    #[error("invalid repetition bounds {{{min},{max}}} at {location}")]
+    InvalidRepetitionBounds {
+        /// The minimum number of repetitions.
+        min: u32,
+        /// The maximum number of repetitions.
+        max: u32,
+        /// The location of the repetition.
+        location: Location,
+    },
+    /// A repetition count that does not fit into a `u32`.
+    #[error("repetition count is too large at {0}")]
+    RepetitionTooLarge(Location),
+    /// A rule is referenced but never defined.
+    #[error("undefined rule `{name}` at {location}")]
+    UndefinedRule {
+        /// The name of the rule.
+        name: String,
+        /// The location of the first reference to the rule.
+        location: Location,
+    },
+    /// The grammar does not define a `root` rule.
+    #[error("grammar does not define a `root` rule")]
+    MissingRoot,
+    /// A rule can reach itself without consuming input, which llama.cpp rejects.
+    #[error("left recursion detected in rule `{0}`")]
+    LeftRecursion(String),
+}
+
+impl GrammarParseError {
+    /// The location the error occurred at, if it is tied to a position in the grammar.
+    #[must_use]
+    pub fn location(&self) -> Option<Location> {
+        match self {
+            Self::UnexpectedEndOfInput(location)
+            | Self::UnknownEscape(location)
+            | Self::MissingRepetitionTarget(location)
+            | Self::RepetitionTooLarge(location)
+            | Self::Expected { location, .. }
+            | Self::InvalidHexEscape { location, .. }
+            | Self::InvalidCodePoint { location, .. }
+            | Self::InvalidRepetitionBounds { location, .. }
+            | Self::UndefinedRule { location, .. } => Some(*location),
+            Self::MissingRoot | Self::LeftRecursion(_) => None,
+        }
+    }
+}
+
+/// A validated GBNF grammar.
+///
+/// Parsing a grammar checks everything llama.cpp checks when creating a grammar sampler (syntax,
+/// undefined rules, a `root` rule and left recursion), so a grammar that parses can be handed to
+/// [`crate::sampling::LlamaSampler::grammar`] with [`LlamaGrammar::as_str`] and `"root"`.
+#[derive(Debug, Clone, PartialEq)]
+#[allow(clippy::module_name_repetitions)]
+pub struct LlamaGrammar {
+    source: String,
+    parse: ParseState,
+}
+
+impl LlamaGrammar {
+    /// The grammar as it was parsed.
+    #[must_use]
+    pub fn as_str(&self) -> &str {
+        &self.source
+    }
+
+    /// The rules of the grammar, indexed by symbol id.
+    #[must_use]
+    pub fn rules(&self) -> &[Vec<llama_grammar_element>] {
+        &self.parse.rules
+    }
+
+    /// The symbol id of every named rule, including the rules synthesized for groups and
+    /// repetitions.
+    #[must_use]
+    pub fn symbol_ids(&self) -> &BTreeMap<String, u32> {
+        &self.parse.symbol_ids
+    }
+
+    /// The symbol id of the `root` rule.
+    ///
+    /// # Panics
+    ///
+    /// Never, a [`LlamaGrammar`] always has a root rule.
+    #[must_use]
+    pub fn root_id(&self) -> u32 {
+        self.parse.symbol_ids["root"]
+    }
+}
+
+impl FromStr for LlamaGrammar {
+    type Err = GrammarParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parse = ParseState::from_str(s)?;
+        if !parse.symbol_ids.contains_key("root") {
+            return Err(GrammarParseError::MissingRoot);
+        }
+        if let Some(rule_id) = parse.find_left_recursion() {
+            return Err(GrammarParseError::LeftRecursion(
+                parse.symbol_name(rule_id).to_string(),
+            ));
+        }
+        Ok(Self {
+            source: s.to_string(),
+            parse,
+        })
+    }
+}
+
+impl Display for LlamaGrammar {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.source)
+    }
+}
+
+/// The output of the grammar parser: the symbol table and the rules of a grammar.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct ParseState {
+    symbol_ids: BTreeMap<String, u32>,
+    rules: Vec<Vec<llama_grammar_element>>,
+}
+
+impl ParseState {
+    fn new() -> Self {
+        Self {
+            symbol_ids: BTreeMap::new(),
+            rules: Vec::new(),
+        }
+    }
+
+    fn next_id(&self) -> u32 {
+        u32::try_from(self.symbol_ids.len()).expect("number of symbols fits into a u32")
+    }
+
+    fn get_symbol_id(&mut self, name: &str) -> u32 {
+        let next_id = self.next_id();
+        *self.symbol_ids.entry(name.to_string()).or_insert(next_id)
+    }
+
+    fn generate_symbol_id(&mut self, base_name: &str) -> u32 {
+        let next_id = self.next_id();
+        self.symbol_ids
+            .insert(format!("{base_name}_{next_id}"), next_id);
+        next_id
+    }
+
+    fn add_rule(&mut self, rule_id: u32, rule: Vec<llama_grammar_element>) {
+        let rule_id = rule_id as usize;
+        if self.rules.len() <= rule_id {
+            self.rules.resize(rule_id + 1, Vec::new());
+        }
+        self.rules[rule_id] = rule;
+    }
+
+    fn symbol_name(&self, rule_id: u32) -> &str {
+        self.symbol_ids
+            .iter()
+            .find(|(_, &id)| id == rule_id)
+            .map_or("", |(name, _)| name.as_str())
+    }
+
+    /// Port of `llama_grammar_detect_left_recursion`. Returns the first rule that is left
+    /// recursive.
+    fn find_left_recursion(&self) -> Option<u32> {
+        let n_rules = self.rules.len();
+        let mut visited = vec![false; n_rules];
+        let mut in_progress = vec![false; n_rules];
+        let mut may_be_empty = vec![false; n_rules];
+        (0..n_rules)
+            .find(|&i| {
+                !visited[i]
+                    && self.detect_left_recursion(
+                        i,
+                        &mut visited,
+                        &mut in_progress,
+                        &mut may_be_empty,
+                    )
+            })
+            .map(|i| u32::try_from(i).expect("number of rules fits into a u32"))
+    }
+
+    fn detect_left_recursion(
+        &self,
+        rule_index: usize,
+        visited: &mut [bool],
+        in_progress: &mut [bool],
+        may_be_empty: &mut [bool],
+    ) -> bool {
+        if in_progress[rule_index] {
+            return true;
+        }
+        in_progress[rule_index] = true;
+        let rule = &self.rules[rule_index];
+
+        // first check if the rule might produce the empty string
+        let mut at_rule_start = true;
+        for element in rule {
+            if is_end_of_sequence(*element) {
+                if at_rule_start {
+                    may_be_empty[rule_index] = true;
+                    break;
+                }
+                at_rule_start = true;
+            } else {
+                at_rule_start = false;
+            }
+        }
+
+        // then recurse into leftmost nonterminals (or next-leftmost as long as the previous
+        // nonterminal may be empty)
+        let mut recurse_into_nonterminal = true;
+        for element in rule {
+            if element.type_ == LLAMA_GRETYPE_RULE_REF && recurse_into_nonterminal {
+                let referenced = element.value as usize;
+                if self.detect_left_recursion(referenced, visited, in_progress, may_be_empty) {
+                    return true;
+                }
+                if !may_be_empty[referenced] {
+                    recurse_into_nonterminal = false;
+                }
+            } else {
+                recurse_into_nonterminal = is_end_of_sequence(*element);
+            }
+        }
+
+        in_progress[rule_index] = false;
+        visited[rule_index] = true;
+        false
+    }
+}
+
+impl FromStr for ParseState {
+    type Err = GrammarParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parser = Parser {
+            src: s,
+            state: ParseState::new(),
+            references: BTreeMap::new(),
+        };
+        parser.parse()?;
+        Ok(parser.state)
+    }
+}
+
+fn element(type_: llama_gretype, value: u32) -> llama_grammar_element {
+    llama_grammar_element { type_, value }
+}
+
+fn is_end_of_sequence(element: llama_grammar_element) -> bool {
+    matches!(element.type_, LLAMA_GRETYPE_END | LLAMA_GRETYPE_ALT)
+}
+
+/// Format a string as a GBNF literal.
+pub(crate) fn format_literal(literal: &str) -> String {
+    let mut out = String::with_capacity(literal.len() + 2);
+    out.push('"');
+    for c in literal.chars() {
+        match c {
+            '\r' => out.push_str("\\r"),
+            '\n' => out.push_str("\\n"),
+            '\t' => out.push_str("\\t"),
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            c if c.is_control() => {
+                let _ = write!(out, "\\x{:02X}", u32::from(c));
+            }
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+/// Format a character for use inside a GBNF character class.
+pub(crate) fn format_range_char(c: char) -> String {
+    match c {
+        '\r' => "\\r".to_string(),
+        '\n' => "\\n".to_string(),
+        '\t' => "\\t".to_string(),
+        '"' | '[' | ']' | '\\' => format!("\\{c}"),
+        // GBNF has no escapes for these, and both are special inside a character class
+        '-' | '^' => format!("\\x{:02X}", u32::from(c)),
+        c if c.is_control() => format!("\\x{:02X}", u32::from(c)),
+        c => c.to_string(),
+    }
+}
+
+fn is_word_char(c: u8) -> bool {
+    c.is_ascii_alphanumeric() || c == b'-' || c == b'_'
+}
+
+type ParseResult<T> = Result<T, GrammarParseError>;
+
+/// A recursive descent parser over byte offsets into `src`.
+struct Parser<'a> {
+    src: &'a str,
+    state: ParseState,
+    /// The offset of the first reference to each symbol, used to report undefined rules.
+    references: BTreeMap<u32, usize>,
+}
+
+impl<'a> Parser<'a> {
+    fn peek(&self, pos: usize) -> Option<u8> {
+        self.src.as_bytes().get(pos).copied()
+    }
+
+    fn at(&self, pos: usize) -> Location {
+        Location::from_offset(self.src, pos)
+    }
+
+    fn expected(&self, expected: &'static str, pos: usize) -> GrammarParseError {
+        match self.peek(pos) {
+            None => GrammarParseError::UnexpectedEndOfInput(self.at(pos)),
+            Some(_) => GrammarParseError::Expected {
+                expected,
+                location: self.at(pos),
+            },
+        }
+    }
+
+    fn parse(&mut self) -> ParseResult<()> {
+        let mut pos = self.parse_space(0, true);
+        while pos < self.src.len() {
+            pos = self.parse_rule(pos)?;
+        }
+
+        // ensure that all referenced rules are defined
+        let undefined = self
+            .state
+            .symbol_ids
+            .iter()
+            .filter(|(_, &id)| self.state.rules.get(id as usize).is_none_or(Vec::is_empty))
+            .filter_map(|(name, id)| Some((*self.references.get(id)?, name)))
+            .min();
+        if let Some((pos, name)) = undefined {
+            return Err(GrammarParseError::UndefinedRule {
+                name: name.clone(),
+                location: self.at(pos),
+            });
+        }
+        Ok(())
+    }
+
+    fn parse_space(&self, mut pos: usize, newline_ok: bool) -> usize {
+        while let Some(c) = self.peek(pos) {
+            match c {
+                b' ' | b'\t' => pos += 1,
+                b'\r' | b'\n' if newline_ok => pos += 1,
+                b'#' => {
+                    while !matches!(self.peek(pos), None | Some(b'\r' | b'\n')) {
+                        pos += 1;
+                    }
+                }
+                _ => break,
+            }
+        }
+        pos
+    }
+
+    fn parse_name(&self, pos: usize) -> ParseResult<usize> {
+        let mut end = pos;
+        while self.peek(end).is_some_and(is_word_char) {
+            end += 1;
+        }
+        if end == pos {
+            return Err(self.expected("a rule name", pos));
+        }
+        Ok(end)
+    }
+
+    fn parse_int(&self, pos: usize) -> ParseResult<(u32, usize)> {
+        let mut end = pos;
+        while self.peek(end).is_some_and(|c| c.is_ascii_digit()) {
+            end += 1;
+        }
+        if end == pos {
+            return Err(self.expected("an integer", pos));
+        }
+        let value = self.src[pos..end]
+            .parse()
+            .map_err(|_| GrammarParseError::RepetitionTooLarge(self.at(pos)))?;
+        Ok((value, end))
+    }
+
+    fn parse_hex(&self, escape: usize, pos: usize, digits: usize) -> ParseResult<(u32, usize)> {
+        let mut value = 0u32;
+        let mut end = pos;
+        while end < pos + digits {
+            match self.peek(end).and_then(|c| char::from(c).to_digit(16)) {
+                Some(digit) => value = value << 4 | digit,
+                None => break,
+            }
+            end += 1;
+        }
+        if end != pos + digits {
+            return Err(GrammarParseError::InvalidHexEscape {
+                digits,
+                location: self.at(escape),
+            });
+        }
+        if char::from_u32(value).is_none() {
+            return Err(GrammarParseError::InvalidCodePoint {
+                code_point: value,
+                location: self.at(escape),
+            });
+        }
+        Ok((value, end))
+    }
+
+    fn parse_char(&self, pos: usize) -> ParseResult<(u32, usize)> {
+        match self.peek(pos) {
+            Some(b'\\') => match self.peek(pos + 1) {
+                Some(b'x') => self.parse_hex(pos, pos + 2, 2),
+                Some(b'u') => self.parse_hex(pos, pos + 2, 4),
+                Some(b'U') => self.parse_hex(pos, pos + 2, 8),
+                Some(b't') => Ok((u32::from('\t'), pos + 2)),
+                Some(b'r') => Ok((u32::from('\r'), pos + 2)),
+                Some(b'n') => Ok((u32::from('\n'), pos + 2)),
+                Some(c @ (b'\\' | b'"' | b'[' | b']')) => Ok((u32::from(c), pos + 2)),
+                Some(_) => Err(GrammarParseError::UnknownEscape(self.at(pos))),
+                None => Err(GrammarParseError::UnexpectedEndOfInput(self.at(pos + 1))),
+            },
+            Some(_) => {
+                let c = self.src[pos..]
+                    .chars()
+                    .next()
+                    .expect("pos is not at the end");
+                Ok((u32::from(c), pos + c.len_utf8()))
+            }
+            None => Err(GrammarParseError::UnexpectedEndOfInput(self.at(pos))),
+        }
+    }
+
+    fn parse_rule(&mut self, pos: usize) -> ParseResult<usize> {
+        let name_end = self.parse_name(pos)?;
+        let name = &self.src[pos..name_end];
+        let rule_id = self.state.get_symbol_id(name);
+
+        let pos = self.parse_space(name_end, false);
+        if !self.src[pos..].starts_with("::=") {
+            return Err(self.expected("`::=`", pos));
+        }
+        let pos = self.parse_space(pos + 3, true);
+        let pos = self.parse_alternates(pos, name, rule_id, false)?;
+
+        let pos = match self.peek(pos) {
+            Some(b'\r') if self.peek(pos + 1) == Some(b'\n') => pos + 2,
+            Some(b'\r' | b'\n') => pos + 1,
+            None => pos,
+            Some(_) => return Err(self.expected("a newline or the end of the grammar", pos)),
+        };
+        Ok(self.parse_space(pos, true))
+    }
+
+    fn parse_alternates(
+        &mut self,
+        pos: usize,
+        rule_name: &'a str,
+        rule_id: u32,
+        is_nested: bool,
+    ) -> ParseResult<usize> {
+        let mut rule = Vec::new();
+        let mut pos = self.parse_sequence(pos, rule_name, &mut rule, is_nested)?;
+        while self.peek(pos) == Some(b'|') {
+            rule.push(element(LLAMA_GRETYPE_ALT, 0));
+            pos = self.parse_space(pos + 1, true);
+            pos = self.parse_sequence(pos, rule_name, &mut rule, is_nested)?;
+        }
+        rule.push(element(LLAMA_GRETYPE_END, 0));
+        self.state.add_rule(rule_id, rule);
+        Ok(pos)
+    }
+
+    #[allow(clippy::too_many_lines)]
+    fn parse_sequence(
+        &mut self,
+        mut pos: usize,
+        rule_name: &'a str,
+        out: &mut Vec<llama_grammar_element>,
+        is_nested: bool,
+    ) -> ParseResult<usize> {
+        let mut last_sym_start = out.len();
+        while let Some(c) = self.peek(pos) {
+            match c {
+                // literal string
+                b'"' => {
+                    pos += 1;
+                    last_sym_start = out.len();
+                    while self.peek(pos) != Some(b'"') {
+                        let (value, next) = self.parse_char(pos)?;
+                        out.push(element(LLAMA_GRETYPE_CHAR, value));
+                        pos = next;
+                    }
+                    pos = self.parse_space(pos + 1, is_nested);
+                }
+                // char range(s)
+                b'[' => {
+                    pos += 1;
+                    let mut start_type = LLAMA_GRETYPE_CHAR;
+                    if self.peek(pos) == Some(b'^') {
+                        pos += 1;
+                        start_type = LLAMA_GRETYPE_CHAR_NOT;
+                    }
+                    last_sym_start = out.len();
+                    while self.peek(pos) != Some(b']') {
+                        let (value, next) = self.parse_char(pos)?;
+                        let type_ = if last_sym_start < out.len() {
+                            LLAMA_GRETYPE_CHAR_ALT
+                        } else {
+                            start_type
+                        };
+                        out.push(element(type_, value));
+                        pos = next;
+                        if self.peek(pos) == Some(b'-') && self.peek(pos + 1) != Some(b']') {
+                            let (value, next) = self.parse_char(pos + 1)?;
+                            out.push(element(LLAMA_GRETYPE_CHAR_RNG_UPPER, value));
+                            pos = next;
+                        }
+                    }
+                    pos = self.parse_space(pos + 1, is_nested);
+                }
+                // rule reference
+                c if is_word_char(c) => {
+                    let name_end = self.parse_name(pos)?;
+                    let ref_rule_id = self.state.get_symbol_id(&self.src[pos..name_end]);
+                    self.references.entry(ref_rule_id).or_insert(pos);
+                    pos = self.parse_space(name_end, is_nested);
+                    last_sym_start = out.len();
+                    out.push(element(LLAMA_GRETYPE_RULE_REF, ref_rule_id));
+                }
+                // grouping, parse nested alternates into a synthesized rule
+                b'(' => {
+                    let open = pos;
+                    pos = self.parse_space(pos + 1, true);
+                    let sub_rule_id = self.state.generate_symbol_id(rule_name);
+                    pos = self.parse_alternates(pos, rule_name, sub_rule_id, true)?;
+                    last_sym_start = out.len();
+                    out.push(element(LLAMA_GRETYPE_RULE_REF, sub_rule_id));
+                    if self.peek(pos) != Some(b')') {
+                        return Err(match self.peek(pos) {
+                            None => GrammarParseError::UnexpectedEndOfInput(self.at(open)),
+                            Some(_) => self.expected("`)`", pos),
+                        });
+                    }
+                    pos = self.parse_space(pos + 1, is_nested);
+                }
+                // any char
+                b'.' => {
+                    last_sym_start = out.len();
+                    out.push(element(LLAMA_GRETYPE_CHAR_ANY, 0));
+                    pos = self.parse_space(pos + 1, is_nested);
+                }
+                b'*' | b'+' | b'?' => {
+                    let (min_times, max_times) = match c {
+                        b'*' => (0, None),
+                        b'+' => (1, None),
+                        _ => (0, Some(1)),
+                    };
+                    self.handle_repetitions(
+                        pos,
+                        rule_name,
+                        out,
+                        last_sym_start,
+                        min_times,
+                        max_times,
+                    )?;
+                    pos = self.parse_space(pos + 1, is_nested);
+                }
+                b'{' => {
+                    let open = pos;
+                    pos = self.parse_space(pos + 1, is_nested);
+                    let (min_times, int_end) = self.parse_int(pos)?;
+                    pos = self.parse_space(int_end, is_nested);
+
+                    let max_times = match self.peek(pos) {
+                        Some(b'}') => Some(min_times),
+                        Some(b',') => {
+                            pos = self.parse_space(pos + 1, is_nested);
+                            let max_times = if self.peek(pos).is_some_and(|c| c.is_ascii_digit()) {
+                                let (max_times, int_end) = self.parse_int(pos)?;
+                                pos = self.parse_space(int_end, is_nested);
+                                Some(max_times)
+                            } else {
+                                None
+                            };
+                            if self.peek(pos) != Some(b'}') {
+                                return Err(self.expected("`}`", pos));
+                            }
+                            max_times
+                        }
+                        _ => return Err(self.expected("`,` or `}`", pos)),
+                    };
+                    if let Some(max) = max_times.filter(|&max| max < min_times) {
+                        return Err(GrammarParseError::InvalidRepetitionBounds {
+                            min: min_times,
+                            max,
+                            location: self.at(open),
+                        });
+                    }
+                    self.handle_repetitions(
+                        open,
+                        rule_name,
+                        out,
+                        last_sym_start,
+                        min_times,
+                        max_times,
+                    )?;
+                    pos = self.parse_space(pos + 1, is_nested);
+                }
+                _ => break,
+            }
+        }
+        Ok(pos)
+    }
+
+    /// Applies a repetition to the previous symbol (`last_sym_start` to the end of `out`)
+    /// according to the following rewrite rules:
+    ///
+    /// ```text
+    /// S{m,n} --> S S S (m times) S'(n-m)
+    ///            S'(n-m) ::= S S'(n-m-1) |
+    ///            (... n-m definitions of these S' rules ...)
+    ///            S'(1) ::= S |
+    /// S{m,} -->  S S S (m times) S'
+    ///            S' ::= S S' |
+    /// S*     --> S{0,}
+    /// S+     --> S{1,}
+    /// S?     --> S{0,1}
+    /// ```
+    fn handle_repetitions(
+        &mut self,
+        pos: usize,
+        rule_name: &str,
+        out: &mut Vec<llama_grammar_element>,
+        last_sym_start: usize,
+        min_times: u32,
+        max_times: Option<u32>,
+    ) -> ParseResult<()> {
+        if last_sym_start == out.len() {
+            return Err(GrammarParseError::MissingRepetitionTarget(self.at(pos)));
+        }
+
+        let prev_rule = out[last_sym_start..].to_vec();
+        if min_times == 0 {
+            out.truncate(last_sym_start);
+        } else {
+            for _ in 1..min_times {
+                out.extend_from_slice(&prev_rule);
+            }
+        }
+
+        let mut last_rec_rule_id = 0;
+        let n_opt = max_times.map_or(1, |max_times| max_times - min_times);
+        for i in 0..n_opt {
+            let mut rec_rule = prev_rule.clone();
+            let rec_rule_id = self.state.generate_symbol_id(rule_name);
+            if i > 0 || max_times.is_none() {
+                let next = if max_times.is_none() {
+                    rec_rule_id
+                } else {
+                    last_rec_rule_id
+                };
+                rec_rule.push(element(LLAMA_GRETYPE_RULE_REF, next));
+            }
+            rec_rule.push(element(LLAMA_GRETYPE_ALT, 0));
+            rec_rule.push(element(LLAMA_GRETYPE_END, 0));
+            self.state.add_rule(rec_rule_id, rec_rule);
+            last_rec_rule_id = rec_rule_id;
+        }
+        if n_opt > 0 {
+            out.push(element(LLAMA_GRETYPE_RULE_REF, last_rec_rule_id));
+        }
+        Ok(())
+    }
+}
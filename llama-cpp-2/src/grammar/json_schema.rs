@@ -0,0 +1,1108 @@
+//! Conversion of JSON schemas into GBNF grammars.
+//!
+//! This is a translation of `common/json-schema-to-grammar.cpp` in llama.cpp. The generated grammar
+//! has a `root` rule and can be passed directly to [`crate::sampling::LlamaSampler::grammar`] or
+//! [`crate::sampling::LlamaSampler::grammar_lazy`].
+//!
+//! ```
+//! # use std::str::FromStr;
+//! use llama_cpp_2::grammar::json_schema::json_schema_to_grammar;
+//! use llama_cpp_2::grammar::LlamaGrammar;
+//!
+//! let schema = serde_json::json!({
+//!     "type": "object",
+//!     "properties": {
+//!         "name": { "type": "string" },
+//!         "age": { "type": "integer", "minimum": 0 }
+//!     },
+//!     "required": ["name"]
+//! });
+//! let grammar = json_schema_to_grammar(&schema).unwrap();
+//! assert!(LlamaGrammar::from_str(&grammar).is_ok());
+//! ```
+//!
+//! Supported are `type` (including arrays of types), `properties`, `required`,
+//! `additionalProperties`, `enum`, `const`, `anyOf`, `oneOf`, `allOf` (of objects), local `$ref`s,
+//! `items`, `prefixItems`, `minItems`, `maxItems`, `minLength`, `maxLength`, integer bounds and the
+//! `date`, `time`, `date-time` and `uuid` string formats and `pattern`s, see [`super::regex`].
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt::Write;
+
+use serde_json::Value;
+
+use super::regex::{regex_to_rule, RegexError};
+use super::{format_literal, format_range_char};
+
+/// An error converting a JSON schema into a grammar.
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum JsonSchemaError {
+    /// The schema is malformed, for example a keyword has a value of the wrong type.
+    #[error("invalid schema: {0}")]
+    InvalidSchema(String),
+    /// A `$ref` points to a location that does not exist in the schema.
+    #[error("unresolved reference `{0}`")]
+    UnresolvedRef(String),
+    /// The schema uses a feature that cannot be converted.
+    #[error("unsupported schema feature: {0}")]
+    Unsupported(String),
+    /// A `pattern` could not be converted.
+    #[error("invalid pattern: {0}")]
+    Pattern(#[from] RegexError),
+}
+
+type Result<T> = std::result::Result<T, JsonSchemaError>;
+
+/// Convert a JSON schema into a GBNF grammar with a `root` rule.
+///
+/// # Errors
+///
+/// See [`JsonSchemaError`].
+pub fn json_schema_to_grammar(schema: &Value) -> Result<String> {
+    let mut converter = SchemaConverter::new(schema);
+    converter.visit(schema, "")?;
+    Ok(converter.format_grammar())
+}
+
+const SPACE_RULE: &str = r#"| " " | "\n"{1,2} [ \t]{0,20}"#;
+
+struct BuiltinRule {
+    content: &'static str,
+    deps: &'static [&'static str],
+}
+
+fn primitive_rule(name: &str) -> Option<BuiltinRule> {
+    let (content, deps): (_, &[_]) = match name {
+        "boolean" => (r#"("true" | "false") space"#, &[]),
+        "decimal-part" => ("[0-9]{1,16}", &[]),
+        "integral-part" => ("[0] | [1-9] [0-9]{0,15}", &[]),
+        "number" => (
+            r#"("-"? integral-part) ("." decimal-part)? ([eE] [-+]? integral-part)? space"#,
+            &["integral-part", "decimal-part"],
+        ),
+        "integer" => (r#"("-"? integral-part) space"#, &["integral-part"]),
+        "value" => (
+            "object | array | string | number | boolean | null",
+            &["object", "array", "string", "number", "boolean", "null"],
+        ),
+        "object" => (
+            r#""{" space ( string ":" space value ("," space string ":" space value)* )? "}" space"#,
+            &["string", "value"],
+        ),
+        "array" => (
+            r#""[" space ( value ("," space value)* )? "]" space"#,
+            &["value"],
+        ),
+        "uuid" => (
+            r#""\"" [0-9a-fA-F]{8} "-" [0-9a-fA-F]{4} "-" [0-9a-fA-F]{4} "-" [0-9a-fA-F]{4} "-" [0-9a-fA-F]{12} "\"" space"#,
+            &[],
+        ),
+        "char" => (
+            r#"[^"\\\x7F\x00-\x1F] | [\\] (["\\bfnrt] | "u" [0-9a-fA-F]{4})"#,
+            &[],
+        ),
+        "string" => (r#""\"" char* "\"" space"#, &["char"]),
+        "null" => (r#""null" space"#, &[]),
+        _ => return None,
+    };
+    Some(BuiltinRule { content, deps })
+}
+
+fn string_format_rule(name: &str) -> Option<BuiltinRule> {
+    let (content, deps): (_, &[_]) = match name {
+        "date" => (
+            r#"[0-9]{4} "-" ( "0" [1-9] | "1" [0-2] ) "-" ( "0" [1-9] | [1-2] [0-9] | "3" [0-1] )"#,
+            &[],
+        ),
+        "time" => (
+            r#"([01] [0-9] | "2" [0-3]) ":" [0-5] [0-9] ":" [0-5] [0-9] ( "." [0-9]{3} )? ( "Z" | ( "+" | "-" ) ( [01] [0-9] | "2" [0-3] ) ":" [0-5] [0-9] )"#,
+            &[],
+        ),
+        "date-time" => (r#"date "T" time"#, &["date", "time"]),
+        "date-string" => (r#""\"" date "\"" space"#, &["date"]),
+        "time-string" => (r#""\"" time "\"" space"#, &["time"]),
+        "date-time-string" => (r#""\"" date-time "\"" space"#, &["date-time"]),
+        _ => return None,
+    };
+    Some(BuiltinRule { content, deps })
+}
+
+fn is_reserved_name(name: &str) -> bool {
+    name == "root" || primitive_rule(name).is_some() || string_format_rule(name).is_some()
+}
+
+/// Replace every run of characters that are not allowed in rule names with a `-`.
+fn escape_rule_name(name: &str) -> String {
+    let mut escaped = String::with_capacity(name.len());
+    let mut in_invalid_run = false;
+    for c in name.chars() {
+        if c.is_ascii_alphanumeric() || c == '-' {
+            escaped.push(c);
+            in_invalid_run = false;
+        } else if !in_invalid_run {
+            escaped.push('-');
+            in_invalid_run = true;
+        }
+    }
+    escaped
+}
+
+fn build_repetition(
+    item_rule: &str,
+    min_items: u64,
+    max_items: Option<u64>,
+    separator_rule: &str,
+) -> String {
+    if max_items == Some(0) {
+        return String::new();
+    }
+    if min_items == 0 && max_items == Some(1) {
+        return format!("{item_rule}?");
+    }
+
+    if separator_rule.is_empty() {
+        return match (min_items, max_items) {
+            (1, None) => format!("{item_rule}+"),
+            (0, None) => format!("{item_rule}*"),
+            (min_items, max_items) => format!(
+                "{item_rule}{{{min_items},{}}}",
+                max_items.map_or_else(String::new, |max_items| max_items.to_string())
+            ),
+        };
+    }
+
+    let result = format!(
+        "{item_rule} {}",
+        build_repetition(
+            &format!("({separator_rule} {item_rule})"),
+            min_items.saturating_sub(1),
+            max_items.map(|max_items| max_items - 1),
+            "",
+        )
+    );
+    if min_items == 0 {
+        format!("({result})?")
+    } else {
+        result
+    }
+}
+
+fn digit_range(from: u8, to: u8, out: &mut String) {
+    out.push('[');
+    out.push(char::from(from));
+    if from != to {
+        out.push('-');
+        out.push(char::from(to));
+    }
+    out.push(']');
+}
+
+fn more_digits(min_digits: usize, max_digits: usize, out: &mut String) {
+    out.push_str("[0-9]");
+    if min_digits == max_digits && min_digits == 1 {
+        return;
+    }
+    out.push('{');
+    out.push_str(&min_digits.to_string());
+    if max_digits != min_digits {
+        out.push(',');
+        out.push_str(&max_digits.to_string());
+    }
+    out.push('}');
+}
+
+/// Match every number between `from` and `to`, two decimal strings of the same length.
+fn uniform_range(from: &[u8], to: &[u8], out: &mut String) {
+    let mut i = 0;
+    while i < from.len() && i < to.len() && from[i] == to[i] {
+        i += 1;
+    }
+    if i > 0 {
+        out.push('"');
+        out.extend(from[..i].iter().map(|&c| char::from(c)));
+        out.push('"');
+    }
+    if i < from.len() && i < to.len() {
+        if i > 0 {
+            out.push(' ');
+        }
+        let sub_len = from.len() - i - 1;
+        if sub_len > 0 {
+            let from_sub = &from[i + 1..];
+            let to_sub = &to[i + 1..];
+            let sub_zeros = "0".repeat(sub_len);
+            let sub_nines = "9".repeat(sub_len);
+
+            let mut to_reached = false;
+            out.push('(');
+            if from_sub == sub_zeros.as_bytes() {
+                digit_range(from[i], to[i] - 1, out);
+                out.push(' ');
+                more_digits(sub_len, sub_len, out);
+            } else {
+                out.push('[');
+                out.push(char::from(from[i]));
+                out.push_str("] (");
+                uniform_range(from_sub, sub_nines.as_bytes(), out);
+                out.push(')');
+                if from[i] < to[i] - 1 {
+                    out.push_str(" | ");
+                    if to_sub == sub_nines.as_bytes() {
+                        digit_range(from[i] + 1, to[i], out);
+                        to_reached = true;
+                    } else {
+                        digit_range(from[i] + 1, to[i] - 1, out);
+                    }
+                    out.push(' ');
+                    more_digits(sub_len, sub_len, out);
+                }
+            }
+            if !to_reached {
+                out.push_str(" | ");
+                digit_range(to[i], to[i], out);
+                out.push(' ');
+                uniform_range(sub_zeros.as_bytes(), to_sub, out);
+            }
+            out.push(')');
+        } else {
+            digit_range(from[i], to[i], out);
+        }
+    }
+}
+
+/// Build the alternatives matching every integer in `min_value..=max_value`, at least one of the
+/// bounds must be set.
+#[allow(clippy::too_many_lines)]
+fn build_min_max_int(
+    min_value: Option<i64>,
+    max_value: Option<i64>,
+    out: &mut String,
+    decimals_left: usize,
+    top_level: bool,
+) {
+    let less_decimals = decimals_left.saturating_sub(1).max(1);
+
+    match (min_value, max_value) {
+        (Some(min_value), Some(max_value)) => {
+            if min_value < 0 && max_value < 0 {
+                out.push_str("\"-\" (");
+                build_min_max_int(
+                    Some(max_value.saturating_neg()),
+                    Some(min_value.saturating_neg()),
+                    out,
+                    decimals_left,
+                    true,
+                );
+                out.push(')');
+                return;
+            }
+
+            let mut min_value = min_value;
+            if min_value < 0 {
+                out.push_str("\"-\" (");
+                build_min_max_int(
+                    Some(0),
+                    Some(min_value.saturating_neg()),
+                    out,
+                    decimals_left,
+                    true,
+                );
+                out.push_str(") | ");
+                min_value = 0;
+            }
+
+            let mut min_s = min_value.to_string();
+            let max_s = max_value.to_string();
+            for digits in min_s.len()..max_s.len() {
+                uniform_range(min_s.as_bytes(), "9".repeat(digits).as_bytes(), out);
+                min_s = format!("1{}", "0".repeat(digits));
+                out.push_str(" | ");
+            }
+            uniform_range(min_s.as_bytes(), max_s.as_bytes(), out);
+        }
+        (Some(min_value), None) => {
+            if min_value < 0 {
+                out.push_str("\"-\" (");
+                build_min_max_int(
+                    None,
+                    Some(min_value.saturating_neg()),
+                    out,
+                    decimals_left,
+                    false,
+                );
+                out.push_str(") | [0] | [1-9] ");
+                more_digits(0, decimals_left.saturating_sub(1), out);
+            } else if min_value == 0 {
+                if top_level {
+                    out.push_str("[0] | [1-9] ");
+                    more_digits(0, less_decimals, out);
+                } else {
+                    more_digits(1, decimals_left, out);
+                }
+            } else if min_value <= 9 {
+                let c = b'0' + u8::try_from(min_value).expect("min_value is a single digit");
+                let range_start = if top_level { b'1' } else { b'0' };
+                if c > range_start {
+                    digit_range(range_start, c - 1, out);
+                    out.push(' ');
+                    more_digits(1, less_decimals, out);
+                    out.push_str(" | ");
+                }
+                digit_range(c, b'9', out);
+                out.push(' ');
+                more_digits(0, less_decimals, out);
+            } else {
+                let min_s = min_value.to_string();
+                let len = min_s.len();
+                let c = min_s.as_bytes()[0];
+
+                if c > b'1' {
+                    digit_range(if top_level { b'1' } else { b'0' }, c - 1, out);
+                    out.push(' ');
+                    more_digits(len, less_decimals, out);
+                    out.push_str(" | ");
+                }
+                digit_range(c, c, out);
+                out.push_str(" (");
+                let rest = min_s[1..].parse().expect("min_s is a decimal number");
+                build_min_max_int(Some(rest), None, out, less_decimals, false);
+                out.push(')');
+                if c < b'9' {
+                    out.push_str(" | ");
+                    digit_range(c + 1, b'9', out);
+                    out.push(' ');
+                    more_digits(len - 1, less_decimals, out);
+                }
+            }
+        }
+        (None, Some(max_value)) => {
+            if max_value >= 0 {
+                if top_level {
+                    out.push_str("\"-\" [1-9] ");
+                    more_digits(0, less_decimals, out);
+                    out.push_str(" | ");
+                }
+                build_min_max_int(Some(0), Some(max_value), out, decimals_left, true);
+            } else {
+                out.push_str("\"-\" (");
+                build_min_max_int(
+                    Some(max_value.saturating_neg()),
+                    None,
+                    out,
+                    decimals_left,
+                    false,
+                );
+                out.push(')');
+            }
+        }
+        (None, None) => unreachable!("at least one of min_value or max_value must be set"),
+    }
+}
+
+#[derive(Default)]
+struct TrieNode {
+    children: BTreeMap<char, TrieNode>,
+    is_end_of_string: bool,
+}
+
+impl TrieNode {
+    fn insert(&mut self, string: &str) {
+        let node = string
+            .chars()
+            .fold(self, |node, c| node.children.entry(c).or_default());
+        node.is_end_of_string = true;
+    }
+}
+
+struct SchemaConverter<'a> {
+    root: &'a Value,
+    rules: BTreeMap<String, String>,
+    refs_being_resolved: HashSet<String>,
+}
+
+impl<'a> SchemaConverter<'a> {
+    fn new(root: &'a Value) -> Self {
+        Self {
+            root,
+            rules: BTreeMap::from([("space".to_string(), SPACE_RULE.to_string())]),
+            refs_being_resolved: HashSet::new(),
+        }
+    }
+
+    fn format_grammar(&self) -> String {
+        let mut grammar = String::new();
+        for (name, rule) in &self.rules {
+            writeln!(grammar, "{name} ::= {rule}").expect("writing to a string cannot fail");
+        }
+        grammar
+    }
+
+    fn add_rule(&mut self, name: &str, rule: String) -> String {
+        let esc_name = escape_rule_name(name);
+        if self
+            .rules
+            .get(&esc_name)
+            .is_none_or(|existing| *existing == rule)
+        {
+            self.rules.insert(esc_name.clone(), rule);
+            return esc_name;
+        }
+        let mut i = 0;
+        let key = loop {
+            let key = format!("{esc_name}{i}");
+            if self
+                .rules
+                .get(&key)
+                .is_none_or(|existing| *existing == rule)
+            {
+                break key;
+            }
+            i += 1;
+        };
+        self.rules.insert(key.clone(), rule);
+        key
+    }
+
+    fn add_primitive(&mut self, name: &str, rule: &BuiltinRule) -> String {
+        let n = self.add_rule(name, rule.content.to_string());
+        for dep in rule.deps {
+            let dep_rule = primitive_rule(dep)
+                .or_else(|| string_format_rule(dep))
+                .expect("dependencies of builtin rules are builtin rules");
+            if !self.rules.contains_key(*dep) {
+                self.add_primitive(dep, &dep_rule);
+            }
+        }
+        n
+    }
+
+    fn generate_union_rule(&mut self, name: &str, alt_schemas: &[Value]) -> Result<String> {
+        let rules = alt_schemas
+            .iter()
+            .enumerate()
+            .map(|(i, schema)| {
+                let sep = if name.is_empty() { "alternative-" } else { "-" };
+                self.visit(schema, &format!("{name}{sep}{i}"))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Ok(rules.join(" | "))
+    }
+
+    /// A rule matching any JSON string except the given ones.
+    fn not_strings(&mut self, strings: &[&str]) -> String {
+        fn visit(node: &TrieNode, char_rule: &str, out: &mut String) {
+            let mut rejects = String::new();
+            let mut first = true;
+            for (&c, child) in &node.children {
+                rejects.push_str(&format_range_char(c));
+                if first {
+                    first = false;
+                } else {
+                    out.push_str(" | ");
+                }
+                out.push('[');
+                out.push_str(&format_range_char(c));
+                out.push(']');
+                if !child.children.is_empty() {
+                    out.push_str(" (");
+                    visit(child, char_rule, out);
+                    out.push(')');
+                } else if child.is_end_of_string {
+                    out.push(' ');
+                    out.push_str(char_rule);
+                    out.push('+');
+                }
+            }
+            if !node.children.is_empty() {
+                if !first {
+                    out.push_str(" | ");
+                }
+                out.push_str("[^\"");
+                out.push_str(&rejects);
+                out.push_str("] ");
+                out.push_str(char_rule);
+                out.push('*');
+            }
+        }
+
+        let mut trie = TrieNode::default();
+        for string in strings {
+            trie.insert(string);
+        }
+
+        let char_rule = self.add_primitive("char", &primitive_rule("char").expect("builtin"));
+        let mut out = String::from("[\"] ( ");
+        visit(&trie, &char_rule, &mut out);
+        out.push_str(" )");
+        if !trie.is_end_of_string {
+            out.push('?');
+        }
+        out.push_str(" [\"] space");
+        out
+    }
+
+    fn lookup_ref(&self, reference: &str) -> Result<&'a Value> {
+        let Some(pointer) = reference.strip_prefix('#') else {
+            return Err(JsonSchemaError::Unsupported(format!(
+                "remote reference `{reference}`"
+            )));
+        };
+        self.root
+            .pointer(pointer)
+            .ok_or_else(|| JsonSchemaError::UnresolvedRef(reference.to_string()))
+    }
+
+    fn resolve_ref(&mut self, reference: &str) -> Result<String> {
+        let ref_name = escape_rule_name(reference.rsplit('/').next().unwrap_or(reference));
+        if !self.rules.contains_key(&ref_name) && !self.refs_being_resolved.contains(reference) {
+            let resolved = self.lookup_ref(reference)?;
+            self.refs_being_resolved.insert(reference.to_string());
+            let name = self.visit(resolved, &ref_name)?;
+            self.refs_being_resolved.remove(reference);
+            return Ok(name);
+        }
+        Ok(ref_name)
+    }
+
+    fn build_object_rule(
+        &mut self,
+        properties: &[(&str, &Value)],
+        required: &HashSet<&str>,
+        name: &str,
+        additional_properties: Option<&Value>,
+    ) -> Result<String> {
+        let prefix = if name.is_empty() {
+            String::new()
+        } else {
+            format!("{name}-")
+        };
+        let mut required_props = Vec::new();
+        let mut optional_props = Vec::new();
+        let mut prop_kv_rule_names = HashMap::new();
+        for &(prop_name, prop_schema) in properties {
+            let prop_rule_name = self.visit(prop_schema, &format!("{prefix}{prop_name}"))?;
+            let kv_rule_name = self.add_rule(
+                &format!("{prefix}{prop_name}-kv"),
+                format!(
+                    "{} space \":\" space {prop_rule_name}",
+                    format_literal(&Value::from(prop_name).to_string())
+                ),
+            );
+            prop_kv_rule_names.insert(prop_name, kv_rule_name);
+            if required.contains(prop_name) {
+                required_props.push(prop_name);
+            } else {
+                optional_props.push(prop_name);
+            }
+        }
+        if let Some(additional @ (Value::Bool(true) | Value::Object(_))) = additional_properties {
+            let sub_name = format!("{prefix}additional");
+            let value_rule = if additional.is_object() {
+                self.visit(additional, &format!("{sub_name}-value"))?
+            } else {
+                self.add_primitive("value", &primitive_rule("value").expect("builtin"))
+            };
+            let key_rule = if properties.is_empty() {
+                self.add_primitive("string", &primitive_rule("string").expect("builtin"))
+            } else {
+                let prop_names = properties.iter().map(|(n, _)| *n).collect::<Vec<_>>();
+                let not_strings = self.not_strings(&prop_names);
+                self.add_rule(&format!("{sub_name}-k"), not_strings)
+            };
+            let kv_rule = self.add_rule(
+                &format!("{sub_name}-kv"),
+                format!("{key_rule} \":\" space {value_rule}"),
+            );
+            prop_kv_rule_names.insert("*", kv_rule);
+            optional_props.push("*");
+        }
+
+        let mut rule = String::from("\"{\" space ");
+        rule.push_str(
+            &required_props
+                .iter()
+                .map(|prop| prop_kv_rule_names[prop].as_str())
+                .collect::<Vec<_>>()
+                .join(" \",\" space "),
+        );
+
+        if !optional_props.is_empty() {
+            rule.push_str(" (");
+            if !required_props.is_empty() {
+                rule.push_str(" \",\" space ( ");
+            }
+            let alternatives = (0..optional_props.len())
+                .map(|i| {
+                    self.optional_props_rule(
+                        &prefix,
+                        &optional_props[i..],
+                        &prop_kv_rule_names,
+                        false,
+                    )
+                })
+                .collect::<Vec<_>>();
+            rule.push_str(&alternatives.join(" | "));
+            if !required_props.is_empty() {
+                rule.push_str(" )");
+            }
+            rule.push_str(" )?");
+        }
+
+        rule.push_str(" \"}\" space");
+        Ok(rule)
+    }
+
+    /// The rule for the optional properties `keys`, which may appear in order with any of them
+    /// left out.
+    fn optional_props_rule(
+        &mut self,
+        prefix: &str,
+        keys: &[&str],
+        prop_kv_rule_names: &HashMap<&str, String>,
+        first_is_optional: bool,
+    ) -> String {
+        let Some((&k, rest)) = keys.split_first() else {
+            return String::new();
+        };
+        let kv_rule_name = &prop_kv_rule_names[k];
+        let comma_ref = format!("( \",\" space {kv_rule_name} )");
+        let mut res = if first_is_optional {
+            format!("{comma_ref}{}", if k == "*" { "*" } else { "?" })
+        } else if k == "*" {
+            format!("{kv_rule_name} {comma_ref}*")
+        } else {
+            kv_rule_name.clone()
+        };
+        if !rest.is_empty() {
+            let rest_rule = self.optional_props_rule(prefix, rest, prop_kv_rule_names, true);
+            res.push(' ');
+            res.push_str(&self.add_rule(&format!("{prefix}{k}-rest"), rest_rule));
+        }
+        res
+    }
+
+    #[allow(clippy::too_many_lines)]
+    fn visit(&mut self, schema: &Value, name: &str) -> Result<String> {
+        let Some(object) = schema.as_object() else {
+            return Err(JsonSchemaError::InvalidSchema(format!(
+                "expected an object, found `{schema}`"
+            )));
+        };
+        let schema_type = object.get("type").filter(|t| !t.is_null());
+        let type_name = schema_type.and_then(Value::as_str);
+        let untyped_or = |t: &str| schema_type.is_none() || type_name == Some(t);
+        let schema_format = object.get("format").and_then(Value::as_str).unwrap_or("");
+        let rule_name = if is_reserved_name(name) {
+            format!("{name}-")
+        } else if name.is_empty() {
+            "root".to_string()
+        } else {
+            name.to_string()
+        };
+
+        if let Some(reference) = object.get("$ref") {
+            let reference = reference
+                .as_str()
+                .ok_or_else(|| invalid("`$ref` must be a string"))?;
+            let resolved = self.resolve_ref(reference)?;
+            Ok(self.add_rule(&rule_name, resolved))
+        } else if let Some(alt_schemas) = object.get("oneOf").or_else(|| object.get("anyOf")) {
+            let alt_schemas = alt_schemas
+                .as_array()
+                .ok_or_else(|| invalid("`oneOf` and `anyOf` must be arrays"))?;
+            let rule = self.generate_union_rule(name, alt_schemas)?;
+            Ok(self.add_rule(&rule_name, rule))
+        } else if let Some(Value::Array(types)) = schema_type {
+            let schema_types = types
+                .iter()
+                .map(|t| {
+                    let mut schema = object.clone();
+                    schema.insert("type".to_string(), t.clone());
+                    Value::Object(schema)
+                })
+                .collect::<Vec<_>>();
+            let rule = self.generate_union_rule(name, &schema_types)?;
+            Ok(self.add_rule(&rule_name, rule))
+        } else if let Some(value) = object.get("const") {
+            Ok(self.add_rule(
+                &rule_name,
+                format!("{} space", format_literal(&value.to_string())),
+            ))
+        } else if let Some(values) = object.get("enum") {
+            let values = values
+                .as_array()
+                .ok_or_else(|| invalid("`enum` must be an array"))?
+                .iter()
+                .map(|value| format_literal(&value.to_string()))
+                .collect::<Vec<_>>();
+            Ok(self.add_rule(&rule_name, format!("({}) space", values.join(" | "))))
+        } else if untyped_or("object")
+            && (object.contains_key("properties")
+                || object
+                    .get("additionalProperties")
+                    .is_some_and(|additional| *additional != Value::Bool(true)))
+        {
+            let required = object
+                .get("required")
+                .and_then(Value::as_array)
+                .into_iter()
+                .flatten()
+                .filter_map(Value::as_str)
+                .collect::<HashSet<_>>();
+            let properties = match object.get("properties") {
+                None => Vec::new(),
+                Some(Value::Object(properties)) => properties
+                    .iter()
+                    .map(|(name, schema)| (name.as_str(), schema))
+                    .collect(),
+                Some(_) => return Err(invalid("`properties` must be an object")),
+            };
+            let rule = self.build_object_rule(
+                &properties,
+                &required,
+                name,
+                object.get("additionalProperties"),
+            )?;
+            Ok(self.add_rule(&rule_name, rule))
+        } else if untyped_or("object") && object.contains_key("allOf") {
+            let components = object
+                .get("allOf")
+                .and_then(Value::as_array)
+                .ok_or_else(|| invalid("`allOf` must be an array"))?;
+            let mut required = HashSet::new();
+            let mut properties = Vec::new();
+            for component in components {
+                if let Some(alternatives) = component.get("anyOf").and_then(Value::as_array) {
+                    for alternative in alternatives {
+                        self.add_all_of_component(
+                            alternative,
+                            false,
+                            &mut properties,
+                            &mut required,
+                        )?;
+                    }
+                } else {
+                    self.add_all_of_component(component, true, &mut properties, &mut required)?;
+                }
+            }
+            let properties = properties
+                .iter()
+                .map(|(name, schema)| (name.as_str(), schema))
+                .collect::<Vec<_>>();
+            let required = required.iter().map(String::as_str).collect();
+            let rule = self.build_object_rule(&properties, &required, name, None)?;
+            Ok(self.add_rule(&rule_name, rule))
+        } else if untyped_or("array")
+            && (object.contains_key("items") || object.contains_key("prefixItems"))
+        {
+            let items = object
+                .get("items")
+                .or_else(|| object.get("prefixItems"))
+                .expect("checked above");
+            let prefix = if name.is_empty() {
+                String::new()
+            } else {
+                format!("{name}-")
+            };
+            if let Value::Array(items) = items {
+                let items = items
+                    .iter()
+                    .enumerate()
+                    .map(|(i, item)| self.visit(item, &format!("{prefix}tuple-{i}")))
+                    .collect::<Result<Vec<_>>>()?;
+                let rule = format!("\"[\" space {} \"]\" space", items.join(" \",\" space "));
+                Ok(self.add_rule(&rule_name, rule))
+            } else {
+                let item_rule_name = self.visit(items, &format!("{prefix}item"))?;
+                let min_items = get_u64(object, "minItems")?.unwrap_or(0);
+                let max_items = get_u64(object, "maxItems")?;
+                check_bounds(min_items, max_items, "minItems", "maxItems")?;
+                let rule = format!(
+                    "\"[\" space {} \"]\" space",
+                    build_repetition(&item_rule_name, min_items, max_items, "\",\" space")
+                );
+                Ok(self.add_rule(&rule_name, rule))
+            }
+        } else if let Some(pattern) = object.get("pattern").filter(|_| untyped_or("string")) {
+            let pattern = pattern
+                .as_str()
+                .ok_or_else(|| invalid("`pattern` must be a string"))?;
+            let rule = format!(r#""\"" ({}) "\"" space"#, regex_to_rule(pattern, true)?);
+            Ok(self.add_rule(&rule_name, rule))
+        } else if untyped_or("string") && is_uuid_format(schema_format) {
+            let name = if rule_name == "root" {
+                "root"
+            } else {
+                schema_format
+            };
+            Ok(self.add_primitive(name, &primitive_rule("uuid").expect("builtin")))
+        } else if let Some(rule) = untyped_or("string")
+            .then(|| string_format_rule(&format!("{schema_format}-string")))
+            .flatten()
+        {
+            let primitive = self.add_primitive(&format!("{schema_format}-string"), &rule);
+            Ok(self.add_rule(&rule_name, primitive))
+        } else if type_name == Some("string")
+            && (object.contains_key("minLength") || object.contains_key("maxLength"))
+        {
+            let char_rule = self.add_primitive("char", &primitive_rule("char").expect("builtin"));
+            let min_len = get_u64(object, "minLength")?.unwrap_or(0);
+            let max_len = get_u64(object, "maxLength")?;
+            check_bounds(min_len, max_len, "minLength", "maxLength")?;
+            let rule = format!(
+                "\"\\\"\" {} \"\\\"\" space",
+                build_repetition(&char_rule, min_len, max_len, "")
+            );
+            Ok(self.add_rule(&rule_name, rule))
+        } else if type_name == Some("integer")
+            && ["minimum", "exclusiveMinimum", "maximum", "exclusiveMaximum"]
+                .iter()
+                .any(|key| object.contains_key(*key))
+        {
+            let min_value = match get_i64(object, "minimum")? {
+                Some(minimum) => Some(minimum),
+                None => get_i64(object, "exclusiveMinimum")?.map(|min| min.saturating_add(1)),
+            };
+            let max_value = match get_i64(object, "maximum")? {
+                Some(maximum) => Some(maximum),
+                None => get_i64(object, "exclusiveMaximum")?.map(|max| max.saturating_sub(1)),
+            };
+            if let (Some(min_value), Some(max_value)) = (min_value, max_value) {
+                if min_value > max_value {
+                    return Err(invalid("the minimum is greater than the maximum"));
+                }
+            }
+            let mut rule = String::from("(");
+            build_min_max_int(min_value, max_value, &mut rule, 16, true);
+            rule.push_str(") space");
+            Ok(self.add_rule(&rule_name, rule))
+        } else if object.is_empty() || type_name == Some("object") {
+            let primitive =
+                self.add_primitive("object", &primitive_rule("object").expect("builtin"));
+            Ok(self.add_rule(&rule_name, primitive))
+        } else {
+            let Some((type_name, rule)) =
+                type_name.and_then(|t| primitive_rule(t).map(|rule| (t, rule)))
+            else {
+                return Err(invalid(&format!("unrecognized schema `{schema}`")));
+            };
+            let name = if rule_name == "root" {
+                "root"
+            } else {
+                type_name
+            };
+            Ok(self.add_primitive(name, &rule))
+        }
+    }
+
+    fn add_all_of_component(
+        &self,
+        component: &Value,
+        is_required: bool,
+        properties: &mut Vec<(String, Value)>,
+        required: &mut HashSet<String>,
+    ) -> Result<()> {
+        if let Some(reference) = component.get("$ref").and_then(Value::as_str) {
+            let resolved = self.lookup_ref(reference)?;
+            return self.add_all_of_component(resolved, is_required, properties, required);
+        }
+        if let Some(component_properties) = component.get("properties").and_then(Value::as_object) {
+            for (name, schema) in component_properties {
+                properties.push((name.clone(), schema.clone()));
+                if is_required {
+                    required.insert(name.clone());
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+fn invalid(message: &str) -> JsonSchemaError {
+    JsonSchemaError::InvalidSchema(message.to_string())
+}
+
+fn is_uuid_format(format: &str) -> bool {
+    format
+        .strip_prefix("uuid")
+        .is_some_and(|version| matches!(version, "" | "1" | "2" | "3" | "4" | "5"))
+}
+
+fn get_u64(object: &serde_json::Map<String, Value>, key: &str) -> Result<Option<u64>> {
+    object
+        .get(key)
+        .map(|value| {
+            value
+                .as_u64()
+                .ok_or_else(|| invalid(&format!("`{key}` must be a non-negative integer")))
+        })
+        .transpose()
+}
+
+fn get_i64(object: &serde_json::Map<String, Value>, key: &str) -> Result<Option<i64>> {
+    object
+        .get(key)
+        .map(|value| {
+            value
+                .as_i64()
+                .ok_or_else(|| invalid(&format!("`{key}` must be an integer")))
+        })
+        .transpose()
+}
+
+fn check_bounds(min: u64, max: Option<u64>, min_key: &str, max_key: &str) -> Result<()> {
+    match max {
+        Some(max) if max < min => Err(invalid(&format!("`{min_key}` is greater than `{max_key}`"))),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use serde_json::json;
+
+    use super::*;
+    use crate::grammar::LlamaGrammar;
+
+    fn convert(schema: &Value) -> String {
+        let grammar = json_schema_to_grammar(schema).unwrap();
+        LlamaGrammar::from_str(&grammar).unwrap();
+        grammar
+    }
+
+    #[test]
+    fn integer_minimum() {
+        assert_eq!(
+            convert(&json!({"type": "integer", "minimum": 0})),
+            "root ::= ([0] | [1-9] [0-9]{0,15}) space\n\
+             space ::= | \" \" | \"\\n\"{1,2} [ \\t]{0,20}\n"
+        );
+        assert_eq!(
+            convert(&json!({"type": "integer", "minimum": 3})),
+            "root ::= ([1-2] [0-9]{1,15} | [3-9] [0-9]{0,15}) space\n\
+             space ::= | \" \" | \"\\n\"{1,2} [ \\t]{0,20}\n"
+        );
+    }
+
+    #[test]
+    fn string_pattern() {
+        assert_eq!(
+            convert(&json!({"type": "string", "pattern": r"^\d{3}-[A-Z]+$"})),
+            "root ::= \"\\\"\" ([0-9]{3} \"-\" [A-Z]+) \"\\\"\" space\n\
+             space ::= | \" \" | \"\\n\"{1,2} [ \\t]{0,20}\n"
+        );
+        assert!(matches!(
+            json_schema_to_grammar(&json!({"type": "string", "pattern": "(a"})),
+            Err(JsonSchemaError::Pattern(RegexError::Unclosed { .. }))
+        ));
+    }
+
+    #[test]
+    fn integer_range() {
+        assert_eq!(
+            convert(&json!({"type": "integer", "minimum": -5, "maximum": 42})),
+            "root ::= (\"-\" ([0-5]) | [0-9] | ([1-3] [0-9] | [4] [0-2])) space\n\
+             space ::= | \" \" | \"\\n\"{1,2} [ \\t]{0,20}\n"
+        );
+    }
+
+    #[test]
+    fn required_and_optional_properties() {
+        let grammar = convert(&json!({
+            "type": "object",
+            "properties": {
+                "b": {"type": "string"},
+                "a": {"type": "integer"},
+                "c": {"enum": ["x", 1]}
+            },
+            "required": ["b"]
+        }));
+        assert!(grammar.contains(
+            "root ::= \"{\" space b-kv ( \",\" space ( a-kv a-rest | c-kv ) )? \"}\" space\n"
+        ));
+        assert!(grammar.contains("a-rest ::= ( \",\" space c-kv )?\n"));
+        assert!(grammar.contains("b-kv ::= \"\\\"b\\\"\" space \":\" space string\n"));
+        assert!(grammar.contains("c ::= (\"\\\"x\\\"\" | \"1\") space\n"));
+    }
+
+    #[test]
+    fn arrays() {
+        let grammar = convert(&json!({
+            "type": "array",
+            "items": {"type": "number"},
+            "minItems": 1,
+            "maxItems": 3
+        }));
+        assert!(
+            grammar.contains("root ::= \"[\" space number (\",\" space number){0,2} \"]\" space\n")
+        );
+        let grammar = convert(&json!({"prefixItems": [{"type": "string"}, {"type": "boolean"}]}));
+        assert!(grammar.contains("root ::= \"[\" space string \",\" space boolean \"]\" space\n"));
+    }
+
+    #[test]
+    fn refs_and_unions() {
+        let grammar = convert(&json!({
+            "$defs": {
+                "node": {
+                    "type": "object",
+                    "properties": {
+                        "children": {"type": "array", "items": {"$ref": "#/$defs/node"}},
+                        "when": {"anyOf": [{"type": "string", "format": "date"}, {"type": "null"}]}
+                    },
+                    "required": ["children"]
+                }
+            },
+            "$ref": "#/$defs/node"
+        }));
+        assert!(grammar.contains("root ::= node\n"));
+        assert!(grammar.contains("node-children-item ::= node\n"));
+        assert!(grammar.contains("node-when ::= node-when-0 | null\n"));
+        assert!(grammar.contains("node-when-0 ::= date-string\n"));
+    }
+
+    #[test]
+    fn additional_properties() {
+        convert(&json!({
+            "type": "object",
+            "properties": {"a": {"type": "string", "maxLength": 4}},
+            "additionalProperties": {"type": "integer"}
+        }));
+        convert(&json!({"type": "object", "additionalProperties": true}));
+    }
+
+    #[test]
+    fn escaping() {
+        // excluding the known keys from other keys puts their characters into classes
+        let grammar = convert(&json!({
+            "type": "object",
+            "properties": {"first-name": {"type": "string"}, "^x": {"type": "integer"}},
+            "additionalProperties": true
+        }));
+        assert!(grammar.contains(r"[\x2D] ([n]"));
+        assert!(grammar.contains(r#"[^"\x5Ef] char*"#));
+        assert_eq!(format_literal("a\tb\u{1}"), r#""a\tb\x01""#);
+        assert_eq!(format_range_char('\t'), r"\t");
+        assert_eq!(format_range_char('['), r"\[");
+    }
+
+    #[test]
+    fn errors() {
+        assert_eq!(
+            json_schema_to_grammar(&json!({"$ref": "#/definitions/missing"})),
+            Err(JsonSchemaError::UnresolvedRef(
+                "#/definitions/missing".to_string()
+            ))
+        );
+        assert!(matches!(
+            json_schema_to_grammar(&json!({"$ref": "https://example.com/schema.json"})),
+            Err(JsonSchemaError::Unsupported(_))
+        ));
+        assert!(matches!(
+            json_schema_to_grammar(&json!({"type": "frobnicate"})),
+            Err(JsonSchemaError::InvalidSchema(_))
+        ));
+    }
+}
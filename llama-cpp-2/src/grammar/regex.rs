@@ -0,0 +1,778 @@
+//! Conversion of regular expressions into GBNF grammars.
+//!
+//! The generated grammar matches exactly the strings that the whole expression matches, so it can
+//! be used to constrain generated text with [`crate::sampling::LlamaSampler::regex`]. The `pattern`
+//! keyword of JSON schemas is converted the same way.
+//!
+//! ```
+//! # use std::str::FromStr;
+//! use llama_cpp_2::grammar::regex::regex_to_grammar;
+//! use llama_cpp_2::grammar::LlamaGrammar;
+//!
+//! let grammar = regex_to_grammar(r"\d{3}-\d{4}").unwrap();
+//! assert_eq!(grammar, "root ::= [0-9]{3} \"-\" [0-9]{4}\n");
+//! assert!(LlamaGrammar::from_str(&grammar).is_ok());
+//! ```
+//!
+//! Supported are literals, `.`, character classes, the escapes `\d`, `\w`, `\s` (and their
+//! negations), `\n`, `\r`, `\t`, `\f`, `\v`, `\0`, `\xHH`, `\uHHHH`, `\x{...}` and `\u{...}`,
+//! groups (capturing, non-capturing and named), alternation, the quantifiers `*`, `+`, `?`, `{n}`,
+//! `{n,}` and `{n,m}` (lazy quantifiers are treated as greedy ones) and `^` and `$` at the ends of
+//! the expression or of its top-level alternatives, like `^yes$|^no$`. Other anchors, word
+//! boundaries, backreferences, lookaround and inline flags are rejected with
+//! [`RegexError::Unsupported`].
+
+use super::{format_literal, format_range_char};
+
+/// An error converting a regular expression into a grammar.
+///
+/// Offsets are counted in characters from the start of the expression.
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum RegexError {
+    /// A group or character class is not closed.
+    #[error("unclosed `{delimiter}` at offset {offset}")]
+    Unclosed {
+        /// The opening delimiter.
+        delimiter: char,
+        /// The offset of the opening delimiter.
+        offset: usize,
+    },
+    /// A `)` without a matching `(`.
+    #[error("unmatched `)` at offset {0}")]
+    UnmatchedParenthesis(usize),
+    /// A quantifier follows nothing or another quantifier.
+    #[error("nothing to repeat at offset {0}")]
+    NothingToRepeat(usize),
+    /// A `{n,m}` quantifier is malformed or `m` is smaller than `n`.
+    #[error("invalid repetition at offset {0}")]
+    InvalidRepetition(usize),
+    /// An unknown or malformed escape sequence.
+    #[error("invalid escape sequence at offset {0}")]
+    InvalidEscape(usize),
+    /// A character class range whose end is before its start.
+    #[error("invalid character class range at offset {0}")]
+    InvalidRange(usize),
+    /// The expression uses a feature that cannot be expressed as a grammar.
+    #[error("unsupported regex feature at offset {offset}: {feature}")]
+    Unsupported {
+        /// A description of the feature.
+        feature: &'static str,
+        /// The offset at which the feature is used.
+        offset: usize,
+    },
+}
+
+type Result<T> = std::result::Result<T, RegexError>;
+
+/// Convert a regular expression into a GBNF grammar with a single `root` rule.
+///
+/// # Errors
+///
+/// If the expression is malformed or uses an unsupported feature, see [`RegexError`].
+pub fn regex_to_grammar(pattern: &str) -> Result<String> {
+    Ok(format!("root ::= {}\n", regex_to_rule(pattern, false)?))
+}
+
+/// Convert a regular expression into the body of a grammar rule.
+///
+/// With `json_string` the rule matches the contents of a JSON string literal: characters that must
+/// be escaped in JSON are matched by their escape sequences in literals and classes, and excluded
+/// from `.` and negated classes.
+pub(super) fn regex_to_rule(pattern: &str, json_string: bool) -> Result<String> {
+    let node = Parser::new(pattern).parse()?;
+    Ok(Emitter { json_string }.rule(&node))
+}
+
+const DIGIT: &[(char, char)] = &[('0', '9')];
+const WORD: &[(char, char)] = &[('0', '9'), ('A', 'Z'), ('_', '_'), ('a', 'z')];
+const SPACE: &[(char, char)] = &[('\t', '\r'), (' ', ' ')];
+
+/// The ranges of the `\d`, `\w` and `\s` escapes and whether they are negated.
+fn class_escape(c: char) -> Option<(bool, &'static [(char, char)])> {
+    match c {
+        'd' => Some((false, DIGIT)),
+        'D' => Some((true, DIGIT)),
+        'w' => Some((false, WORD)),
+        'W' => Some((true, WORD)),
+        's' => Some((false, SPACE)),
+        'S' => Some((true, SPACE)),
+        _ => None,
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Node {
+    Char(char),
+    Any,
+    Class {
+        negated: bool,
+        ranges: Vec<(char, char)>,
+    },
+    Concat(Vec<Node>),
+    Alternation(Vec<Node>),
+    Repeat {
+        node: Box<Node>,
+        min: u32,
+        max: Option<u32>,
+    },
+}
+
+impl Node {
+    /// Whether the node matches the empty string.
+    fn is_nullable(&self) -> bool {
+        match self {
+            Node::Char(_) | Node::Any | Node::Class { .. } => false,
+            Node::Concat(items) => items.iter().all(Node::is_nullable),
+            Node::Alternation(alternatives) => alternatives.iter().any(Node::is_nullable),
+            Node::Repeat { node, min, .. } => *min == 0 || node.is_nullable(),
+        }
+    }
+
+    /// A node whose repetitions match the same strings as the repetitions of this one, but which
+    /// does not match the empty string itself. `None` if only the empty string is matched.
+    fn non_empty(self) -> Option<Node> {
+        if !self.is_nullable() {
+            return Some(self);
+        }
+        let parts = match self {
+            Node::Repeat { max: Some(0), .. } => return None,
+            Node::Repeat { node, .. } => return node.non_empty(),
+            // the items of a nullable sequence are nullable, so repeating the sequence is the
+            // same as repeating any of its items
+            Node::Concat(parts) | Node::Alternation(parts) => parts,
+            Node::Char(_) | Node::Any | Node::Class { .. } => unreachable!("not nullable"),
+        };
+        let mut alternatives: Vec<_> = parts.into_iter().filter_map(Node::non_empty).collect();
+        match alternatives.len() {
+            0 => None,
+            1 => alternatives.pop(),
+            _ => Some(Node::Alternation(alternatives)),
+        }
+    }
+}
+
+struct Parser {
+    chars: Vec<char>,
+    pos: usize,
+    /// The number of groups the parser is in.
+    depth: usize,
+}
+
+impl Parser {
+    fn new(pattern: &str) -> Self {
+        Self {
+            chars: pattern.chars().collect(),
+            pos: 0,
+            depth: 0,
+        }
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).copied()
+    }
+
+    fn peek_at(&self, n: usize) -> Option<char> {
+        self.chars.get(self.pos + n).copied()
+    }
+
+    fn bump(&mut self) -> Option<char> {
+        let c = self.peek()?;
+        self.pos += 1;
+        Some(c)
+    }
+
+    fn eat(&mut self, c: char) -> bool {
+        let found = self.peek() == Some(c);
+        if found {
+            self.pos += 1;
+        }
+        found
+    }
+
+    fn parse(mut self) -> Result<Node> {
+        let node = self.alternation()?;
+        match self.peek() {
+            Some(_) => Err(RegexError::UnmatchedParenthesis(self.pos)),
+            None => Ok(node),
+        }
+    }
+
+    fn alternation(&mut self) -> Result<Node> {
+        let mut alternatives = vec![self.concat()?];
+        while self.eat('|') {
+            alternatives.push(self.concat()?);
+        }
+        Ok(if alternatives.len() == 1 {
+            alternatives.pop().expect("one alternative")
+        } else {
+            Node::Alternation(alternatives)
+        })
+    }
+
+    fn concat(&mut self) -> Result<Node> {
+        let mut items = Vec::new();
+        while let Some(c) = self.peek() {
+            match c {
+                '|' | ')' => break,
+                // the whole expression is matched, so anchors around alternatives are no-ops
+                '^' if self.depth == 0 && items.is_empty() => self.pos += 1,
+                '$' if self.depth == 0 && matches!(self.peek_at(1), None | Some('|')) => {
+                    self.pos += 1;
+                }
+                _ => {
+                    let atom = self.atom()?;
+                    items.push(self.quantifier(atom)?);
+                }
+            }
+        }
+        Ok(if items.len() == 1 {
+            items.pop().expect("one item")
+        } else {
+            Node::Concat(items)
+        })
+    }
+
+    fn quantifier(&mut self, atom: Node) -> Result<Node> {
+        let offset = self.pos;
+        let (min, max) = match self.peek() {
+            Some('*') => (0, None),
+            Some('+') => (1, None),
+            Some('?') => (0, Some(1)),
+            Some('{') => self.repetition()?,
+            _ => return Ok(atom),
+        };
+        if offset == self.pos {
+            self.pos += 1;
+        }
+        self.eat('?');
+        if matches!(self.peek(), Some('*' | '+' | '?' | '{')) {
+            return Err(RegexError::NothingToRepeat(self.pos));
+        }
+        if matches!(&atom, Node::Concat(items) if items.is_empty()) {
+            // repetitions of the empty string are the empty string, and `""?` is not valid GBNF
+            return Ok(atom);
+        }
+        if max.is_none() && atom.is_nullable() {
+            // `x*` with an `x` that matches the empty string would be left recursive in the
+            // grammar, so repeat only the non-empty matches of `x` instead
+            return Ok(atom
+                .non_empty()
+                .map_or(Node::Concat(Vec::new()), |node| Node::Repeat {
+                    node: Box::new(node),
+                    min: 0,
+                    max: None,
+                }));
+        }
+        Ok(Node::Repeat {
+            node: Box::new(atom),
+            min,
+            max,
+        })
+    }
+
+    /// Parse `{n}`, `{n,}`, `{,m}` or `{n,m}`.
+    fn repetition(&mut self) -> Result<(u32, Option<u32>)> {
+        let offset = self.pos;
+        self.pos += 1;
+        let min = self.number(offset)?;
+        let max = if self.eat(',') {
+            self.number(offset)?
+        } else {
+            Some(min.ok_or(RegexError::InvalidRepetition(offset))?)
+        };
+        if !self.eat('}') {
+            return Err(RegexError::InvalidRepetition(offset));
+        }
+        let min = min.unwrap_or(0);
+        if max.is_some_and(|max| max < min) {
+            return Err(RegexError::InvalidRepetition(offset));
+        }
+        Ok((min, max))
+    }
+
+    fn number(&mut self, offset: usize) -> Result<Option<u32>> {
+        let start = self.pos;
+        while self.peek().is_some_and(|c| c.is_ascii_digit()) {
+            self.pos += 1;
+        }
+        if start == self.pos {
+            return Ok(None);
+        }
+        self.chars[start..self.pos]
+            .iter()
+            .collect::<String>()
+            .parse()
+            .map(Some)
+            .map_err(|_| RegexError::InvalidRepetition(offset))
+    }
+
+    fn atom(&mut self) -> Result<Node> {
+        let offset = self.pos;
+        match self.bump().expect("checked by caller") {
+            '(' => self.group(offset),
+            '[' => self.class(offset),
+            '.' => Ok(Node::Any),
+            '\\' => {
+                let c = self.bump().ok_or(RegexError::InvalidEscape(offset))?;
+                match class_escape(c) {
+                    Some((negated, ranges)) => Ok(Node::Class {
+                        negated,
+                        ranges: ranges.to_vec(),
+                    }),
+                    None => self.escaped_char(c, offset).map(Node::Char),
+                }
+            }
+            '*' | '+' | '?' | '{' => Err(RegexError::NothingToRepeat(offset)),
+            '^' => Err(RegexError::Unsupported {
+                feature: "`^` anywhere but at the start of an alternative",
+                offset,
+            }),
+            '$' => Err(RegexError::Unsupported {
+                feature: "`$` anywhere but at the end of an alternative",
+                offset,
+            }),
+            c => Ok(Node::Char(c)),
+        }
+    }
+
+    fn group(&mut self, offset: usize) -> Result<Node> {
+        if self.eat('?') {
+            match (self.peek(), self.peek_at(1)) {
+                (Some(':'), _) => self.pos += 1,
+                (Some('<'), Some(c)) if c != '=' && c != '!' => self.group_name()?,
+                (Some('P'), Some('<')) => {
+                    self.pos += 1;
+                    self.group_name()?;
+                }
+                _ => {
+                    return Err(RegexError::Unsupported {
+                        feature: "lookaround and inline flags",
+                        offset,
+                    })
+                }
+            }
+        }
+        self.depth += 1;
+        let node = self.alternation()?;
+        self.depth -= 1;
+        if !self.eat(')') {
+            return Err(RegexError::Unclosed {
+                delimiter: '(',
+                offset,
+            });
+        }
+        Ok(node)
+    }
+
+    /// Skip `<name>`, group names have no meaning in a grammar.
+    fn group_name(&mut self) -> Result<()> {
+        let offset = self.pos;
+        self.pos += 1;
+        while let Some(c) = self.bump() {
+            if c == '>' {
+                return Ok(());
+            }
+        }
+        Err(RegexError::Unclosed {
+            delimiter: '<',
+            offset,
+        })
+    }
+
+    fn class(&mut self, offset: usize) -> Result<Node> {
+        let unclosed = RegexError::Unclosed {
+            delimiter: '[',
+            offset,
+        };
+        let negated = self.eat('^');
+        let mut ranges = Vec::new();
+        let mut first = true;
+        loop {
+            let item_offset = self.pos;
+            let start = match self.bump().ok_or_else(|| unclosed.clone())? {
+                ']' if !first => break,
+                '\\' => {
+                    let c = self.bump().ok_or_else(|| unclosed.clone())?;
+                    match class_escape(c) {
+                        Some((false, escape_ranges)) => {
+                            ranges.extend_from_slice(escape_ranges);
+                            first = false;
+                            continue;
+                        }
+                        Some((true, _)) => {
+                            return Err(RegexError::Unsupported {
+                                feature: "negated escapes in character classes",
+                                offset: item_offset,
+                            })
+                        }
+                        None => self.escaped_char(c, item_offset)?,
+                    }
+                }
+                c => c,
+            };
+            first = false;
+            if self.peek() == Some('-') && self.peek_at(1).is_some_and(|c| c != ']') {
+                self.pos += 1;
+                let end_offset = self.pos;
+                let end = match self.bump().expect("checked above") {
+                    '\\' => {
+                        let c = self.bump().ok_or_else(|| unclosed.clone())?;
+                        if class_escape(c).is_some() {
+                            return Err(RegexError::InvalidRange(item_offset));
+                        }
+                        self.escaped_char(c, end_offset)?
+                    }
+                    c => c,
+                };
+                if end < start {
+                    return Err(RegexError::InvalidRange(item_offset));
+                }
+                ranges.push((start, end));
+            } else {
+                ranges.push((start, start));
+            }
+        }
+        Ok(Node::Class { negated, ranges })
+    }
+
+    /// The character of the escape sequence `\c`.
+    fn escaped_char(&mut self, c: char, offset: usize) -> Result<char> {
+        match c {
+            'n' => Ok('\n'),
+            'r' => Ok('\r'),
+            't' => Ok('\t'),
+            'f' => Ok('\x0C'),
+            'v' => Ok('\x0B'),
+            '0' => Ok('\0'),
+            'x' => self.hex_escape(2, offset),
+            'u' => self.hex_escape(4, offset),
+            'b' | 'B' | 'A' | 'z' | 'Z' => Err(RegexError::Unsupported {
+                feature: "word boundaries and anchors",
+                offset,
+            }),
+            '1'..='9' => Err(RegexError::Unsupported {
+                feature: "backreferences",
+                offset,
+            }),
+            c if c.is_ascii_alphanumeric() => Err(RegexError::InvalidEscape(offset)),
+            c => Ok(c),
+        }
+    }
+
+    /// Parse `digits` hex digits, or any number of them enclosed in braces.
+    fn hex_escape(&mut self, digits: usize, offset: usize) -> Result<char> {
+        let braced = self.eat('{');
+        let mut value = 0u32;
+        let mut count = 0;
+        loop {
+            if braced && self.eat('}') || !braced && count == digits {
+                break;
+            }
+            let digit = self
+                .bump()
+                .and_then(|c| c.to_digit(16))
+                .ok_or(RegexError::InvalidEscape(offset))?;
+            value = value
+                .checked_mul(16)
+                .map(|value| value + digit)
+                .ok_or(RegexError::InvalidEscape(offset))?;
+            count += 1;
+        }
+        if count == 0 {
+            return Err(RegexError::InvalidEscape(offset));
+        }
+        char::from_u32(value).ok_or(RegexError::InvalidEscape(offset))
+    }
+}
+
+struct Emitter {
+    json_string: bool,
+}
+
+impl Emitter {
+    fn rule(&self, node: &Node) -> String {
+        match node {
+            Node::Alternation(alternatives) => alternatives
+                .iter()
+                .map(|alternative| self.sequence(alternative))
+                .collect::<Vec<_>>()
+                .join(" | "),
+            node => self.sequence(node),
+        }
+    }
+
+    /// Emit a sequence, merging consecutive characters into one literal.
+    fn sequence(&self, node: &Node) -> String {
+        let Node::Concat(items) = node else {
+            return self.atom(node);
+        };
+        if items.is_empty() {
+            return "\"\"".to_string();
+        }
+        let mut out = Vec::new();
+        let mut literal = String::new();
+        for item in items {
+            if let Node::Char(c) = item {
+                literal.push(*c);
+            } else {
+                if !literal.is_empty() {
+                    out.push(self.literal(&literal));
+                    literal.clear();
+                }
+                out.push(self.atom(item));
+            }
+        }
+        if !literal.is_empty() {
+            out.push(self.literal(&literal));
+        }
+        out.join(" ")
+    }
+
+    fn atom(&self, node: &Node) -> String {
+        match node {
+            Node::Char(c) => self.literal(&c.to_string()),
+            Node::Any if self.json_string => r#"[^"\\\x7F\x00-\x1F]"#.to_string(),
+            Node::Any => r"[^\n\r]".to_string(),
+            Node::Class {
+                negated: false,
+                ranges,
+            } if self.json_string => {
+                // characters that must be escaped in JSON are matched by their escape sequences
+                let escaped: Vec<_> = ('\0'..='\u{1f}')
+                    .chain(['"', '\\'])
+                    .filter(|&c| {
+                        ranges
+                            .iter()
+                            .any(|&(start, end)| (start..=end).contains(&c))
+                    })
+                    .map(|c| self.literal(&c.to_string()))
+                    .collect();
+                if escaped.is_empty() {
+                    return class(false, ranges);
+                }
+                let allowed = subtract_ranges(ranges, JSON_ESCAPED);
+                let alternatives: Vec<_> = (!allowed.is_empty())
+                    .then(|| class(false, &allowed))
+                    .into_iter()
+                    .chain(escaped)
+                    .collect();
+                format!("({})", alternatives.join(" | "))
+            }
+            Node::Class { negated, ranges } => {
+                let mut out = class(*negated, ranges);
+                if *negated && self.json_string {
+                    out.insert_str(out.len() - 1, r#""\\\x00-\x1F"#);
+                }
+                out
+            }
+            Node::Repeat { node, min, max } => {
+                let item = match **node {
+                    Node::Repeat { .. } => format!("({})", self.atom(node)),
+                    _ => self.atom(node),
+                };
+                match (min, max) {
+                    (0, None) => format!("{item}*"),
+                    (1, None) => format!("{item}+"),
+                    (0, Some(1)) => format!("{item}?"),
+                    (min, None) => format!("{item}{{{min},}}"),
+                    (min, Some(max)) if min == max => format!("{item}{{{min}}}"),
+                    (min, Some(max)) => format!("{item}{{{min},{max}}}"),
+                }
+            }
+            Node::Concat(items) if items.is_empty() => self.sequence(node),
+            Node::Concat(_) | Node::Alternation(_) => format!("({})", self.rule(node)),
+        }
+    }
+
+    fn literal(&self, literal: &str) -> String {
+        if self.json_string {
+            let encoded: String = literal
+                .chars()
+                .map(|c| json_escape(c).unwrap_or_else(|| c.to_string()))
+                .collect();
+            format_literal(&encoded)
+        } else {
+            format_literal(literal)
+        }
+    }
+}
+
+/// The characters that must be escaped in a JSON string.
+const JSON_ESCAPED: &[(char, char)] = &[('\0', '\u{1f}'), ('"', '"'), ('\\', '\\')];
+
+/// A character class matching `ranges`, or any character but them if `negated`.
+fn class(negated: bool, ranges: &[(char, char)]) -> String {
+    let mut out = String::from(if negated { "[^" } else { "[" });
+    for &(start, end) in ranges {
+        out.push_str(&format_range_char(start));
+        if start != end {
+            out.push('-');
+            out.push_str(&format_range_char(end));
+        }
+    }
+    out.push(']');
+    out
+}
+
+/// The parts of `ranges` outside of the ASCII ranges `removed`.
+fn subtract_ranges(ranges: &[(char, char)], removed: &[(char, char)]) -> Vec<(char, char)> {
+    let mut ranges = ranges.to_vec();
+    for &(removed_start, removed_end) in removed {
+        ranges = ranges
+            .into_iter()
+            .flat_map(|(start, end)| {
+                if end < removed_start || start > removed_end {
+                    return vec![(start, end)];
+                }
+                let before = char::from_u32(u32::from(removed_start).wrapping_sub(1))
+                    .filter(|_| start < removed_start)
+                    .map(|before| (start, before));
+                let after = char::from_u32(u32::from(removed_end) + 1)
+                    .filter(|_| end > removed_end)
+                    .map(|after| (after, end));
+                before.into_iter().chain(after).collect()
+            })
+            .collect();
+    }
+    ranges
+}
+
+/// The escape sequence of `c` in a JSON string, as `serde_json` writes it, if `c` needs one.
+fn json_escape(c: char) -> Option<String> {
+    let escaped = match c {
+        '"' => r#"\""#,
+        '\\' => r"\\",
+        '\u{8}' => r"\b",
+        '\u{c}' => r"\f",
+        '\n' => r"\n",
+        '\r' => r"\r",
+        '\t' => r"\t",
+        '\0'..='\u{1f}' => return Some(format!("\\u{:04x}", u32::from(c))),
+        _ => return None,
+    };
+    Some(escaped.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use super::*;
+    use crate::grammar::LlamaGrammar;
+
+    fn convert(pattern: &str) -> String {
+        let grammar = regex_to_grammar(pattern).unwrap();
+        LlamaGrammar::from_str(&grammar).unwrap();
+        grammar
+            .strip_prefix("root ::= ")
+            .unwrap()
+            .trim_end()
+            .to_string()
+    }
+
+    #[test]
+    fn literals_and_classes() {
+        assert_eq!(convert(r"\d{3}-\d{4}"), r#"[0-9]{3} "-" [0-9]{4}"#);
+        assert_eq!(convert(r"^abc$"), r#""abc""#);
+        assert_eq!(convert(r"^a$|b$|^c"), r#""a" | "b" | "c""#);
+        assert_eq!(convert(r"a\.b\\c"), r#""a.b\\c""#);
+        assert_eq!(convert(r"[a-z_]+\w*"), r"[a-z_]+ [0-9A-Z_a-z]*");
+        assert_eq!(convert(r"[^\s\-]"), r"[^\t-\r \x2D]");
+        assert_eq!(convert(r"\S."), r"[^\t-\r ] [^\n\r]");
+        assert_eq!(convert(r"[]a-]"), r"[\]a\x2D]");
+        assert_eq!(convert(r"\x41\u{1F600}\t"), "\"A\u{1F600}\\t\"");
+    }
+
+    #[test]
+    fn groups_and_quantifiers() {
+        assert_eq!(convert(r"(ab)*c"), r#"("ab")* "c""#);
+        assert_eq!(convert(r"(?:a|bc)?d"), r#"("a" | "bc")? "d""#);
+        assert_eq!(convert(r"(?<x>a)(?P<y>b)"), r#""ab""#);
+        assert_eq!(convert(r"yes|no|"), r#""yes" | "no" | """#);
+        assert_eq!(
+            convert(r"a{2,}b{,3}c{1,2}?"),
+            r#""a"{2,} "b"{0,3} "c"{1,2}"#
+        );
+        assert_eq!(convert(r"(a+)?"), r#"("a"+)?"#);
+        // repeating what matches the empty string would be left recursive
+        assert_eq!(convert(r"(a*)*"), r#""a"*"#);
+        assert_eq!(convert(r"()*b"), r#""" "b""#);
+        assert_eq!(convert(r"(a|)+"), r#""a"*"#);
+        assert_eq!(convert(r"(a?b{0}c*)*"), r#"("a" | "c")*"#);
+        assert_eq!(convert(r"()?a"), r#""" "a""#);
+        assert_eq!(convert(r"(){2}"), r#""""#);
+        assert_eq!(convert(r"(?:)?"), r#""""#);
+    }
+
+    #[test]
+    fn json_string() {
+        assert_eq!(
+            regex_to_rule(r#"a"\\[^x]."#, true).unwrap(),
+            r#""a\\\"\\\\" [^x"\\\x00-\x1F] [^"\\\x7F\x00-\x1F]"#
+        );
+        // JSON strings may not contain raw quotes, backslashes or control characters
+        for pattern in [r#"[a"]"#, r"\s", r"[\\]+", r"[\x00-\x7F]"] {
+            let rule = regex_to_rule(pattern, true).unwrap();
+            LlamaGrammar::from_str(&format!("root ::= {rule}\n")).unwrap();
+        }
+        assert_eq!(regex_to_rule(r#"[a"]"#, true).unwrap(), r#"([a] | "\\\"")"#);
+        assert_eq!(
+            regex_to_rule(r"\s", true).unwrap(),
+            r#"([ ] | "\\t" | "\\n" | "\\u000b" | "\\f" | "\\r")"#
+        );
+        assert_eq!(regex_to_rule(r"[\\]+", true).unwrap(), r#"("\\\\")+"#);
+        assert_eq!(
+            regex_to_rule(r"[\x00-\x7F]", true)
+                .unwrap()
+                .split(" | ")
+                .count(),
+            35
+        );
+    }
+
+    #[test]
+    fn errors() {
+        let err = |pattern| regex_to_grammar(pattern).unwrap_err();
+        assert_eq!(
+            err("(ab"),
+            RegexError::Unclosed {
+                delimiter: '(',
+                offset: 0
+            }
+        );
+        assert_eq!(
+            err("a[bc"),
+            RegexError::Unclosed {
+                delimiter: '[',
+                offset: 1
+            }
+        );
+        assert_eq!(err("ab)"), RegexError::UnmatchedParenthesis(2));
+        assert_eq!(err("*a"), RegexError::NothingToRepeat(0));
+        assert_eq!(err("a**"), RegexError::NothingToRepeat(2));
+        assert_eq!(err("a{3,2}"), RegexError::InvalidRepetition(1));
+        assert_eq!(err("a{x}"), RegexError::InvalidRepetition(1));
+        assert_eq!(err(r"\q"), RegexError::InvalidEscape(0));
+        assert_eq!(err("[z-a]"), RegexError::InvalidRange(1));
+        assert!(matches!(
+            err(r"(a)\1"),
+            RegexError::Unsupported { offset: 3, .. }
+        ));
+        assert!(matches!(
+            err(r"a(?=b)"),
+            RegexError::Unsupported { offset: 1, .. }
+        ));
+        assert!(matches!(
+            err(r"a^b"),
+            RegexError::Unsupported { offset: 1, .. }
+        ));
+        assert!(matches!(
+            err(r"(^a|b$)"),
+            RegexError::Unsupported { offset: 1, .. }
+        ));
+        assert!(matches!(
+            err(r"\bword"),
+            RegexError::Unsupported { offset: 0, .. }
+        ));
+    }
+}
@@ -88,3 +88,58 @@ fn check_parse_char_range() {
         parse_state
     );
 }
+
+#[test]
+fn check_parse_repetition() {
+    let grammar = LlamaGrammar::from_str(r#"root ::= "a"{2,3}"#).unwrap();
+    let root = &grammar.rules()[grammar.root_id() as usize];
+    assert_eq!(
+        root.iter().map(|e| e.type_).collect::<Vec<_>>(),
+        vec![
+            llama_cpp_sys_2::LLAMA_GRETYPE_CHAR,
+            llama_cpp_sys_2::LLAMA_GRETYPE_CHAR,
+            llama_cpp_sys_2::LLAMA_GRETYPE_RULE_REF,
+            llama_cpp_sys_2::LLAMA_GRETYPE_END,
+        ]
+    );
+}
+
+#[test]
+fn check_parse_errors() {
+    assert_eq!(
+        LlamaGrammar::from_str("root ::= foo\n"),
+        Err(GrammarParseError::UndefinedRule {
+            name: "foo".to_string(),
+            location: Location {
+                line: 1,
+                column: 10
+            },
+        })
+    );
+    assert_eq!(
+        LlamaGrammar::from_str("root ::= \"a\"\nother ::= [a-z"),
+        Err(GrammarParseError::UnexpectedEndOfInput(Location {
+            line: 2,
+            column: 15
+        }))
+    );
+    assert_eq!(
+        LlamaGrammar::from_str("root ::= \"a\"{3,1}"),
+        Err(GrammarParseError::InvalidRepetitionBounds {
+            min: 3,
+            max: 1,
+            location: Location {
+                line: 1,
+                column: 13
+            },
+        })
+    );
+    assert_eq!(
+        LlamaGrammar::from_str("other ::= \"a\""),
+        Err(GrammarParseError::MissingRoot)
+    );
+    assert_eq!(
+        LlamaGrammar::from_str("root ::= root \"a\" | \"b\""),
+        Err(GrammarParseError::LeftRecursion("root".to_string()))
+    );
+}
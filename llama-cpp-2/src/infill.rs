@@ -0,0 +1,283 @@
+//! Fill-in-the-middle (FIM) prompts for code models.
+//!
+//! Code models trained for infilling generate the text between a prefix and a suffix, for example
+//! the code around the cursor in an editor. The prompt is built from special tokens of the model,
+//! which [`FimTokens`] reads from its vocabulary, so the same code works for every model family
+//! that has them. Repository-level models also get the repository name and other files as context.
+//!
+//! Generate the infill with [`crate::sampling::LlamaSampler::infill`] in the sampler chain, it
+//! ends the infill early when no text is likely:
+//!
+//! ```no_run
+//! # use llama_cpp_2::context::LlamaContext;
+//! # fn example(ctx: &mut LlamaContext) -> Result<(), Box<dyn std::error::Error>> {
+//! use llama_cpp_2::completion::Completion;
+//! use llama_cpp_2::infill::InfillPrompt;
+//! use llama_cpp_2::sampling::LlamaSampler;
+//!
+//! let prompt = InfillPrompt::new("fn add(a: i32, b: i32) -> i32 {\n    ", "\n}\n")
+//!     .with_repo_name("calculator")
+//!     .with_context_file("src/lib.rs", "mod add;\n")
+//!     .with_file_name("src/add.rs")
+//!     .tokenize(ctx.model)?;
+//! let sampler = LlamaSampler::chain_simple([
+//!     LlamaSampler::top_k(40),
+//!     LlamaSampler::infill(ctx.model),
+//!     LlamaSampler::dist(42),
+//! ]);
+//! for token in Completion::new(ctx, &prompt, sampler).with_max_tokens(64) {
+//!     print!("{}", token?.text);
+//! }
+//! # Ok(())
+//! # }
+//! ```
+
+use crate::model::{AddBos, LlamaModel};
+use crate::token::LlamaToken;
+use crate::StringToTokenError;
+
+/// Separates context files for models without a file separator token, as in the llama.cpp
+/// server.
+const SNIPPET_SEPARATOR: &str = "\n\n--- snippet ---\n\n";
+
+/// An error while building an infill prompt.
+#[derive(Debug, thiserror::Error)]
+pub enum InfillError {
+    /// The model has no FIM token of this kind, so it does not support infilling.
+    #[error("the model has no FIM {0} token")]
+    MissingToken(&'static str),
+    /// Tokenizing a part of the prompt failed.
+    #[error("{0}")]
+    StringToTokenError(#[from] StringToTokenError),
+}
+
+/// The FIM special tokens of a model.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FimTokens {
+    /// Starts the text before the infill.
+    pub prefix: LlamaToken,
+    /// Starts the text after the infill.
+    pub suffix: LlamaToken,
+    /// Starts the infill.
+    pub middle: LlamaToken,
+    /// Pads the infill, it ends generation.
+    pub pad: Option<LlamaToken>,
+    /// Starts the repository name.
+    pub repo: Option<LlamaToken>,
+    /// Starts the name and text of a file.
+    pub file_separator: Option<LlamaToken>,
+}
+
+impl FimTokens {
+    /// Read the FIM tokens from the vocabulary of `model`.
+    ///
+    /// # Errors
+    ///
+    /// If the model has no prefix, suffix or middle token.
+    pub fn from_model(model: &LlamaModel) -> Result<Self, InfillError> {
+        let token =
+            |token: LlamaToken| (token.0 != llama_cpp_sys_2::LLAMA_TOKEN_NULL).then_some(token);
+        let required = |t: LlamaToken, name| token(t).ok_or(InfillError::MissingToken(name));
+        Ok(Self {
+            prefix: required(model.token_fim_pre(), "prefix")?,
+            suffix: required(model.token_fim_suf(), "suffix")?,
+            middle: required(model.token_fim_mid(), "middle")?,
+            pad: token(model.token_fim_pad()),
+            repo: token(model.token_fim_rep()),
+            file_separator: token(model.token_fim_sep()),
+        })
+    }
+}
+
+/// The order of the prefix and the suffix in the prompt.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum FimOrder {
+    /// Prefix, suffix, middle, the order most models are trained with.
+    #[default]
+    PrefixSuffixMiddle,
+    /// Suffix, prefix, middle, for models trained with the SPM order.
+    SuffixPrefixMiddle,
+}
+
+/// The parts of an infill prompt, see [the module docs](self).
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct InfillPrompt {
+    prefix: String,
+    suffix: String,
+    repo_name: Option<String>,
+    context_files: Vec<(String, String)>,
+    file_name: Option<String>,
+    order: FimOrder,
+}
+
+impl InfillPrompt {
+    /// A prompt for the text between `prefix` and `suffix`.
+    #[must_use]
+    pub fn new(prefix: impl Into<String>, suffix: impl Into<String>) -> Self {
+        Self {
+            prefix: prefix.into(),
+            suffix: suffix.into(),
+            ..Self::default()
+        }
+    }
+
+    /// Start the prompt with the name of the repository, if the model has a repository token.
+    #[must_use]
+    pub fn with_repo_name(mut self, name: impl Into<String>) -> Self {
+        self.repo_name = Some(name.into());
+        self
+    }
+
+    /// Add a file as context before the prefix. Without a file separator token the files are
+    /// separated by text, without their names.
+    #[must_use]
+    pub fn with_context_file(mut self, name: impl Into<String>, text: impl Into<String>) -> Self {
+        self.context_files.push((name.into(), text.into()));
+        self
+    }
+
+    /// The name of the file that is infilled, if the model has a file separator token. Set it
+    /// when there are context files, so the model can tell them apart.
+    #[must_use]
+    pub fn with_file_name(mut self, name: impl Into<String>) -> Self {
+        self.file_name = Some(name.into());
+        self
+    }
+
+    /// Put the prefix and the suffix in `order`, [`FimOrder::PrefixSuffixMiddle`] by default.
+    #[must_use]
+    pub fn with_order(mut self, order: FimOrder) -> Self {
+        self.order = order;
+        self
+    }
+
+    /// Build the prompt for `model`, like the infill endpoint of the llama.cpp server: the
+    /// repository name, the context files, the file name, the prefix and the suffix, then the
+    /// middle token. Generation continues after it. The parts are tokenized as plain text, so
+    /// text like `<|fim_middle|>` in them does not become a special token.
+    ///
+    /// # Errors
+    ///
+    /// If the model does not support infilling or tokenizing fails.
+    pub fn tokenize(&self, model: &LlamaModel) -> Result<Vec<LlamaToken>, InfillError> {
+        let fim = FimTokens::from_model(model)?;
+        let add_bos = unsafe { llama_cpp_sys_2::llama_vocab_get_add_bos(model.vocab_ptr()) };
+        let bos = add_bos.then(|| model.token_bos());
+        Ok(self.build(&fim, bos, |text| {
+            model.str_to_token_plaintext(text, AddBos::Never)
+        })?)
+    }
+
+    fn build(
+        &self,
+        fim: &FimTokens,
+        bos: Option<LlamaToken>,
+        mut tokenize: impl FnMut(&str) -> Result<Vec<LlamaToken>, StringToTokenError>,
+    ) -> Result<Vec<LlamaToken>, StringToTokenError> {
+        let mut tokens: Vec<_> = bos.into_iter().collect();
+        if let (Some(repo), Some(name)) = (fim.repo, &self.repo_name) {
+            tokens.push(repo);
+            tokens.extend(tokenize(&format!("{name}\n"))?);
+        }
+        for (name, text) in &self.context_files {
+            if let Some(separator) = fim.file_separator {
+                tokens.push(separator);
+                tokens.extend(tokenize(&format!("{name}\n"))?);
+            } else {
+                tokens.extend(tokenize(SNIPPET_SEPARATOR)?);
+            }
+            tokens.extend(tokenize(text)?);
+        }
+        if let (Some(separator), Some(name)) = (fim.file_separator, &self.file_name) {
+            tokens.push(separator);
+            tokens.extend(tokenize(&format!("{name}\n"))?);
+        }
+
+        let mut prefix = vec![fim.prefix];
+        prefix.extend(tokenize(&self.prefix)?);
+        let mut suffix = vec![fim.suffix];
+        suffix.extend(tokenize(&self.suffix)?);
+        let (first, second) = match self.order {
+            FimOrder::PrefixSuffixMiddle => (prefix, suffix),
+            FimOrder::SuffixPrefixMiddle => (suffix, prefix),
+        };
+        tokens.extend(first);
+        tokens.extend(second);
+        tokens.push(fim.middle);
+        Ok(tokens)
+    }
+}
+
+/// Build the infill prompt for the text between `prefix` and `suffix`, see [`InfillPrompt`] for
+/// more options.
+///
+/// # Errors
+///
+/// If the model does not support infilling or tokenizing fails.
+pub fn infill(
+    model: &LlamaModel,
+    prefix: &str,
+    suffix: &str,
+) -> Result<Vec<LlamaToken>, InfillError> {
+    InfillPrompt::new(prefix, suffix).tokenize(model)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FIM: FimTokens = FimTokens {
+        prefix: LlamaToken(-2),
+        suffix: LlamaToken(-3),
+        middle: LlamaToken(-4),
+        pad: None,
+        repo: Some(LlamaToken(-5)),
+        file_separator: Some(LlamaToken(-6)),
+    };
+
+    fn build(prompt: &InfillPrompt, fim: &FimTokens) -> String {
+        let tokens = prompt
+            .build(fim, Some(LlamaToken(-1)), |text| {
+                Ok(text.bytes().map(|b| LlamaToken(i32::from(b))).collect())
+            })
+            .unwrap();
+        tokens
+            .into_iter()
+            .map(|LlamaToken(t)| match t {
+                -1 => "<bos>".to_string(),
+                -2 => "<pre>".to_string(),
+                -3 => "<suf>".to_string(),
+                -4 => "<mid>".to_string(),
+                -5 => "<repo>".to_string(),
+                -6 => "<sep>".to_string(),
+                b => char::from(u8::try_from(b).unwrap()).to_string(),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn prompt() {
+        let prompt = InfillPrompt::new("a", "b");
+        assert_eq!(build(&prompt, &FIM), "<bos><pre>a<suf>b<mid>");
+        let spm = prompt.clone().with_order(FimOrder::SuffixPrefixMiddle);
+        assert_eq!(build(&spm, &FIM), "<bos><suf>b<pre>a<mid>");
+
+        let repo = prompt
+            .with_repo_name("r")
+            .with_context_file("x", "1")
+            .with_file_name("y");
+        assert_eq!(
+            build(&repo, &FIM),
+            "<bos><repo>r\n<sep>x\n1<sep>y\n<pre>a<suf>b<mid>"
+        );
+        let no_repo = FimTokens {
+            repo: None,
+            file_separator: None,
+            ..FIM
+        };
+        assert_eq!(
+            build(&repo, &no_repo),
+            "<bos>\n\n--- snippet ---\n\n1<pre>a<suf>b<mid>"
+        );
+    }
+}
@@ -13,6 +13,10 @@
 //!
 //! - `cuda` enables CUDA gpu support.
 //! - `sampler` adds the [`context::sample::sampler`] struct for a more rusty way of sampling.
+//! - `serde` adds [`grammar::json_schema`] and [`structured`], and serde support to
+//!   [`sampling::config`] and [`sampling::logprobs`].
+//! - `derive` adds a derive macro for [`structured::LlamaStructured`]. Implies `serde`.
+//! - `async` adds [`completion::stream`] to generate text as a `Stream` on a worker thread.
 use std::ffi::{c_char, NulError};
 use std::fmt::Debug;
 use std::num::NonZeroI32;
@@ -22,7 +26,11 @@ use std::os::raw::c_int;
 use std::path::PathBuf;
 use std::string::FromUtf8Error;
 
+pub mod completion;
 pub mod context;
+pub mod detokenize;
+pub mod grammar;
+pub mod infill;
 pub mod llama_backend;
 pub mod llama_batch;
 mod log;
@@ -30,6 +38,9 @@ pub mod model;
 #[cfg(feature = "mtmd")]
 pub mod mtmd;
 pub mod sampling;
+pub mod stop;
+#[cfg(feature = "serde")]
+pub mod structured;
 pub mod timing;
 pub mod token;
 pub mod token_type;
@@ -115,19 +126,47 @@ pub enum LlamaContextLoadError {
 }
 
 /// Failed to decode a batch.
+///
+/// Each variant documents the state of the context afterwards. [`DecodeError::is_transient`] tells
+/// the failures that can succeed on a retry from the fatal ones.
 #[derive(Debug, Eq, PartialEq, thiserror::Error)]
 pub enum DecodeError {
-    /// No kv cache slot was available.
-    #[error("Decode Error 1: NoKvCacheSlot")]
-    NoKvCacheSlot,
-    /// The number of tokens in the batch was 0.
-    #[error("Decode Error -1: n_tokens == 0")]
-    NTokensZero,
+    /// No KV cache slot could be found for the batch. Nothing was processed and the KV cache is
+    /// unchanged. Decoding can succeed with a smaller batch or after freeing KV cache space, see
+    /// [`context::LlamaContext::set_split_on_no_kv_slot`].
+    #[error("Decode Error 1: no KV cache slot for the batch")]
+    NoKvSlot,
+    /// The abort callback of the context stopped the computation, see
+    /// [`context::LlamaContext::set_abort_callback`]. Already processed micro-batches remain in
+    /// the KV cache.
+    #[error("Decode Error 2: aborted")]
+    Aborted,
+    /// The batch was rejected, for example because it is empty or contains an invalid token,
+    /// sequence id or position. Nothing was processed and the KV cache is unchanged.
+    #[error("Decode Error -1: invalid batch")]
+    InvalidBatch,
+    /// The compute buffers could not be allocated. Already processed micro-batches remain in the
+    /// KV cache.
+    #[error("Decode Error -2: allocation failed")]
+    AllocationFailed,
+    /// The computation failed in the backend. Already processed micro-batches remain in the KV
+    /// cache.
+    #[error("Decode Error -3: compute failed")]
+    ComputeFailed,
     /// An unknown error occurred.
     #[error("Decode Error {0}: unknown")]
     Unknown(c_int),
 }
 
+impl DecodeError {
+    /// Whether decoding the batch again can succeed: there was no KV cache slot or the decode was
+    /// aborted. The other errors are fatal for the batch.
+    #[must_use]
+    pub fn is_transient(&self) -> bool {
+        matches!(self, Self::NoKvSlot | Self::Aborted)
+    }
+}
+
 /// Failed to decode a batch.
 #[derive(Debug, Eq, PartialEq, thiserror::Error)]
 pub enum EncodeError {
@@ -137,6 +176,10 @@ pub enum EncodeError {
     /// The number of tokens in the batch was 0.
     #[error("Encode Error -1: n_tokens == 0")]
     NTokensZero,
+    /// The abort callback of the context stopped the computation, see
+    /// [`context::LlamaContext::set_abort_callback`].
+    #[error("Encode Error 2: aborted")]
+    Aborted,
     /// An unknown error occurred.
     #[error("Encode Error {0}: unknown")]
     Unknown(c_int),
@@ -165,20 +208,32 @@ pub enum GrammarError {
     /// The trigger word contains null bytes
     #[error("Trigger word contains null bytes")]
     TriggerWordNullBytes,
+    /// A DRY sequence breaker contains null bytes
+    #[error("DRY sequence breaker contains null bytes")]
+    SequenceBreakerNullBytes,
     /// The grammar string or root contains null bytes
     #[error("Grammar string or root contains null bytes")]
     GrammarNullBytes,
     /// The grammar call returned null
     #[error("Grammar call returned null")]
     NullGrammar,
+    /// The regular expression could not be converted into a grammar
+    #[error("Invalid regular expression: {0}")]
+    InvalidRegex(#[from] grammar::regex::RegexError),
+    /// The grammar could not be parsed
+    #[error("Invalid grammar: {0}")]
+    InvalidGrammar(#[from] grammar::GrammarParseError),
 }
 
 /// Decode a error from llama.cpp into a [`DecodeError`].
 impl From<NonZeroI32> for DecodeError {
     fn from(value: NonZeroI32) -> Self {
         match value.get() {
-            1 => DecodeError::NoKvCacheSlot,
-            -1 => DecodeError::NTokensZero,
+            1 => DecodeError::NoKvSlot,
+            2 => DecodeError::Aborted,
+            -1 => DecodeError::InvalidBatch,
+            -2 => DecodeError::AllocationFailed,
+            -3 => DecodeError::ComputeFailed,
             i => DecodeError::Unknown(i),
         }
     }
@@ -190,6 +245,7 @@ impl From<NonZeroI32> for EncodeError {
         match value.get() {
             1 => EncodeError::NoKvCacheSlot,
             -1 => EncodeError::NTokensZero,
+            2 => EncodeError::Aborted,
             i => EncodeError::Unknown(i),
         }
     }
@@ -196,6 +196,69 @@ impl<'a> LlamaBatch<'a> {
     pub fn n_tokens(&self) -> i32 {
         self.llama_batch.n_tokens
     }
+
+    /// The tokens `start..end` of the batch, sharing its buffers. `None` for a batch of embeddings.
+    ///
+    /// # Panics
+    ///
+    /// If the range is not within the batch.
+    pub(crate) fn view(&self, start: i32, end: i32) -> Option<llama_batch> {
+        // `llama_batch_get_one` leaves everything but the tokens null
+        fn offset<T>(ptr: *mut T, start: usize) -> *mut T {
+            if ptr.is_null() {
+                ptr
+            } else {
+                unsafe { ptr.add(start) }
+            }
+        }
+
+        assert!(
+            0 <= start && start <= end && end <= self.n_tokens(),
+            "{start}..{end} is not within the batch of {} tokens",
+            self.n_tokens()
+        );
+        if !self.llama_batch.embd.is_null() {
+            return None;
+        }
+        let start_usize = usize::try_from(start).expect("start is positive");
+        Some(llama_batch {
+            n_tokens: end - start,
+            token: offset(self.llama_batch.token, start_usize),
+            embd: self.llama_batch.embd,
+            pos: offset(self.llama_batch.pos, start_usize),
+            n_seq_id: offset(self.llama_batch.n_seq_id, start_usize),
+            seq_id: offset(self.llama_batch.seq_id, start_usize),
+            logits: offset(self.llama_batch.logits, start_usize),
+        })
+    }
+
+    /// The sequence id and position of each sequence of the tokens `start..end`. `None` if
+    /// llama.cpp assigns them, as for a batch from [`Self::get_one`].
+    pub(crate) fn seq_positions(
+        &self,
+        start: i32,
+        end: i32,
+    ) -> Option<Vec<(llama_seq_id, llama_pos)>> {
+        let batch = &self.llama_batch;
+        if batch.pos.is_null() || batch.n_seq_id.is_null() || batch.seq_id.is_null() {
+            return None;
+        }
+        let mut positions = Vec::new();
+        for i in start..end {
+            let i = usize::try_from(i).expect("token index is positive");
+            let (pos, n_seq_id, seq_ids) = unsafe {
+                (
+                    *batch.pos.add(i),
+                    *batch.n_seq_id.add(i),
+                    *batch.seq_id.add(i),
+                )
+            };
+            for j in 0..usize::try_from(n_seq_id).expect("n_seq_id is positive") {
+                positions.push((unsafe { *seq_ids.add(j) }, pos));
+            }
+        }
+        Some(positions)
+    }
 }
 
 impl<'a> Drop for LlamaBatch<'a> {
@@ -202,6 +202,48 @@ impl LlamaModel {
         LlamaToken(token)
     }
 
+    /// Get the fill-in-the-middle prefix token, `LlamaToken(-1)` if the model has none.
+    #[must_use]
+    pub fn token_fim_pre(&self) -> LlamaToken {
+        let token = unsafe { llama_cpp_sys_2::llama_vocab_fim_pre(self.vocab_ptr()) };
+        LlamaToken(token)
+    }
+
+    /// Get the fill-in-the-middle suffix token, `LlamaToken(-1)` if the model has none.
+    #[must_use]
+    pub fn token_fim_suf(&self) -> LlamaToken {
+        let token = unsafe { llama_cpp_sys_2::llama_vocab_fim_suf(self.vocab_ptr()) };
+        LlamaToken(token)
+    }
+
+    /// Get the fill-in-the-middle middle token, `LlamaToken(-1)` if the model has none.
+    #[must_use]
+    pub fn token_fim_mid(&self) -> LlamaToken {
+        let token = unsafe { llama_cpp_sys_2::llama_vocab_fim_mid(self.vocab_ptr()) };
+        LlamaToken(token)
+    }
+
+    /// Get the fill-in-the-middle padding token, `LlamaToken(-1)` if the model has none.
+    #[must_use]
+    pub fn token_fim_pad(&self) -> LlamaToken {
+        let token = unsafe { llama_cpp_sys_2::llama_vocab_fim_pad(self.vocab_ptr()) };
+        LlamaToken(token)
+    }
+
+    /// Get the fill-in-the-middle repository name token, `LlamaToken(-1)` if the model has none.
+    #[must_use]
+    pub fn token_fim_rep(&self) -> LlamaToken {
+        let token = unsafe { llama_cpp_sys_2::llama_vocab_fim_rep(self.vocab_ptr()) };
+        LlamaToken(token)
+    }
+
+    /// Get the fill-in-the-middle file separator token, `LlamaToken(-1)` if the model has none.
+    #[must_use]
+    pub fn token_fim_sep(&self) -> LlamaToken {
+        let token = unsafe { llama_cpp_sys_2::llama_vocab_fim_sep(self.vocab_ptr()) };
+        LlamaToken(token)
+    }
+
     /// Convert single token to a string.
     ///
     /// # Errors
@@ -296,6 +338,34 @@ impl LlamaModel {
         &self,
         str: &str,
         add_bos: AddBos,
+    ) -> Result<Vec<LlamaToken>, StringToTokenError> {
+        self.tokenize(str, add_bos, true)
+    }
+
+    /// Convert a string to a Vector of tokens like [`Self::str_to_token`], but the text of special
+    /// tokens, like `<|endoftext|>`, is tokenized as plain text. Use this for text that is not
+    /// trusted to contain control tokens, such as user input or source code.
+    ///
+    /// # Errors
+    ///
+    /// - if [`str`] contains a null byte.
+    ///
+    /// # Panics
+    ///
+    /// - if there is more than [`usize::MAX`] [`LlamaToken`]s in [`str`].
+    pub fn str_to_token_plaintext(
+        &self,
+        str: &str,
+        add_bos: AddBos,
+    ) -> Result<Vec<LlamaToken>, StringToTokenError> {
+        self.tokenize(str, add_bos, false)
+    }
+
+    fn tokenize(
+        &self,
+        str: &str,
+        add_bos: AddBos,
+        parse_special: bool,
     ) -> Result<Vec<LlamaToken>, StringToTokenError> {
         let add_bos = match add_bos {
             AddBos::Always => true,
@@ -317,7 +387,7 @@ impl LlamaModel {
                 buffer.as_mut_ptr().cast::<llama_cpp_sys_2::llama_token>(),
                 buffer_capacity,
                 add_bos,
-                true,
+                parse_special,
             )
         };
 
@@ -333,7 +403,7 @@ impl LlamaModel {
                     buffer.as_mut_ptr().cast::<llama_cpp_sys_2::llama_token>(),
                     -size,
                     add_bos,
-                    true,
+                    parse_special,
                 )
             }
         } else {
@@ -796,7 +866,12 @@ impl LlamaModel {
         };
         let context = NonNull::new(context).ok_or(LlamaContextLoadError::NullReturn)?;
 
-        Ok(LlamaContext::new(self, context, params.embeddings()))
+        Ok(LlamaContext::new(
+            self,
+            context,
+            params.embeddings(),
+            params.kv_unified(),
+        ))
     }
 
     /// Apply the models chat template to some messages.
@@ -1,16 +1,25 @@
 //! Safe wrapper around `llama_sampler`.
 
 use std::borrow::Borrow;
-use std::ffi::{c_char, CString};
+use std::ffi::{c_char, CStr, CString};
 use std::fmt::{Debug, Formatter};
+use std::str::FromStr;
+use std::sync::OnceLock;
 
 use crate::context::LlamaContext;
+use crate::grammar::regex::regex_to_grammar;
+use crate::grammar::LlamaGrammar;
 use crate::model::LlamaModel;
+use crate::timing::LlamaSamplerTimings;
 use crate::token::data_array::LlamaTokenDataArray;
 use crate::token::logit_bias::LlamaLogitBias;
 use crate::token::LlamaToken;
 use crate::GrammarError;
 
+pub mod config;
+pub mod custom;
+pub mod logprobs;
+
 /// A safe wrapper around `llama_sampler`.
 pub struct LlamaSampler {
     pub(crate) sampler: *mut llama_cpp_sys_2::llama_sampler,
@@ -18,7 +27,88 @@ pub struct LlamaSampler {
 
 impl Debug for LlamaSampler {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        f.debug_struct("LlamaSamplerChain").finish()
+        SamplerDebug(self.sampler).fmt(f)
+    }
+}
+
+/// Prints the name of a sampler, or the members of a chain.
+struct SamplerDebug(*mut llama_cpp_sys_2::llama_sampler);
+
+impl Debug for SamplerDebug {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match unsafe { chain_len(self.0) } {
+            Some(len) => f
+                .debug_struct("LlamaSamplerChain")
+                .field(
+                    "samplers",
+                    &(0..len)
+                        .map(|i| SamplerDebug(unsafe { chain_get(self.0, i) }))
+                        .collect::<Vec<_>>(),
+                )
+                .finish(),
+            None => f
+                .debug_struct("LlamaSampler")
+                .field("name", &unsafe { sampler_name(self.0) })
+                .finish(),
+        }
+    }
+}
+
+/// The vtable shared by all chains, used to tell chains apart from other samplers.
+fn chain_iface() -> *const llama_cpp_sys_2::llama_sampler_i {
+    static CHAIN_IFACE: OnceLock<usize> = OnceLock::new();
+    let iface = *CHAIN_IFACE.get_or_init(|| unsafe {
+        let chain = llama_cpp_sys_2::llama_sampler_chain_init(
+            llama_cpp_sys_2::llama_sampler_chain_default_params(),
+        );
+        let iface = (*chain).iface;
+        llama_cpp_sys_2::llama_sampler_free(chain);
+        iface as usize
+    });
+    iface as *const _
+}
+
+/// # Safety
+///
+/// `sampler` must point to a valid sampler.
+unsafe fn sampler_name(sampler: *const llama_cpp_sys_2::llama_sampler) -> String {
+    let name = llama_cpp_sys_2::llama_sampler_name(sampler);
+    if name.is_null() {
+        String::new()
+    } else {
+        CStr::from_ptr(name).to_string_lossy().into_owned()
+    }
+}
+
+/// The number of members of `sampler` if it is a chain.
+///
+/// # Safety
+///
+/// `sampler` must point to a valid sampler.
+unsafe fn chain_len(sampler: *const llama_cpp_sys_2::llama_sampler) -> Option<usize> {
+    if (*sampler).iface != chain_iface() {
+        return None;
+    }
+    let len = llama_cpp_sys_2::llama_sampler_chain_n(sampler);
+    Some(usize::try_from(len).expect("chain length is non-negative"))
+}
+
+/// # Safety
+///
+/// `chain` must point to a valid chain with more than `i` members.
+unsafe fn chain_get(
+    chain: *const llama_cpp_sys_2::llama_sampler,
+    i: usize,
+) -> *mut llama_cpp_sys_2::llama_sampler {
+    let i = i32::try_from(i).expect("chain index fits into an i32");
+    llama_cpp_sys_2::llama_sampler_chain_get(chain, i)
+}
+
+/// Clones the sampler including its state, with `llama_sampler_clone`.
+impl Clone for LlamaSampler {
+    fn clone(&self) -> Self {
+        let sampler = unsafe { llama_cpp_sys_2::llama_sampler_clone(self.sampler) };
+        Self { sampler }
     }
 }
 
@@ -27,7 +117,11 @@ impl LlamaSampler {
     #[must_use]
     pub fn sample(&mut self, ctx: &LlamaContext, idx: i32) -> LlamaToken {
         let token = unsafe {
-            llama_cpp_sys_2::llama_sampler_sample(self.sampler, ctx.context.as_ptr(), idx)
+            llama_cpp_sys_2::llama_sampler_sample(
+                self.sampler,
+                ctx.context.as_ptr(),
+                ctx.output_index(idx),
+            )
         };
 
         LlamaToken(token)
@@ -144,6 +238,108 @@ impl LlamaSampler {
         Self::chain(samplers, false)
     }
 
+    /// The name of the sampler as reported by llama.cpp, e.g. `"top-k"` or `"chain"`.
+    #[must_use]
+    pub fn name(&self) -> String {
+        unsafe { sampler_name(self.sampler) }
+    }
+
+    /// Whether the sampler was created with [`Self::chain`] or [`Self::chain_simple`].
+    #[must_use]
+    pub fn is_chain(&self) -> bool {
+        self.chain_len().is_some()
+    }
+
+    /// The number of samplers in the chain, or `None` if this sampler is not a chain.
+    ///
+    /// # Example
+    /// ```rust
+    /// use llama_cpp_2::sampling::LlamaSampler;
+    /// use llama_cpp_2::llama_backend::LlamaBackend;
+    /// let backend = LlamaBackend::init().unwrap();
+    ///
+    /// let mut chain = LlamaSampler::chain_simple([LlamaSampler::greedy()]);
+    /// chain.chain_insert(0, LlamaSampler::top_k(40));
+    ///
+    /// assert_eq!(chain.chain_len(), Some(2));
+    /// assert_eq!(chain.chain_member_name(0).as_deref(), Some("top-k"));
+    /// assert_eq!(chain.chain_member_name(1).as_deref(), Some("greedy"));
+    ///
+    /// let top_k = chain.chain_remove(0).unwrap();
+    /// assert_eq!(top_k.chain_len(), None);
+    /// assert_eq!(chain.chain_len(), Some(1));
+    /// ```
+    #[must_use]
+    pub fn chain_len(&self) -> Option<usize> {
+        unsafe { chain_len(self.sampler) }
+    }
+
+    /// The name of the `i`-th sampler of the chain, or `None` if this sampler is not a chain or
+    /// has no `i`-th member.
+    #[must_use]
+    pub fn chain_member_name(&self, i: usize) -> Option<String> {
+        (i < self.chain_len()?).then(|| unsafe { sampler_name(chain_get(self.sampler, i)) })
+    }
+
+    /// Removes the `i`-th sampler from the chain and returns it, or `None` if this sampler is not
+    /// a chain or has no `i`-th member.
+    pub fn chain_remove(&mut self, i: usize) -> Option<LlamaSampler> {
+        if i >= self.chain_len()? {
+            return None;
+        }
+        let i = i32::try_from(i).ok()?;
+        let sampler = unsafe { llama_cpp_sys_2::llama_sampler_chain_remove(self.sampler, i) };
+        Some(Self { sampler })
+    }
+
+    /// Appends `sampler` to the end of the chain.
+    ///
+    /// # Panics
+    ///
+    /// If this sampler is not a chain.
+    pub fn chain_push(&mut self, sampler: LlamaSampler) {
+        self.chain_insert(self.chain_len().expect("sampler is not a chain"), sampler);
+    }
+
+    /// Inserts `sampler` into the chain at position `i`, shifting all samplers after it.
+    ///
+    /// # Panics
+    ///
+    /// If this sampler is not a chain or `i` is greater than the length of the chain.
+    pub fn chain_insert(&mut self, i: usize, sampler: LlamaSampler) {
+        let len = self.chain_len().expect("sampler is not a chain");
+        assert!(
+            i <= len,
+            "insertion index (is {i}) should be <= len (is {len})"
+        );
+
+        // llama.cpp can only append, so the tail is removed and appended again
+        let tail: Vec<_> = (i..len)
+            .map(|_| self.chain_remove(i).expect("index is in bounds"))
+            .collect();
+        for sampler in std::iter::once(sampler).chain(tail) {
+            unsafe { llama_cpp_sys_2::llama_sampler_chain_add(self.sampler, sampler.sampler) };
+            // the chain now owns the sampler
+            std::mem::forget(sampler);
+        }
+    }
+
+    /// Returns the timings of the chain, or `None` if this sampler is not a chain. llama.cpp only
+    /// records timings for whole chains, and only if they were created with `no_perf = false`.
+    #[must_use]
+    pub fn timings(&self) -> Option<LlamaSamplerTimings> {
+        self.chain_len()?;
+        let timings = unsafe { llama_cpp_sys_2::llama_perf_sampler(self.sampler) };
+        Some(LlamaSamplerTimings { timings })
+    }
+
+    /// Resets the timings of the chain. Does nothing if this sampler is not a chain.
+    pub fn reset_timings(&mut self) {
+        if self.is_chain() {
+            unsafe { llama_cpp_sys_2::llama_perf_sampler_reset(self.sampler) }
+        }
+    }
+
     #[allow(clippy::doc_markdown)]
     /// Updates the logits l_i' = l_i/t. When t <= 0.0f, the maximum logit is kept at it's original
     /// value, the rest are set to -inf
@@ -336,6 +532,31 @@ impl LlamaSampler {
         }
     }
 
+    /// Grammar sampler constraining the output to strings matching the regular expression `pattern`
+    /// as a whole. See [`crate::grammar::regex`] for the supported syntax.
+    ///
+    /// ```no_run
+    /// # use llama_cpp_2::model::LlamaModel;
+    /// # use llama_cpp_2::sampling::LlamaSampler;
+    /// # fn example(model: &LlamaModel) -> Result<(), llama_cpp_2::GrammarError> {
+    /// let sampler = LlamaSampler::chain_simple([
+    ///     LlamaSampler::regex(model, r"\d{3}-\d{4}")?,
+    ///     LlamaSampler::greedy(),
+    /// ]);
+    /// # Ok(())
+    /// # }
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// If `pattern` can not be converted into a grammar, or the grammar sampler can not be created.
+    pub fn regex(model: &LlamaModel, pattern: &str) -> Result<Self, GrammarError> {
+        let grammar = regex_to_grammar(pattern)?;
+        // report invalid grammars as errors instead of letting llama.cpp log them
+        LlamaGrammar::from_str(&grammar)?;
+        Self::grammar(model, &grammar, "root")
+    }
+
     fn sanitize_grammar_strings(
         grammar_str: &str,
         grammar_root: &str,
@@ -509,6 +730,16 @@ impl LlamaSampler {
         Self { sampler }
     }
 
+    /// Fill-in-the-middle sampler for code models, see [`crate::infill`]. It merges the
+    /// probabilities of tokens that are prefixes of each other and ends the infill with an
+    /// end-of-generation token when no text is likely. Use it after samplers like
+    /// [`Self::top_k`] and before [`Self::dist`].
+    #[must_use]
+    pub fn infill(model: &LlamaModel) -> Self {
+        let sampler = unsafe { llama_cpp_sys_2::llama_sampler_init_infill(model.vocab_ptr()) };
+        Self { sampler }
+    }
+
     /// Creates a sampler that applies bias values to specific tokens during sampling.
     ///
     /// # Parameters
@@ -0,0 +1,391 @@
+//! A declarative sampler configuration, which can be (de)serialized with the `serde` feature.
+//!
+//! [`SamplerConfig`] mirrors `common_params_sampling` of llama.cpp, including its defaults, and
+//! [`SamplerConfig::build`] assembles the same chain as `common_sampler_init`. Every field has a
+//! default, so a configuration only needs to list what it changes:
+//!
+//! ```
+//! # #[cfg(feature = "serde")] {
+//! use llama_cpp_2::sampling::config::{SamplerConfig, SamplerStage};
+//!
+//! let config: SamplerConfig = serde_json::from_str(r#"{
+//!     "temperature": 0.2,
+//!     "top_k": 20,
+//!     "stages": ["top_k", "temperature"]
+//! }"#).unwrap();
+//!
+//! assert_eq!(config.top_p, 0.95);
+//! assert_eq!(config.stages, [SamplerStage::TopK, SamplerStage::Temperature]);
+//! # }
+//! ```
+//!
+//! Unset optional fields are skipped when serializing, so configurations also round trip through
+//! formats without `null`, like TOML.
+
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+
+use crate::model::LlamaModel;
+use crate::sampling::LlamaSampler;
+use crate::token::logit_bias::LlamaLogitBias;
+use crate::token::LlamaToken;
+use crate::GrammarError;
+
+/// The seed llama.cpp replaces with a random one.
+pub const DEFAULT_SEED: u32 = 0xFFFF_FFFF;
+
+/// A stage of the sampler chain built by [`SamplerConfig::build`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+pub enum SamplerStage {
+    /// [`LlamaSampler::penalties`]
+    Penalties,
+    /// [`LlamaSampler::dry`]
+    Dry,
+    /// [`LlamaSampler::top_n_sigma`]
+    TopNSigma,
+    /// [`LlamaSampler::top_k`]
+    TopK,
+    /// [`LlamaSampler::typical`]
+    Typical,
+    /// [`LlamaSampler::top_p`]
+    TopP,
+    /// [`LlamaSampler::min_p`]
+    MinP,
+    /// [`LlamaSampler::xtc`]
+    Xtc,
+    /// [`LlamaSampler::temp_ext`]
+    Temperature,
+}
+
+impl SamplerStage {
+    /// The order of the stages in llama.cpp.
+    pub const DEFAULT_ORDER: [Self; 9] = [
+        Self::Penalties,
+        Self::Dry,
+        Self::TopNSigma,
+        Self::TopK,
+        Self::Typical,
+        Self::TopP,
+        Self::MinP,
+        Self::Xtc,
+        Self::Temperature,
+    ];
+}
+
+/// Replaces the stages and the final token selection with Mirostat.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+pub enum Mirostat {
+    /// Use the configured stages followed by [`LlamaSampler::dist`].
+    #[default]
+    Disabled,
+    /// [`LlamaSampler::mirostat`], after the temperature.
+    V1 {
+        /// The target surprise.
+        tau: f32,
+        /// The learning rate.
+        eta: f32,
+    },
+    /// [`LlamaSampler::mirostat_v2`], after the temperature.
+    V2 {
+        /// The target surprise.
+        tau: f32,
+        /// The learning rate.
+        eta: f32,
+    },
+}
+
+/// A bias added to the logit of a token, see [`LlamaSampler::logit_bias`].
+///
+/// Banning a token is a flag rather than a bias of `f32::NEG_INFINITY`, which JSON can not
+/// represent.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct TokenBias {
+    /// The id of the token.
+    pub token: i32,
+    /// The bias, ignored if the token is banned.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub bias: f32,
+    /// Prevent the token from being sampled.
+    #[cfg_attr(
+        feature = "serde",
+        serde(default, skip_serializing_if = "std::ops::Not::not")
+    )]
+    pub ban: bool,
+}
+
+impl TokenBias {
+    /// Add `bias` to the logit of `token`.
+    #[must_use]
+    pub fn new(token: i32, bias: f32) -> Self {
+        Self {
+            token,
+            bias,
+            ban: false,
+        }
+    }
+
+    /// Prevent `token` from being sampled.
+    #[must_use]
+    pub fn ban(token: i32) -> Self {
+        Self {
+            token,
+            bias: 0.0,
+            ban: true,
+        }
+    }
+
+    /// The bias added to the logit, `f32::NEG_INFINITY` for a banned token.
+    #[must_use]
+    pub fn logit_bias(&self) -> f32 {
+        if self.ban {
+            f32::NEG_INFINITY
+        } else {
+            self.bias
+        }
+    }
+}
+
+/// The configuration of a sampler chain, see [the module docs](self).
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serde", serde(default))]
+pub struct SamplerConfig {
+    /// The seed of the random samplers, [`DEFAULT_SEED`] for a random seed.
+    pub seed: u32,
+    /// The temperature, `<= 0.0` keeps only the most likely token.
+    pub temperature: f32,
+    /// Dynamic temperature range, `0.0` = disabled.
+    pub dynatemp_range: f32,
+    /// Dynamic temperature exponent.
+    pub dynatemp_exponent: f32,
+    /// Top-k, `<= 0` = disabled.
+    pub top_k: i32,
+    /// Top-p, `1.0` = disabled.
+    pub top_p: f32,
+    /// Min-p, `0.0` = disabled.
+    pub min_p: f32,
+    /// Locally typical sampling, `1.0` = disabled.
+    pub typical_p: f32,
+    /// XTC probability, `0.0` = disabled.
+    pub xtc_probability: f32,
+    /// XTC threshold, `> 0.5` = disabled.
+    pub xtc_threshold: f32,
+    /// Top-n-sigma, `-1.0` = disabled.
+    pub top_n_sigma: f32,
+    /// The minimum number of candidates kept by the truncating samplers.
+    pub min_keep: usize,
+    /// The number of last tokens to penalize, `0` = disabled. Unlike for
+    /// [`Self::dry_penalty_last_n`], llama.cpp does not replace `-1` with the context size but
+    /// treats it as `0`.
+    pub penalty_last_n: i32,
+    /// Repetition penalty, `1.0` = disabled.
+    pub penalty_repeat: f32,
+    /// Frequency penalty, `0.0` = disabled.
+    pub penalty_freq: f32,
+    /// Presence penalty, `0.0` = disabled.
+    pub penalty_present: f32,
+    /// DRY multiplier, `0.0` = disabled.
+    pub dry_multiplier: f32,
+    /// DRY base.
+    pub dry_base: f32,
+    /// DRY allowed length.
+    pub dry_allowed_length: i32,
+    /// The number of last tokens DRY considers, `0` = disabled, `-1` = context size.
+    pub dry_penalty_last_n: i32,
+    /// DRY sequence breakers, which must not contain null bytes.
+    pub dry_sequence_breakers: Vec<String>,
+    /// Mirostat.
+    pub mirostat: Mirostat,
+    /// Biases added to the logits before any stage.
+    pub logit_bias: Vec<TokenBias>,
+    /// A GBNF grammar with a `root` rule, applied before any stage.
+    #[cfg_attr(feature = "serde", serde(skip_serializing_if = "Option::is_none"))]
+    pub grammar: Option<String>,
+    /// The stages in the order they are applied.
+    pub stages: Vec<SamplerStage>,
+}
+
+impl Default for SamplerConfig {
+    fn default() -> Self {
+        Self {
+            seed: DEFAULT_SEED,
+            temperature: 0.8,
+            dynatemp_range: 0.0,
+            dynatemp_exponent: 1.0,
+            top_k: 40,
+            top_p: 0.95,
+            min_p: 0.05,
+            typical_p: 1.0,
+            xtc_probability: 0.0,
+            xtc_threshold: 0.1,
+            top_n_sigma: -1.0,
+            min_keep: 0,
+            penalty_last_n: 64,
+            penalty_repeat: 1.0,
+            penalty_freq: 0.0,
+            penalty_present: 0.0,
+            dry_multiplier: 0.0,
+            dry_base: 1.75,
+            dry_allowed_length: 2,
+            dry_penalty_last_n: -1,
+            dry_sequence_breakers: ["\n", ":", "\"", "*"].map(String::from).to_vec(),
+            mirostat: Mirostat::Disabled,
+            logit_bias: Vec::new(),
+            grammar: None,
+            stages: SamplerStage::DEFAULT_ORDER.to_vec(),
+        }
+    }
+}
+
+impl SamplerConfig {
+    /// Build the sampler chain: the grammar, the logit biases, then either the stages followed by
+    /// [`LlamaSampler::dist`], or the temperature followed by Mirostat.
+    ///
+    /// # Errors
+    ///
+    /// If the grammar is invalid or a DRY sequence breaker contains a null byte.
+    pub fn build(&self, model: &LlamaModel) -> Result<LlamaSampler, GrammarError> {
+        if self
+            .dry_sequence_breakers
+            .iter()
+            .any(|breaker| breaker.contains('\0'))
+        {
+            return Err(GrammarError::SequenceBreakerNullBytes);
+        }
+        let mut samplers = Vec::new();
+        if let Some(grammar) = &self.grammar {
+            samplers.push(LlamaSampler::grammar(model, grammar, "root")?);
+        }
+        if !self.logit_bias.is_empty() {
+            let biases: Vec<_> = self
+                .logit_bias
+                .iter()
+                .map(|bias| LlamaLogitBias::new(LlamaToken(bias.token), bias.logit_bias()))
+                .collect();
+            samplers.push(LlamaSampler::logit_bias(model.n_vocab(), &biases));
+        }
+
+        match self.mirostat {
+            Mirostat::Disabled => {
+                samplers.extend(self.stages.iter().map(|&stage| self.stage(model, stage)));
+                samplers.push(LlamaSampler::dist(self.seed));
+            }
+            Mirostat::V1 { tau, eta } => {
+                samplers.push(LlamaSampler::temp(self.temperature));
+                samplers.push(LlamaSampler::mirostat(
+                    model.n_vocab(),
+                    self.seed,
+                    tau,
+                    eta,
+                    100,
+                ));
+            }
+            Mirostat::V2 { tau, eta } => {
+                samplers.push(LlamaSampler::temp(self.temperature));
+                samplers.push(LlamaSampler::mirostat_v2(self.seed, tau, eta));
+            }
+        }
+
+        Ok(LlamaSampler::chain_simple(samplers))
+    }
+
+    fn stage(&self, model: &LlamaModel, stage: SamplerStage) -> LlamaSampler {
+        match stage {
+            SamplerStage::Penalties => LlamaSampler::penalties(
+                self.penalty_last_n,
+                self.penalty_repeat,
+                self.penalty_freq,
+                self.penalty_present,
+            ),
+            SamplerStage::Dry => LlamaSampler::dry(
+                model,
+                self.dry_multiplier,
+                self.dry_base,
+                self.dry_allowed_length,
+                self.dry_penalty_last_n,
+                &self.dry_sequence_breakers,
+            ),
+            SamplerStage::TopNSigma => LlamaSampler::top_n_sigma(self.top_n_sigma),
+            SamplerStage::TopK => LlamaSampler::top_k(self.top_k),
+            SamplerStage::Typical => LlamaSampler::typical(self.typical_p, self.min_keep),
+            SamplerStage::TopP => LlamaSampler::top_p(self.top_p, self.min_keep),
+            SamplerStage::MinP => LlamaSampler::min_p(self.min_p, self.min_keep),
+            SamplerStage::Xtc => LlamaSampler::xtc(
+                self.xtc_probability,
+                self.xtc_threshold,
+                self.min_keep,
+                self.seed,
+            ),
+            SamplerStage::Temperature => LlamaSampler::temp_ext(
+                self.temperature,
+                self.dynatemp_range,
+                self.dynatemp_exponent,
+            ),
+        }
+    }
+}
+
+#[cfg(all(test, feature = "serde"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let config = SamplerConfig {
+            mirostat: Mirostat::V2 { tau: 5.0, eta: 0.5 },
+            logit_bias: vec![TokenBias::new(42, -1.5), TokenBias::ban(7)],
+            ..SamplerConfig::default()
+        };
+        let json = serde_json::to_value(&config).unwrap();
+        assert!(json.get("grammar").is_none());
+        assert_eq!(
+            json["mirostat"],
+            serde_json::json!({"v2": {"tau": 5.0, "eta": 0.5}})
+        );
+        assert_eq!(json["stages"][3], "top_k");
+        assert_eq!(
+            json["logit_bias"][1],
+            serde_json::json!({"token": 7, "bias": 0.0, "ban": true})
+        );
+        assert_eq!(
+            serde_json::from_value::<SamplerConfig>(json).unwrap(),
+            config
+        );
+    }
+
+    #[test]
+    fn toml_round_trip() {
+        let config: SamplerConfig = toml::from_str(
+            r#"
+            temperature = 0.5
+            stages = ["top_k", "temperature"]
+
+            [mirostat.v1]
+            tau = 5.0
+            eta = 0.1
+
+            [[logit_bias]]
+            token = 42
+            bias = 2.0
+
+            [[logit_bias]]
+            token = 7
+            ban = true
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.mirostat, Mirostat::V1 { tau: 5.0, eta: 0.1 });
+        assert_eq!(
+            config.logit_bias,
+            [TokenBias::new(42, 2.0), TokenBias::ban(7)]
+        );
+        assert!(config.logit_bias[1].logit_bias().is_infinite());
+        let toml = toml::to_string(&config).unwrap();
+        assert_eq!(toml::from_str::<SamplerConfig>(&toml).unwrap(), config);
+    }
+}
@@ -0,0 +1,194 @@
+//! Samplers implemented in Rust.
+//!
+//! A type implementing [`Sampler`] is wrapped into a [`LlamaSampler`] with [`LlamaSampler::custom`]
+//! and can then be used like any of the built-in samplers, including as a member of a
+//! [`LlamaSampler::chain`].
+//!
+//! ```rust
+//! use llama_cpp_2::llama_backend::LlamaBackend;
+//! use llama_cpp_2::sampling::custom::Sampler;
+//! use llama_cpp_2::sampling::LlamaSampler;
+//! use llama_cpp_2::token::data::LlamaTokenData;
+//! use llama_cpp_2::token::data_array::LlamaTokenDataArray;
+//! use llama_cpp_2::token::LlamaToken;
+//!
+//! /// Never samples the banned tokens.
+//! #[derive(Clone)]
+//! struct BanTokens(Vec<LlamaToken>);
+//!
+//! impl Sampler for BanTokens {
+//!     fn apply(&mut self, candidates: &mut LlamaTokenDataArray) {
+//!         for data in &mut candidates.data {
+//!             if self.0.contains(&data.id()) {
+//!                 data.set_logit(f32::NEG_INFINITY);
+//!             }
+//!         }
+//!     }
+//!
+//!     fn clone_sampler(&self) -> Box<dyn Sampler> {
+//!         Box::new(self.clone())
+//!     }
+//! }
+//!
+//! let _backend = LlamaBackend::init().unwrap();
+//! let mut data_array = LlamaTokenDataArray::new(vec![
+//!     LlamaTokenData::new(LlamaToken(0), 0., 0.),
+//!     LlamaTokenData::new(LlamaToken(1), 2., 0.),
+//!     LlamaTokenData::new(LlamaToken(2), 1., 0.),
+//! ], false);
+//!
+//! data_array.apply_sampler(&LlamaSampler::chain_simple([
+//!     LlamaSampler::custom(BanTokens(vec![LlamaToken(1)])),
+//!     LlamaSampler::greedy(),
+//! ]));
+//!
+//! assert_eq!(data_array.selected_token(), Some(LlamaToken(2)));
+//! ```
+
+use std::ffi::{c_char, CString};
+
+use crate::sampling::LlamaSampler;
+use crate::token::data::LlamaTokenData;
+use crate::token::data_array::LlamaTokenDataArray;
+use crate::token::LlamaToken;
+
+/// A sampler implemented in Rust.
+///
+/// The methods are called by llama.cpp through a `llama_sampler_i` vtable. A panic in any of them
+/// aborts the process, as it can not unwind through llama.cpp.
+pub trait Sampler: 'static {
+    /// The name of the sampler, reported by llama.cpp. It is queried once when the sampler is
+    /// wrapped with [`LlamaSampler::custom`].
+    fn name(&self) -> String {
+        std::any::type_name::<Self>().to_string()
+    }
+
+    /// Modify the candidates, for example by changing logits, removing candidates or selecting a
+    /// token.
+    ///
+    /// The candidates can not grow: entries beyond the original length are dropped.
+    fn apply(&mut self, candidates: &mut LlamaTokenDataArray);
+
+    /// Update the state of the sampler with a token that was selected.
+    fn accept(&mut self, token: LlamaToken) {
+        let _ = token;
+    }
+
+    /// Reset the state of the sampler.
+    fn reset(&mut self) {}
+
+    /// Create a copy of the sampler, including its state. This is used when llama.cpp clones the
+    /// sampler, for example as part of a chain.
+    fn clone_sampler(&self) -> Box<dyn Sampler>;
+}
+
+/// The context of a custom `llama_sampler`.
+struct CustomSampler {
+    name: CString,
+    sampler: Box<dyn Sampler>,
+    /// Reused between calls to `apply` to avoid an allocation per token.
+    candidates: LlamaTokenDataArray,
+}
+
+static CUSTOM_SAMPLER_I: llama_cpp_sys_2::llama_sampler_i = llama_cpp_sys_2::llama_sampler_i {
+    name: Some(custom_name),
+    accept: Some(custom_accept),
+    apply: Some(custom_apply),
+    reset: Some(custom_reset),
+    clone: Some(custom_clone),
+    free: Some(custom_free),
+};
+
+impl LlamaSampler {
+    /// Wrap a [`Sampler`] implemented in Rust. See [the module docs](crate::sampling::custom).
+    #[must_use]
+    pub fn custom(sampler: impl Sampler) -> Self {
+        Self::custom_boxed(Box::new(sampler))
+    }
+
+    fn custom_boxed(sampler: Box<dyn Sampler>) -> Self {
+        let name = CString::new(sampler.name().replace('\0', "")).expect("nul bytes were removed");
+        let ctx = Box::new(CustomSampler {
+            name,
+            sampler,
+            candidates: LlamaTokenDataArray::new(Vec::new(), false),
+        });
+        let sampler = unsafe {
+            llama_cpp_sys_2::llama_sampler_init(
+                &raw const CUSTOM_SAMPLER_I,
+                Box::into_raw(ctx).cast(),
+            )
+        };
+        Self { sampler }
+    }
+}
+
+/// # Safety
+///
+/// `smpl` must have been created by [`LlamaSampler::custom_boxed`] and not yet been freed.
+unsafe fn custom_sampler<'a>(smpl: *const llama_cpp_sys_2::llama_sampler) -> &'a mut CustomSampler {
+    &mut *(*smpl).ctx.cast::<CustomSampler>()
+}
+
+unsafe extern "C" fn custom_name(smpl: *const llama_cpp_sys_2::llama_sampler) -> *const c_char {
+    custom_sampler(smpl).name.as_ptr()
+}
+
+unsafe extern "C" fn custom_accept(
+    smpl: *mut llama_cpp_sys_2::llama_sampler,
+    token: llama_cpp_sys_2::llama_token,
+) {
+    custom_sampler(smpl).sampler.accept(LlamaToken(token));
+}
+
+unsafe extern "C" fn custom_apply(
+    smpl: *mut llama_cpp_sys_2::llama_sampler,
+    cur_p: *mut llama_cpp_sys_2::llama_token_data_array,
+) {
+    let custom = custom_sampler(smpl);
+    let cur_p = &mut *cur_p;
+    let data: &mut [LlamaTokenData] = if cur_p.size == 0 {
+        &mut []
+    } else {
+        // `LlamaTokenData` is a transparent wrapper around `llama_token_data`
+        std::slice::from_raw_parts_mut(cur_p.data.cast(), cur_p.size)
+    };
+
+    let candidates = &mut custom.candidates;
+    candidates.data.clear();
+    candidates.data.extend_from_slice(data);
+    candidates.selected = usize::try_from(cur_p.selected)
+        .ok()
+        .filter(|&selected| selected < data.len());
+    candidates.sorted = cur_p.sorted;
+
+    custom.sampler.apply(candidates);
+
+    let size = candidates.data.len().min(data.len());
+    data[..size].copy_from_slice(&candidates.data[..size]);
+    cur_p.size = size;
+    cur_p.selected = candidates
+        .selected
+        .filter(|&selected| selected < size)
+        .and_then(|selected| selected.try_into().ok())
+        .unwrap_or(-1);
+    cur_p.sorted = candidates.sorted;
+}
+
+unsafe extern "C" fn custom_reset(smpl: *mut llama_cpp_sys_2::llama_sampler) {
+    custom_sampler(smpl).sampler.reset();
+}
+
+unsafe extern "C" fn custom_clone(
+    smpl: *const llama_cpp_sys_2::llama_sampler,
+) -> *mut llama_cpp_sys_2::llama_sampler {
+    let clone = LlamaSampler::custom_boxed(custom_sampler(smpl).sampler.clone_sampler());
+    let sampler = clone.sampler;
+    // ownership passes to the caller
+    std::mem::forget(clone);
+    sampler
+}
+
+unsafe extern "C" fn custom_free(smpl: *mut llama_cpp_sys_2::llama_sampler) {
+    drop(Box::from_raw((*smpl).ctx.cast::<CustomSampler>()));
+}
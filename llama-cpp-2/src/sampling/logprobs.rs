@@ -0,0 +1,249 @@
+//! Log-probabilities of sampled tokens and their most likely alternatives.
+//!
+//! With the `serde` feature, [`TokenLogprobs`] serializes like an entry of
+//! `choices[].logprobs.content` in the `OpenAI` API. [`LlamaSampler::sample_with_logprobs`]
+//! computes it both from the raw logits of the model and from the candidates left by the sampler
+//! chain:
+//!
+//! ```no_run
+//! # use llama_cpp_2::context::LlamaContext;
+//! # use llama_cpp_2::sampling::LlamaSampler;
+//! # fn example(ctx: &LlamaContext, sampler: &mut LlamaSampler, idx: i32) -> Result<(), Box<dyn std::error::Error>> {
+//! let logprobs = sampler.sample_with_logprobs(ctx, idx, 5)?;
+//! # #[cfg(feature = "serde")]
+//! println!("{}", serde_json::to_string(&logprobs.processed)?);
+//! // {"token":" world","logprob":-0.31,"bytes":[32,119,111,114,108,100],"top_logprobs":[...]}
+//! # Ok(())
+//! # }
+//! ```
+
+#[cfg(feature = "serde")]
+use serde::Serialize;
+
+use crate::context::LlamaContext;
+use crate::model::LlamaModel;
+use crate::sampling::LlamaSampler;
+use crate::token::data::LlamaTokenData;
+use crate::token::data_array::LlamaTokenDataArray;
+use crate::token::LlamaToken;
+use crate::TokenToStringError;
+
+/// Errors that can occur when sampling with [`LlamaSampler::sample_with_logprobs`].
+#[derive(Debug, thiserror::Error)]
+pub enum LogprobsError {
+    /// Converting a token to text failed.
+    #[error("{0}")]
+    TokenToStringError(#[from] TokenToStringError),
+    /// The sampler did not select a token, it must end with a sampler like
+    /// [`LlamaSampler::dist`] or [`LlamaSampler::greedy`].
+    #[error("the sampler did not select a token")]
+    NoTokenSelected,
+}
+
+/// A token and its log-probability.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct TopLogprob {
+    /// The token.
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub id: LlamaToken,
+    /// The text of the token, invalid UTF-8 is replaced.
+    pub token: String,
+    /// The natural logarithm of the probability of the token, `-inf` if it was not a candidate.
+    pub logprob: f32,
+    /// The bytes of the token.
+    pub bytes: Vec<u8>,
+}
+
+/// The log-probability of a sampled token and the most likely tokens at its position.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct TokenLogprobs {
+    /// The sampled token.
+    #[cfg_attr(feature = "serde", serde(flatten))]
+    pub sampled: TopLogprob,
+    /// The most likely tokens, most likely first. Tokens that can not be sampled, with a
+    /// log-probability of `-inf`, are left out, so there may be fewer than requested.
+    pub top_logprobs: Vec<TopLogprob>,
+}
+
+/// The log-probabilities of a sampled token, see [`LlamaSampler::sample_with_logprobs`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct SampledLogprobs {
+    /// The sampled token.
+    pub token: LlamaToken,
+    /// From the logits of the model.
+    pub raw: TokenLogprobs,
+    /// From the candidates left by the sampler chain, after e.g. temperature and truncation.
+    pub processed: TokenLogprobs,
+}
+
+impl TokenLogprobs {
+    /// Compute the log-probabilities of `token` and the `n_top` most likely tokens from the
+    /// softmax of the logits of `candidates`.
+    ///
+    /// # Errors
+    ///
+    /// If a token can not be converted to text.
+    pub fn new(
+        model: &LlamaModel,
+        candidates: &LlamaTokenDataArray,
+        token: LlamaToken,
+        n_top: usize,
+    ) -> Result<Self, TokenToStringError> {
+        let (logprob, top) = logprobs(candidates, token, n_top);
+        Ok(Self {
+            sampled: TopLogprob::new(model, token, logprob)?,
+            top_logprobs: top
+                .into_iter()
+                .map(|(id, logprob)| TopLogprob::new(model, id, logprob))
+                .collect::<Result<_, _>>()?,
+        })
+    }
+}
+
+impl TopLogprob {
+    fn new(model: &LlamaModel, id: LlamaToken, logprob: f32) -> Result<Self, TokenToStringError> {
+        let bytes = match model.token_to_piece_bytes(id, 8, true, None) {
+            Err(TokenToStringError::InsufficientBufferSpace(size)) => model.token_to_piece_bytes(
+                id,
+                usize::try_from(size.unsigned_abs()).expect("size fits into usize"),
+                true,
+                None,
+            ),
+            // tokens without a text representation
+            Err(TokenToStringError::UnknownTokenType) => Ok(Vec::new()),
+            bytes => bytes,
+        }?;
+        Ok(Self {
+            id,
+            token: String::from_utf8_lossy(&bytes).into_owned(),
+            logprob,
+            bytes,
+        })
+    }
+}
+
+/// The log-softmax of `token` and the `n_top` most likely candidates.
+fn logprobs(
+    candidates: &LlamaTokenDataArray,
+    token: LlamaToken,
+    n_top: usize,
+) -> (f32, Vec<(LlamaToken, f32)>) {
+    let Some(log_sum) = log_sum_exp(candidates) else {
+        return (f32::NEG_INFINITY, Vec::new());
+    };
+    let logprob = candidates
+        .data
+        .iter()
+        .find(|data| data.id() == token)
+        .map_or(f32::NEG_INFINITY, |data| data.logit() - log_sum);
+    (logprob, top(candidates, log_sum, n_top))
+}
+
+/// The log-softmax of the `n_top` most likely candidates, most likely first, without candidates
+/// that can not be sampled.
+pub(crate) fn top_logprobs(
+    candidates: &LlamaTokenDataArray,
+    n_top: usize,
+) -> Vec<(LlamaToken, f32)> {
+    log_sum_exp(candidates).map_or_else(Vec::new, |log_sum| top(candidates, log_sum, n_top))
+}
+
+/// The logarithm of the sum of the exponentials of the logits, `None` if all are `-inf`.
+fn log_sum_exp(candidates: &LlamaTokenDataArray) -> Option<f32> {
+    let max = candidates
+        .data
+        .iter()
+        .map(LlamaTokenData::logit)
+        .fold(f32::NEG_INFINITY, f32::max);
+    if max == f32::NEG_INFINITY {
+        return None;
+    }
+    let sum = candidates
+        .data
+        .iter()
+        .map(|data| (data.logit() - max).exp())
+        .sum::<f32>();
+    Some(sum.ln() + max)
+}
+
+fn top(candidates: &LlamaTokenDataArray, log_sum: f32, n_top: usize) -> Vec<(LlamaToken, f32)> {
+    let mut top: Vec<_> = candidates
+        .data
+        .iter()
+        .map(|data| (data.id(), data.logit() - log_sum))
+        .filter(|(_, logprob)| logprob.is_finite())
+        .collect();
+    let by_logprob = |a: &(LlamaToken, f32), b: &(LlamaToken, f32)| b.1.total_cmp(&a.1);
+    if n_top < top.len() {
+        top.select_nth_unstable_by(n_top, by_logprob);
+        top.truncate(n_top);
+    }
+    top.sort_by(by_logprob);
+    top
+}
+
+impl LlamaSampler {
+    /// Sample and accept a token from the `idx`-th output of the last evaluation, like
+    /// [`Self::sample`], and return its log-probability and the `n_top` most likely tokens.
+    ///
+    /// The sampler is applied to all logits of the output, so it must select a token, for example
+    /// by ending with [`Self::dist`] or [`Self::greedy`].
+    ///
+    /// # Errors
+    ///
+    /// If the sampler does not select a token or a token can not be converted to text. Nothing is
+    /// accepted if no token was selected.
+    pub fn sample_with_logprobs(
+        &mut self,
+        ctx: &LlamaContext,
+        idx: i32,
+        n_top: usize,
+    ) -> Result<SampledLogprobs, LogprobsError> {
+        let raw = ctx.token_data_array_ith(idx);
+        let mut processed = raw.clone();
+        self.apply(&mut processed);
+        let token = processed
+            .selected_token()
+            .ok_or(LogprobsError::NoTokenSelected)?;
+        self.accept(token);
+
+        Ok(SampledLogprobs {
+            token,
+            raw: TokenLogprobs::new(ctx.model, &raw, token, n_top)?,
+            processed: TokenLogprobs::new(ctx.model, &processed, token, n_top)?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn log_softmax() {
+        let candidates = LlamaTokenDataArray::from_iter(
+            [(0, 1.0), (1, 3.0), (2, f32::NEG_INFINITY), (3, 2.0)]
+                .map(|(id, logit)| LlamaTokenData::new(LlamaToken(id), logit, 0.0)),
+            false,
+        );
+        let (logprob, top) = logprobs(&candidates, LlamaToken(3), 2);
+
+        let log_sum = (1f32.exp() + 3f32.exp() + 2f32.exp()).ln();
+        assert!((logprob - (2.0 - log_sum)).abs() < 1e-6);
+        assert_eq!(top.iter().map(|(id, _)| id.0).collect::<Vec<_>>(), [1, 3]);
+        assert!((top[0].1 - (3.0 - log_sum)).abs() < 1e-6);
+        // candidates removed by the sampler chain are not among the most likely tokens
+        let (_, top) = logprobs(&candidates, LlamaToken(3), 4);
+        assert_eq!(
+            top.iter().map(|(id, _)| id.0).collect::<Vec<_>>(),
+            [1, 3, 0]
+        );
+
+        assert_eq!(
+            logprobs(&candidates, LlamaToken(7), 0),
+            (f32::NEG_INFINITY, Vec::new())
+        );
+    }
+}
@@ -0,0 +1,156 @@
+//! Detection of stop sequences in streamed text.
+//!
+//! Stop sequences can span several tokens or start in the middle of one, so a piece of generated
+//! text can not be emitted as soon as it is decoded. [`StopSequences`] holds back text that could
+//! be the start of a stop sequence until it is known to be safe:
+//!
+//! ```
+//! use llama_cpp_2::stop::{StopSequences, StopStatus};
+//!
+//! let mut stops = StopSequences::new(["\nUser:"]);
+//! assert_eq!(stops.push("Hello"), StopStatus::Continue("Hello".to_string()));
+//! // "\nUs" might be the start of "\nUser:", so it is held back
+//! assert_eq!(stops.push("!\nUs"), StopStatus::Continue("!".to_string()));
+//! assert_eq!(
+//!     stops.push("er: hi"),
+//!     StopStatus::Stop { text: String::new(), stop: 0 }
+//! );
+//! ```
+
+/// The result of [`StopSequences::push`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StopStatus {
+    /// No stop sequence was found. The text is safe to emit, it can be empty if all of the pushed
+    /// text is held back.
+    Continue(String),
+    /// A stop sequence was found and generation should stop.
+    Stop {
+        /// The text before the stop sequence that was not emitted yet.
+        text: String,
+        /// The index of the stop sequence that was found.
+        stop: usize,
+    },
+}
+
+/// A streaming detector of stop sequences, see [the module docs](self).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StopSequences {
+    stops: Vec<String>,
+    pending: String,
+}
+
+impl StopSequences {
+    /// Create a detector for `stops`. Empty stop sequences never match.
+    pub fn new(stops: impl IntoIterator<Item = impl Into<String>>) -> Self {
+        Self {
+            stops: stops.into_iter().map(Into::into).collect(),
+            pending: String::new(),
+        }
+    }
+
+    /// The stop sequences.
+    #[must_use]
+    pub fn stops(&self) -> &[String] {
+        &self.stops
+    }
+
+    /// The text that is currently held back.
+    #[must_use]
+    pub fn pending(&self) -> &str {
+        &self.pending
+    }
+
+    /// Add a piece of generated text.
+    ///
+    /// If several stop sequences are found, the one that starts first wins, and of those the one
+    /// listed first. After a stop the detector is empty and can be reused.
+    pub fn push(&mut self, piece: &str) -> StopStatus {
+        self.pending.push_str(piece);
+
+        let found = self
+            .stops
+            .iter()
+            .enumerate()
+            .filter(|(_, stop)| !stop.is_empty())
+            .filter_map(|(i, stop)| self.pending.find(stop.as_str()).map(|start| (start, i)))
+            .min();
+        if let Some((start, stop)) = found {
+            let mut text = std::mem::take(&mut self.pending);
+            text.truncate(start);
+            return StopStatus::Stop { text, stop };
+        }
+
+        let held = self.held_back();
+        let rest = self.pending.split_off(self.pending.len() - held);
+        StopStatus::Continue(std::mem::replace(&mut self.pending, rest))
+    }
+
+    /// Take the text that is held back, at the end of generation.
+    pub fn flush(&mut self) -> String {
+        std::mem::take(&mut self.pending)
+    }
+
+    /// The length of the longest suffix of the pending text that is a prefix of a stop sequence.
+    fn held_back(&self) -> usize {
+        self.pending
+            .char_indices()
+            .map(|(i, _)| &self.pending[i..])
+            .find(|suffix| self.stops.iter().any(|stop| stop.starts_with(suffix)))
+            .map_or(0, str::len)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cont(text: &str) -> StopStatus {
+        StopStatus::Continue(text.to_string())
+    }
+
+    #[test]
+    fn spanning_tokens() {
+        let mut stops = StopSequences::new(["</s>", "###"]);
+        assert_eq!(stops.push("a <"), cont("a "));
+        assert_eq!(stops.push("/"), cont(""));
+        assert_eq!(stops.pending(), "</");
+        assert_eq!(stops.push("b"), cont("</b"));
+        assert_eq!(stops.push("x#"), cont("x"));
+        assert_eq!(
+            stops.push("##y"),
+            StopStatus::Stop {
+                text: String::new(),
+                stop: 1
+            }
+        );
+        assert_eq!(stops.pending(), "");
+    }
+
+    #[test]
+    fn mid_token() {
+        let mut stops = StopSequences::new(["STOP"]);
+        assert_eq!(
+            stops.push("abcSTOPdef"),
+            StopStatus::Stop {
+                text: "abc".to_string(),
+                stop: 0
+            }
+        );
+        assert_eq!(stops.push("héS"), cont("hé"));
+        assert_eq!(stops.flush(), "S");
+    }
+
+    #[test]
+    fn earliest_stop_wins() {
+        let mut stops = StopSequences::new(["bc", "ab", ""]);
+        assert_eq!(
+            stops.push("xabc"),
+            StopStatus::Stop {
+                text: "x".to_string(),
+                stop: 1
+            }
+        );
+        let mut stops = StopSequences::new(Vec::<String>::new());
+        assert_eq!(stops.push("abc"), cont("abc"));
+    }
+}
@@ -0,0 +1,406 @@
+//! Generate values of rust types, constrained by a grammar derived from the type.
+//!
+//! A type implementing [`LlamaStructured`] describes itself with a JSON schema, which is converted
+//! into a grammar with [`json_schema_to_grammar`]. [`LlamaContext::generate_typed`] then samples
+//! with that grammar and deserializes the output, so the grammar and the type can not drift apart.
+//!
+//! With the `derive` feature `LlamaStructured` can be derived for structs and enums that also
+//! derive [`serde::Deserialize`]:
+//!
+//! ```
+//! # #[cfg(feature = "derive")] {
+//! use llama_cpp_2::structured::LlamaStructured;
+//! use serde::Deserialize;
+//!
+//! #[derive(Deserialize, LlamaStructured)]
+//! #[serde(rename_all = "lowercase")]
+//! enum Sentiment {
+//!     Positive,
+//!     Negative,
+//! }
+//!
+//! #[derive(Deserialize, LlamaStructured)]
+//! struct Review {
+//!     sentiment: Sentiment,
+//!     stars: u8,
+//!     summary: Option<String>,
+//! }
+//!
+//! let grammar = Review::grammar().unwrap();
+//! assert!(grammar.contains(r#"sentiment ::= ("\"positive\"" | "\"negative\"") space"#));
+//! # }
+//! ```
+
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque};
+use std::hash::{BuildHasher, Hash};
+
+use serde::de::DeserializeOwned;
+use serde_json::{json, Value};
+
+use crate::context::LlamaContext;
+use crate::grammar::json_schema::{json_schema_to_grammar, JsonSchemaError};
+use crate::llama_batch::{BatchAddError, LlamaBatch};
+use crate::sampling::LlamaSampler;
+use crate::token::LlamaToken;
+use crate::{DecodeError, GrammarError, TokenToStringError};
+
+#[cfg(feature = "derive")]
+pub use llama_cpp_2_derive::LlamaStructured;
+
+/// A type that can be generated by a model.
+///
+/// The JSON schema must describe exactly the JSON accepted by the type's [`serde::Deserialize`]
+/// implementation.
+pub trait LlamaStructured: DeserializeOwned {
+    /// The JSON schema of the type.
+    ///
+    /// The derived schema of a recursive type is cut off at the first repetition of a type, which
+    /// is left `null`.
+    fn json_schema() -> Value;
+
+    /// The GBNF grammar of the type, for use with [`LlamaSampler::grammar`].
+    ///
+    /// # Errors
+    ///
+    /// If the schema returned by [`LlamaStructured::json_schema`] can not be converted, or the
+    /// derived schema is recursive.
+    fn grammar() -> Result<String, JsonSchemaError> {
+        __private::take_recursive();
+        let schema = Self::json_schema();
+        if let Some(name) = __private::take_recursive() {
+            return Err(JsonSchemaError::Unsupported(format!(
+                "recursive type `{name}`"
+            )));
+        }
+        json_schema_to_grammar(&schema)
+    }
+}
+
+/// Used by the code generated by the derive macro.
+#[doc(hidden)]
+pub mod __private {
+    use std::cell::{Cell, RefCell};
+
+    use serde_json::Value;
+
+    pub use serde_json;
+
+    thread_local! {
+        /// The types whose schemas are being built.
+        static BUILDING: RefCell<Vec<&'static str>> = const { RefCell::new(Vec::new()) };
+        /// The first type whose schema contained itself.
+        static RECURSIVE: Cell<Option<&'static str>> = const { Cell::new(None) };
+    }
+
+    /// Pops the innermost type from `BUILDING`.
+    struct Building;
+
+    impl Drop for Building {
+        fn drop(&mut self) {
+            BUILDING.with_borrow_mut(Vec::pop);
+        }
+    }
+
+    /// Build the schema of `T` with `schema`. If the schema of `T` is already being built, it
+    /// contains itself and would recurse forever, so `null` is returned instead and `T` is
+    /// remembered for `take_recursive`.
+    #[must_use]
+    pub fn guard<T: ?Sized>(schema: impl FnOnce() -> Value) -> Value {
+        let name = std::any::type_name::<T>();
+        if BUILDING.with_borrow(|building| building.contains(&name)) {
+            RECURSIVE.with(|recursive| {
+                if recursive.get().is_none() {
+                    recursive.set(Some(name));
+                }
+            });
+            return Value::Null;
+        }
+        BUILDING.with_borrow_mut(|building| building.push(name));
+        let _building = Building;
+        schema()
+    }
+
+    /// The first recursive type found by [`guard`] since the last call.
+    pub(crate) fn take_recursive() -> Option<&'static str> {
+        RECURSIVE.take()
+    }
+}
+
+/// Failed to generate a value with [`LlamaContext::generate_typed`].
+#[derive(Debug, thiserror::Error)]
+pub enum StructuredGenerationError {
+    /// The prompt was empty.
+    #[error("the prompt must contain at least one token")]
+    EmptyPrompt,
+    /// The schema of the type could not be converted into a grammar.
+    #[error(transparent)]
+    JsonSchema(#[from] JsonSchemaError),
+    /// The grammar sampler could not be created.
+    #[error(transparent)]
+    Grammar(#[from] GrammarError),
+    /// The tokens could not be added to a batch.
+    #[error(transparent)]
+    BatchAdd(#[from] BatchAddError),
+    /// Decoding failed.
+    #[error(transparent)]
+    Decode(#[from] DecodeError),
+    /// A generated token could not be converted to text.
+    #[error(transparent)]
+    TokenToString(#[from] TokenToStringError),
+    /// The output did not complete within the token limit.
+    #[error("the output did not complete within {0} tokens")]
+    MaxTokens(usize),
+    /// The output matched the grammar but could not be deserialized.
+    #[error("failed to deserialize the output: {0}")]
+    Deserialize(#[from] serde_json::Error),
+}
+
+impl LlamaContext<'_> {
+    /// Evaluate `prompt` on sequence 0 and generate a value of type `T`.
+    ///
+    /// The grammar of `T` is applied before `sampler`, which should end with a selecting sampler
+    /// such as [`LlamaSampler::dist`] or [`LlamaSampler::greedy`]. Generation continues from the
+    /// end of sequence 0 in the KV cache, and stops at the first end of generation token.
+    ///
+    /// ```no_run
+    /// # use llama_cpp_2::context::LlamaContext;
+    /// # use llama_cpp_2::model::{AddBos, LlamaModel};
+    /// # use llama_cpp_2::sampling::LlamaSampler;
+    /// # fn example(model: &LlamaModel, ctx: &mut LlamaContext) -> Result<(), Box<dyn std::error::Error>> {
+    /// let prompt = model.str_to_token("List three primes as a JSON array: ", AddBos::Always)?;
+    /// let primes: Vec<u32> = ctx.generate_typed(&prompt, LlamaSampler::greedy(), 64)?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// See [`StructuredGenerationError`].
+    pub fn generate_typed<T: LlamaStructured>(
+        &mut self,
+        prompt: &[LlamaToken],
+        sampler: LlamaSampler,
+        max_tokens: usize,
+    ) -> Result<T, StructuredGenerationError> {
+        if prompt.is_empty() {
+            return Err(StructuredGenerationError::EmptyPrompt);
+        }
+        let grammar = LlamaSampler::grammar(self.model, &T::grammar()?, "root")?;
+        let mut sampler = LlamaSampler::chain_simple([grammar, sampler]);
+
+        let mut batch = LlamaBatch::new(prompt.len(), 1);
+        let mut n_cur = self.kv_cache_seq_pos_max(0) + 1;
+        let last_index = prompt.len() - 1;
+        for (i, &token) in prompt.iter().enumerate() {
+            batch.add(token, n_cur, &[0], i == last_index)?;
+            n_cur += 1;
+        }
+        self.decode(&mut batch)?;
+
+        let mut decoder = encoding_rs::UTF_8.new_decoder();
+        let mut output = String::new();
+        for _ in 0..max_tokens {
+            // `sample` also accepts the token, advancing the grammar
+            let token = sampler.sample(self, batch.n_tokens() - 1);
+            if self.model.is_eog_token(token) {
+                return Ok(serde_json::from_str(&output)?);
+            }
+            output.push_str(
+                &self
+                    .model
+                    .token_to_piece(token, &mut decoder, false, None)?,
+            );
+
+            batch.clear();
+            batch.add(token, n_cur, &[0], true)?;
+            n_cur += 1;
+            self.decode(&mut batch)?;
+        }
+        Err(StructuredGenerationError::MaxTokens(max_tokens))
+    }
+}
+
+macro_rules! impl_structured {
+    ($($ty:ty => $schema:tt),* $(,)?) => {
+        $(
+            impl LlamaStructured for $ty {
+                fn json_schema() -> Value {
+                    json!($schema)
+                }
+            }
+        )*
+    };
+}
+
+impl_structured! {
+    () => { "type": "null" },
+    bool => { "type": "boolean" },
+    i8 => { "type": "integer", "minimum": i8::MIN, "maximum": i8::MAX },
+    i16 => { "type": "integer", "minimum": i16::MIN, "maximum": i16::MAX },
+    i32 => { "type": "integer", "minimum": i32::MIN, "maximum": i32::MAX },
+    i64 => { "type": "integer" },
+    isize => { "type": "integer" },
+    u8 => { "type": "integer", "minimum": 0, "maximum": u8::MAX },
+    u16 => { "type": "integer", "minimum": 0, "maximum": u16::MAX },
+    u32 => { "type": "integer", "minimum": 0, "maximum": u32::MAX },
+    u64 => { "type": "integer", "minimum": 0 },
+    usize => { "type": "integer", "minimum": 0 },
+    f32 => { "type": "number" },
+    f64 => { "type": "number" },
+    char => { "type": "string", "minLength": 1, "maxLength": 1 },
+    String => { "type": "string" },
+}
+
+impl<T: LlamaStructured> LlamaStructured for Option<T> {
+    fn json_schema() -> Value {
+        json!({ "anyOf": [T::json_schema(), { "type": "null" }] })
+    }
+}
+
+impl<T: LlamaStructured> LlamaStructured for Box<T> {
+    fn json_schema() -> Value {
+        T::json_schema()
+    }
+}
+
+impl<T: LlamaStructured> LlamaStructured for Vec<T> {
+    fn json_schema() -> Value {
+        json!({ "type": "array", "items": T::json_schema() })
+    }
+}
+
+impl<T: LlamaStructured> LlamaStructured for VecDeque<T> {
+    fn json_schema() -> Value {
+        Vec::<T>::json_schema()
+    }
+}
+
+impl<T: LlamaStructured + Ord> LlamaStructured for BTreeSet<T> {
+    fn json_schema() -> Value {
+        Vec::<T>::json_schema()
+    }
+}
+
+impl<T: LlamaStructured + Eq + Hash, S: BuildHasher + Default> LlamaStructured for HashSet<T, S> {
+    fn json_schema() -> Value {
+        Vec::<T>::json_schema()
+    }
+}
+
+impl<T: LlamaStructured, const N: usize> LlamaStructured for [T; N]
+where
+    [T; N]: DeserializeOwned,
+{
+    fn json_schema() -> Value {
+        json!({
+            "type": "array",
+            "items": T::json_schema(),
+            "minItems": N,
+            "maxItems": N,
+        })
+    }
+}
+
+impl<V: LlamaStructured> LlamaStructured for BTreeMap<String, V> {
+    fn json_schema() -> Value {
+        json!({ "type": "object", "additionalProperties": V::json_schema() })
+    }
+}
+
+impl<V: LlamaStructured, S: BuildHasher + Default> LlamaStructured for HashMap<String, V, S> {
+    fn json_schema() -> Value {
+        BTreeMap::<String, V>::json_schema()
+    }
+}
+
+macro_rules! impl_structured_tuple {
+    ($($len:literal => ($($name:ident),+)),* $(,)?) => {
+        $(
+            impl<$($name: LlamaStructured),+> LlamaStructured for ($($name,)+) {
+                fn json_schema() -> Value {
+                    json!({
+                        "type": "array",
+                        "prefixItems": [$($name::json_schema()),+],
+                        "minItems": $len,
+                        "maxItems": $len,
+                    })
+                }
+            }
+        )*
+    };
+}
+
+impl_structured_tuple! {
+    1 => (A),
+    2 => (A, B),
+    3 => (A, B, C),
+    4 => (A, B, C, D),
+    5 => (A, B, C, D, E),
+    6 => (A, B, C, D, E, F),
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use super::*;
+    use crate::grammar::LlamaGrammar;
+
+    fn check_grammar<T: LlamaStructured>() -> String {
+        let grammar = T::grammar().unwrap();
+        LlamaGrammar::from_str(&grammar).unwrap();
+        grammar
+    }
+
+    #[test]
+    fn primitives() {
+        assert!(check_grammar::<u8>().contains(
+            "root ::= ([0-9] | ([1-8] [0-9] | [9] [0-9]) | ([1] [0-9]{2} | [2] ([0-4] [0-9] | [5] [0-5]))) space"
+        ));
+        check_grammar::<i32>();
+        check_grammar::<i64>();
+        check_grammar::<f64>();
+        check_grammar::<char>();
+        check_grammar::<bool>();
+        check_grammar::<()>();
+    }
+
+    #[test]
+    fn containers() {
+        check_grammar::<Vec<Option<String>>>();
+        check_grammar::<[u16; 3]>();
+        check_grammar::<(String, bool, f32)>();
+        check_grammar::<HashMap<String, Vec<u64>>>();
+        check_grammar::<Box<BTreeSet<i8>>>();
+    }
+
+    #[test]
+    fn recursive() {
+        #[derive(serde::Deserialize)]
+        struct Tree {
+            #[allow(dead_code)]
+            children: Vec<Tree>,
+        }
+
+        impl LlamaStructured for Tree {
+            fn json_schema() -> Value {
+                __private::guard::<Self>(|| {
+                    json!({
+                        "type": "object",
+                        "properties": { "children": Vec::<Tree>::json_schema() },
+                    })
+                })
+            }
+        }
+
+        assert_eq!(
+            Tree::grammar(),
+            Err(JsonSchemaError::Unsupported(format!(
+                "recursive type `{}`",
+                std::any::type_name::<Tree>()
+            )))
+        );
+        // the error is not reported again
+        check_grammar::<u8>();
+    }
+}
@@ -1,4 +1,4 @@
-//! Safe wrapper around `llama_timings`.
+//! Safe wrappers around the llama.cpp performance counters.
 use std::fmt::{Debug, Display, Formatter};
 
 /// A wrapper around `llama_timings`.
@@ -130,3 +130,36 @@ impl Display for LlamaTimings {
         Ok(())
     }
 }
+
+/// A wrapper around `llama_perf_sampler_data`, the timings of a sampler chain.
+#[derive(Clone, Copy, Debug)]
+pub struct LlamaSamplerTimings {
+    pub(crate) timings: llama_cpp_sys_2::llama_perf_sampler_data,
+}
+
+impl LlamaSamplerTimings {
+    /// Get the sampling time in milliseconds.
+    #[must_use]
+    pub fn t_sample_ms(&self) -> f64 {
+        self.timings.t_sample_ms
+    }
+
+    /// Get the number of sampled tokens.
+    #[must_use]
+    pub fn n_sample(&self) -> i32 {
+        self.timings.n_sample
+    }
+}
+
+impl Display for LlamaSamplerTimings {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        writeln!(
+            f,
+            "sampling time = {:.2} ms / {} runs ({:.2} ms per token, {:.2} tokens per second)",
+            self.t_sample_ms(),
+            self.n_sample(),
+            self.t_sample_ms() / f64::from(self.n_sample()),
+            1e3 / self.t_sample_ms() * f64::from(self.n_sample())
+        )
+    }
+}
@@ -6,3 +6,38 @@
 #![allow(unpredictable_function_pointer_comparisons)]
 
 include!(concat!(env!("OUT_DIR"), "/bindings.rs"));
+
+/// Grammar element types from `llama.cpp/src/llama-grammar.h`.
+///
+/// These are no longer part of the public `llama.h` header and are therefore not generated by
+/// bindgen. They are mirrored here so that grammars can be parsed and inspected on the rust side.
+pub type llama_gretype = ::std::os::raw::c_uint;
+/// end of rule definition
+pub const LLAMA_GRETYPE_END: llama_gretype = 0;
+/// start of alternate definition for rule
+pub const LLAMA_GRETYPE_ALT: llama_gretype = 1;
+/// non-terminal element: reference to rule
+pub const LLAMA_GRETYPE_RULE_REF: llama_gretype = 2;
+/// terminal element: character (code point)
+pub const LLAMA_GRETYPE_CHAR: llama_gretype = 3;
+/// inverse char(s) (`[^a]`, `[^a-b]` `[^abc]`)
+pub const LLAMA_GRETYPE_CHAR_NOT: llama_gretype = 4;
+/// modifies a preceding `LLAMA_GRETYPE_CHAR` or `LLAMA_GRETYPE_CHAR_ALT` to be an inclusive
+/// range (`[a-z]`)
+pub const LLAMA_GRETYPE_CHAR_RNG_UPPER: llama_gretype = 5;
+/// modifies a preceding `LLAMA_GRETYPE_CHAR` or `LLAMA_GRETYPE_CHAR_RNG_UPPER` to add an alternate
+/// char to match (`[ab]`, `[a-zA]`)
+pub const LLAMA_GRETYPE_CHAR_ALT: llama_gretype = 6;
+/// any character (`.`)
+pub const LLAMA_GRETYPE_CHAR_ANY: llama_gretype = 7;
+
+/// A single element of a grammar rule, mirroring `llama_grammar_element` in
+/// `llama.cpp/src/llama-grammar.h`.
+#[repr(C)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct llama_grammar_element {
+    /// The type of the element, one of the `LLAMA_GRETYPE_*` constants.
+    pub type_: llama_gretype,
+    /// Unicode code point or rule ID
+    pub value: u32,
+}